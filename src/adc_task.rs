@@ -1,30 +1,77 @@
 use defmt::*;
+use embassy_futures::join::join;
 use embassy_stm32::{
     Peri,
     adc::{Adc, AdcChannel, SampleTime},
-    peripherals::{ADC1, DMA1_CH1},
+    peripherals::{ADC1, ADC2, DMA1_CH1, DMA1_CH2},
 };
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, channel::Sender};
-use embassy_time::{Duration, Instant, Timer};
+use embassy_sync::{
+    blocking_mutex::raw::ThreadModeRawMutex as Cs,
+    watch::{self, Watch},
+};
+use embassy_time::{Duration, Instant, Ticker};
 use love_letter::Measurements;
 use serde::Serialize;
+use uom::si::volume::milliliter;
 
-use crate::hal::{AdcChannels, NUM_ADC_INPUTS};
+use crate::{
+    comms::message::StatusReport,
+    dac::setpoint::{FlowControllerSetpoint, RegulatorSetpoint},
+    framing_task::publish_status,
+    hal::{AdcChannels, NUM_ADC_INPUTS},
+    heart_control::{
+        air_volume::{AirFlowSample, AirVolumeIntegrator},
+        heart_controller::LEFT_PHASE_WATCH,
+    },
+    loop_control::{
+        fill::ChamberLevels,
+        loop_controller::LOOP_CONFIG_WATCH,
+        sensor::{ADC_MAX_VALUE, SensorCalibrations},
+    },
+};
 
 const SAMPLE_PERIOD: Duration = Duration::from_millis(10);
+/// Flow at the top of the air flow sensor analog output range, Festo SFAH-50
+const AIR_FLOW_MAX_LPM: f32 = 50.0;
+/// Level at the top of the chamber level sensor analog output range, the full chamber height
+const LEVEL_SENSOR_RANGE_MM: f32 = 30.0;
+
+/// Latest pressure regulator feedback, for the closed-loop pressure control
+pub static REGULATOR_PRESSURE_WATCH: Watch<Cs, uom::si::f32::Pressure, 1> = Watch::new();
+/// Latest measurements, as reported to the host, for the mockloop control
+pub static MEASUREMENTS_WATCH: Watch<Cs, Measurements, 1> = Watch::new();
+/// Latest fluid level of the compliance chambers, for the fill sequencer
+pub static CHAMBER_LEVEL_WATCH: Watch<Cs, ChamberLevels, 1> = Watch::new();
 
 static mut DMA_BUF: [u16; NUM_ADC_INPUTS] = [0u16; NUM_ADC_INPUTS];
 
 #[embassy_executor::task]
 pub async fn read_adc(
     mut adc: Adc<'static, ADC1>,
+    mut adc2: Adc<'static, ADC2>,
     mut dma: Peri<'static, DMA1_CH1>,
+    mut adc2_dma: Peri<'static, DMA1_CH2>,
     adc_channels: AdcChannels,
-    frame_out: Sender<'static, Cs, AdcFrame, 2>,
+    frame_out: watch::Sender<'static, Cs, AdcFrame, 1>,
 ) {
     info!("starting ADC task");
 
     let mut read_buffer = unsafe { &mut DMA_BUF[..] };
+    let mut flow_controller_buffer = [0u16; 1];
+
+    let regulator_pressure_tx = REGULATOR_PRESSURE_WATCH.sender();
+    let measurements_tx = MEASUREMENTS_WATCH.sender();
+    let chamber_level_tx = CHAMBER_LEVEL_WATCH.sender();
+    let mut phase_rx = LEFT_PHASE_WATCH
+        .receiver()
+        .expect("Update LEFT_PHASE_WATCH N");
+    let mut config_rx = LOOP_CONFIG_WATCH
+        .receiver()
+        .expect("Update LOOP_CONFIG_WATCH N");
+    // The sensors are read through the latest calibration, the nominal one until it is received
+    let mut sensors = SensorCalibrations::default();
+    let mut air_volume = AirVolumeIntegrator::default();
+    let mut ticker = Ticker::every(SAMPLE_PERIOD);
 
     let mut regulator_pressure = adc_channels.regulator_actual_pressure.degrade_adc();
     let mut systemic_flow = adc_channels.systemic_flow.degrade_adc();
@@ -33,9 +80,17 @@ pub async fn read_adc(
     let mut systemic_afterload_pressure = adc_channels.systemic_afterload_pressure.degrade_adc();
     let mut pulmonary_preload_pressure = adc_channels.pulmonary_preload_pressure.degrade_adc();
     let mut pulmonary_afterload_pressure = adc_channels.pulmonary_afterload_pressure.degrade_adc();
+    let mut pressure_air_flow = adc_channels.pressure_air_flow.degrade_adc();
+    let mut vacuum_air_flow = adc_channels.vacuum_air_flow.degrade_adc();
+    let mut systemic_chamber_level = adc_channels.systemic_chamber_level.degrade_adc();
+    let mut pulmonary_chamber_level = adc_channels.pulmonary_chamber_level.degrade_adc();
+    let mut flow_controller_flow = adc_channels.flow_controller_flow.degrade_adc();
 
     loop {
-        adc.read(
+        // The conversion sequences take microseconds, the start of them timestamps the whole frame
+        let timestamp = Instant::now();
+        // The flow controller feedback is the only input on ADC2, both sequences convert at once
+        let adc1_sequence = adc.read(
             dma.reborrow(),
             [
                 (&mut regulator_pressure, SampleTime::CYCLES24_5),
@@ -45,13 +100,23 @@ pub async fn read_adc(
                 (&mut systemic_afterload_pressure, SampleTime::CYCLES24_5),
                 (&mut pulmonary_preload_pressure, SampleTime::CYCLES24_5),
                 (&mut pulmonary_afterload_pressure, SampleTime::CYCLES24_5),
+                (&mut pressure_air_flow, SampleTime::CYCLES24_5),
+                (&mut vacuum_air_flow, SampleTime::CYCLES24_5),
+                (&mut systemic_chamber_level, SampleTime::CYCLES24_5),
+                (&mut pulmonary_chamber_level, SampleTime::CYCLES24_5),
             ]
             .into_iter(),
             &mut read_buffer,
-        )
-        .await;
+        );
+        let adc2_sequence = adc2.read(
+            adc2_dma.reborrow(),
+            [(&mut flow_controller_flow, SampleTime::CYCLES24_5)].into_iter(),
+            &mut flow_controller_buffer,
+        );
+        join(adc1_sequence, adc2_sequence).await;
 
         let frame = AdcFrame {
+            timestamp: timestamp.as_micros(),
             regulator_actual_pressure: read_buffer[0],
             systemic_flow: read_buffer[1],
             pulmonary_flow: read_buffer[2],
@@ -59,18 +124,47 @@ pub async fn read_adc(
             systemic_afterload_pressure: read_buffer[4],
             pulmonary_preload_pressure: read_buffer[5],
             pulmonary_afterload_pressure: read_buffer[6],
+            pressure_air_flow: read_buffer[7],
+            vacuum_air_flow: read_buffer[8],
+            systemic_chamber_level: read_buffer[9],
+            pulmonary_chamber_level: read_buffer[10],
+            flow_controller_flow: flow_controller_buffer[0],
         };
 
         info!("ADC: measured frame: {:?}", frame);
 
-        frame_out.send(frame).await;
+        if let Some(config) = config_rx.try_changed() {
+            sensors = config.sensors;
+        }
+
+        regulator_pressure_tx.send(frame.regulator_pressure());
+        measurements_tx.send(frame.into_measurement(&sensors));
+        chamber_level_tx.send(frame.chamber_levels());
 
-        Timer::after(SAMPLE_PERIOD).await;
+        // Integrate the actuator air flow over the phases of the left ventricle, at the full
+        // sample rate
+        let switch = phase_rx.try_get();
+        if let Some(volumes) = air_volume.update(switch.as_ref(), frame.air_flow()) {
+            debug!(
+                "ADC: beat {} air volume in {:?}mL, out {:?}mL",
+                volumes.beat,
+                volumes.air_in().get::<milliliter>(),
+                volumes.air_out().get::<milliliter>()
+            );
+            publish_status(StatusReport::AirVolume(volumes));
+        }
+
+        // Readers only need the latest frame, so they never hold up sampling
+        frame_out.send(frame);
+
+        ticker.next().await;
     }
 }
 
-#[derive(Format, Serialize)]
+#[derive(Clone, Copy, Format, Serialize)]
 pub struct AdcFrame {
+    /// Device time the frame was sampled at in microseconds
+    pub timestamp: u64,
     pub regulator_actual_pressure: u16,
     pub systemic_flow: u16,
     pub pulmonary_flow: u16,
@@ -78,33 +172,88 @@ pub struct AdcFrame {
     pub systemic_afterload_pressure: u16,
     pub pulmonary_preload_pressure: u16,
     pub pulmonary_afterload_pressure: u16,
+    pub pressure_air_flow: u16,
+    pub vacuum_air_flow: u16,
+    pub systemic_chamber_level: u16,
+    pub pulmonary_chamber_level: u16,
+    pub flow_controller_flow: u16,
 }
 
 impl AdcFrame {
-    /// Convert an adc frame to si units and collect into a measurement set
-    pub fn into_measurement(self) -> Measurements {
-        use uom::si::pressure::*;
-        use uom::si::volume_rate::*;
+    /// Pressure regulator feedback, the feedback output spans the same range as the setpoint input
+    pub fn regulator_pressure(&self) -> uom::si::f32::Pressure {
+        use uom::si::pressure::bar;
+
+        let fraction = self.regulator_actual_pressure as f32 / ADC_MAX_VALUE;
+
+        uom::si::f32::Pressure::new::<bar>(
+            RegulatorSetpoint::REGULATOR_MIN_PRESSURE_BAR
+                + fraction
+                    * (RegulatorSetpoint::REGULATOR_MAX_PRESSURE_BAR
+                        - RegulatorSetpoint::REGULATOR_MIN_PRESSURE_BAR),
+        )
+    }
+
+    /// Air flow on the driveline pressure and vacuum lines
+    pub fn air_flow(&self) -> AirFlowSample {
+        use uom::si::{f32::VolumeRate, volume_rate::liter_per_minute};
+
+        let to_flow = |raw: u16| {
+            VolumeRate::new::<liter_per_minute>(raw as f32 / ADC_MAX_VALUE * AIR_FLOW_MAX_LPM)
+        };
+
+        AirFlowSample {
+            pressure_line: to_flow(self.pressure_air_flow),
+            vacuum_line: to_flow(self.vacuum_air_flow),
+            timestamp: Instant::from_micros(self.timestamp),
+        }
+    }
+
+    /// Actual flow of the mass-flow controller, the feedback output spans the same range as the
+    /// setpoint input
+    pub fn flow_controller_flow(&self) -> uom::si::f32::VolumeRate {
+        use uom::si::{f32::VolumeRate, volume_rate::liter_per_minute};
+
+        VolumeRate::new::<liter_per_minute>(
+            self.flow_controller_flow as f32 / ADC_MAX_VALUE
+                * FlowControllerSetpoint::FLOW_CONTROLLER_MAX_FLOW_LPM,
+        )
+    }
+
+    /// Fluid level of the compliance chambers, the level sensors span the full chamber height
+    pub fn chamber_levels(&self) -> ChamberLevels {
+        use uom::si::{f32::Length, length::millimeter};
+
+        let to_level = |raw: u16| {
+            Length::new::<millimeter>(raw as f32 / ADC_MAX_VALUE * LEVEL_SENSOR_RANGE_MM)
+        };
+
+        ChamberLevels {
+            systemic: to_level(self.systemic_chamber_level),
+            pulmonary: to_level(self.pulmonary_chamber_level),
+        }
+    }
 
+    /// Convert an adc frame to si units through the sensor `calibrations` and collect into a
+    /// measurement set
+    pub fn into_measurement(self, calibrations: &SensorCalibrations) -> Measurements {
         Measurements {
-            timestamp: Instant::now().as_micros(),
-            regulator_actual_pressure: Pressure::new::<millimeter_of_mercury>(
-                self.regulator_actual_pressure.into(),
-            ),
-            systemic_flow: VolumeRate::new::<liter_per_minute>(self.systemic_flow.into()),
-            pulmonary_flow: VolumeRate::new::<liter_per_minute>(self.pulmonary_flow.into()),
-            systemic_preload_pressure: Pressure::new::<millimeter_of_mercury>(
-                self.systemic_preload_pressure.into(),
-            ),
-            systemic_afterload_pressure: Pressure::new::<millimeter_of_mercury>(
-                self.systemic_afterload_pressure.into(),
-            ),
-            pulmonary_preload_pressure: Pressure::new::<millimeter_of_mercury>(
-                self.pulmonary_preload_pressure.into(),
-            ),
-            pulmonary_afterload_pressure: Pressure::new::<millimeter_of_mercury>(
-                self.pulmonary_afterload_pressure.into(),
-            ),
+            timestamp: self.timestamp,
+            regulator_actual_pressure: self.regulator_pressure(),
+            systemic_flow: calibrations.systemic_flow.flow(self.systemic_flow),
+            pulmonary_flow: calibrations.pulmonary_flow.flow(self.pulmonary_flow),
+            systemic_preload_pressure: calibrations
+                .systemic_preload_pressure
+                .pressure(self.systemic_preload_pressure),
+            systemic_afterload_pressure: calibrations
+                .systemic_afterload_pressure
+                .pressure(self.systemic_afterload_pressure),
+            pulmonary_preload_pressure: calibrations
+                .pulmonary_preload_pressure
+                .pressure(self.pulmonary_preload_pressure),
+            pulmonary_afterload_pressure: calibrations
+                .pulmonary_afterload_pressure
+                .pressure(self.pulmonary_afterload_pressure),
         }
     }
 }
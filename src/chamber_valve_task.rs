@@ -0,0 +1,56 @@
+use defmt::*;
+use embassy_stm32::gpio::Output;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, watch::Watch};
+
+use crate::loop_control::fill::{ChamberValves, PairValves};
+
+/// Fill and drain valve positions of the compliance chamber pairs
+pub static CHAMBER_VALVE_WATCH: Watch<Cs, ChamberValves, 1> = Watch::new();
+
+/// Normally closed fill and drain solenoid valves of both compliance chamber pairs, a high output
+/// opens the valve
+pub struct ChamberValveOutputs {
+    pub systemic_fill: Output<'static>,
+    pub systemic_drain: Output<'static>,
+    pub pulmonary_fill: Output<'static>,
+    pub pulmonary_drain: Output<'static>,
+}
+
+impl ChamberValveOutputs {
+    fn actuate(&mut self, valves: &ChamberValves) {
+        let set = |output: &mut Output<'static>, open: bool| {
+            if open {
+                output.set_high()
+            } else {
+                output.set_low()
+            }
+        };
+        let PairValves { fill, drain } = valves.systemic;
+        set(&mut self.systemic_fill, fill);
+        set(&mut self.systemic_drain, drain);
+        let PairValves { fill, drain } = valves.pulmonary;
+        set(&mut self.pulmonary_fill, fill);
+        set(&mut self.pulmonary_drain, drain);
+    }
+}
+
+/// Compliance chamber valve routine
+/// Drives the fill and drain valves as the fill sequencer asks
+#[embassy_executor::task]
+pub async fn control_chamber_valves(mut outputs: ChamberValveOutputs) {
+    info!("starting CHAMBER VALVE task");
+
+    let mut rx = CHAMBER_VALVE_WATCH
+        .receiver()
+        .expect("Increase chamber valve watch size");
+
+    outputs.actuate(&ChamberValves::CLOSED);
+
+    info!("starting CHAMBER VALVE loop");
+    loop {
+        // Wait for valve actuation request
+        let valves = rx.changed().await;
+        trace!("CHAMBER VALVE: valves to {}", valves);
+        outputs.actuate(&valves);
+    }
+}
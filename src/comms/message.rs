@@ -0,0 +1,455 @@
+//! Extension messages between the host and the firmware
+//! Firmware settings that are not part of the love-letter [`love_letter::Setpoint`] travel as
+//! COBS frames of their own on the same UART. Each frame holds a postcard encoded
+//! `(EXTENSION_TAG, EXTENSION_VERSION, message)` tuple. Love-letter messages start with an
+//! `Option` tag of 0 or 1, so the tag tells both kinds of frame apart. The version keeps a host
+//! built against other extension messages from being misread, until they move into love-letter.
+//! The firmware reports status next to the love-letter [`love_letter::Report`] the same way. Status
+//! is sent as it happens rather than sampled at the report rate, so no beat goes unreported.
+
+use serde::{Deserialize, Serialize};
+use uom::si::f32::VolumeRate;
+
+use crate::{
+    comms::validation::{SetpointLimits, SetpointValidation},
+    heart_control::{
+        actuator_profile::{ProfileAck, ProfileCommand},
+        air_volume::BeatVolumes,
+        beat::{BeatEvent, BeatTiming, RrInterval},
+        config::HeartConfig,
+        pressure_loop::PressureLoopStatus,
+        priming::PrimingStatus,
+    },
+    loop_control::{
+        compliance_loop::ComplianceStatus,
+        config::LoopConfig,
+        fill::{FillCommand, FillProgress, FillStatus},
+        preset::{ActivePreset, PresetAck, PresetCommand},
+        resistance_loop::ResistanceStatus,
+    },
+};
+
+/// First byte of every extension message, never the first byte of a love-letter message
+pub const EXTENSION_TAG: u8 = 0xE5;
+/// Version of the extension messages, bumped on every change that breaks their encoding
+/// Frames of any other version are rejected rather than decoded into the wrong message
+pub const EXTENSION_VERSION: u8 = 1;
+
+/// Longest frame received from the host, extension messages carry more than a setpoint
+pub const FRAME_BYTES: usize = max(love_letter::SETPOINT_BYTES * 4, 512);
+/// Longest serialised [`StatusReport`]
+pub const STATUS_BYTES: usize = 128;
+
+/// Command sent by the host
+// Commands are decoded one at a time and there is no heap to box the configurations in
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum HostCommand {
+    /// Replace the firmware side heart configuration
+    HeartConfig(HeartConfig),
+    /// Replace the firmware side mockloop configuration
+    LoopConfig(LoopConfig),
+    /// Restart the configured start-up sequence of the beating heart
+    StartPriming,
+    /// Replace the limits host setpoints are validated against
+    SetpointLimits(SetpointLimits),
+    /// Set the system flow through the mass-flow controller, validated like a setpoint
+    FlowSetpoint(VolumeRate),
+    /// Select or store an actuator profile, acknowledged with [`StatusReport::ProfileAck`]
+    ActuatorProfile(ProfileCommand),
+    /// Select or store a patient condition preset, acknowledged with [`StatusReport::PresetAck`]
+    Preset(PresetCommand),
+    /// Fill, drain or purge the compliance chambers, followed with [`StatusReport::Fill`] and
+    /// [`StatusReport::FillProgress`]
+    Fill(FillCommand),
+}
+
+/// Status sent to the host
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StatusReport {
+    /// A beat started
+    RrInterval(RrInterval),
+    /// Latest beat scheduling statistics, at the report rate while the heart beats
+    BeatTiming(BeatTiming),
+    /// A ventricle switched phase, lets the host segment its signals per beat
+    BeatEvent(BeatEvent),
+    /// Latest update of the closed-loop pressure control, at the report rate while it is enabled
+    PressureLoop(PressureLoopStatus),
+    /// Progress of the start-up sequence, while it runs and once it ends
+    Priming(PrimingStatus),
+    /// A setpoint failed validation and was rejected or clamped
+    SetpointValidation(SetpointValidation),
+    /// Outcome of an actuator profile command
+    ProfileAck(ProfileAck),
+    /// Actuator air volumes of a beat, once it is complete
+    AirVolume(BeatVolumes),
+    /// Estimated resistance of a circuit, once per beat while the mockloop is enabled
+    Resistance(ResistanceStatus),
+    /// Estimated afterload compliance of a circuit, once per beat while the mockloop is enabled
+    Compliance(ComplianceStatus),
+    /// Outcome of a preset command
+    PresetAck(PresetAck),
+    /// Preset the setpoint follows and the progress towards it, at every step of the transition,
+    /// `None` once the host sends a setpoint of its own
+    ActivePreset(Option<ActivePreset>),
+    /// State of the chamber fill and drain sequencer, on every change
+    Fill(FillStatus),
+    /// Fluid levels of a running fill or drain sequence, at every level check
+    FillProgress(FillProgress),
+    /// Actual flow of the mass-flow controller, measured on its feedback output at the report rate
+    FlowControllerFlow(VolumeRate),
+}
+
+/// Whether the COBS encoded `frame`, without its delimiter, holds an extension message
+/// The first byte of a COBS frame is the distance to the first zero, the first message byte only
+/// follows it if it is not zero itself
+pub fn is_extension_frame(frame: &[u8]) -> bool {
+    match frame {
+        [code, first, ..] if *code > 1 => *first == EXTENSION_TAG,
+        _ => false,
+    }
+}
+
+/// Decode a COBS encoded host command in place
+pub fn deserialize_command(frame: &mut [u8]) -> postcard::Result<HostCommand> {
+    match postcard::from_bytes_cobs(frame)? {
+        (EXTENSION_TAG, EXTENSION_VERSION, command) => Ok(command),
+        (_, _, _) => Err(postcard::Error::DeserializeBadEncoding),
+    }
+}
+
+/// COBS encode a status report into `buf`, delimiter included
+pub fn serialize_status<'a>(
+    status: &StatusReport,
+    buf: &'a mut [u8],
+) -> postcard::Result<&'a mut [u8]> {
+    postcard::to_slice_cobs(&(EXTENSION_TAG, EXTENSION_VERSION, status), buf)
+}
+
+/// [`Duration`](embassy_time::Duration) fields in whole microseconds, as the report timestamps
+pub mod duration_us {
+    use embassy_time::Duration;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_micros())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_micros)
+    }
+}
+
+/// Optional [`Duration`](embassy_time::Duration) fields in whole microseconds
+pub mod option_duration_us {
+    use embassy_time::Duration;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        duration
+            .map(|duration| duration.as_micros())
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<u64>::deserialize(deserializer).map(|us| us.map(Duration::from_micros))
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_time::Duration;
+    use uom::si::{
+        f32::{Length, Time, Volume},
+        length::millimeter,
+        time::millisecond,
+        volume::milliliter,
+        volume_rate::liter_per_minute,
+    };
+
+    use crate::{
+        comms::validation::ValidationPolicy,
+        heart_control::{
+            actuator_profile::{ActuatorProfile, ProfileName},
+            air_volume::PhaseVolumes,
+            config::VentricleSetpoint,
+            phase::{CardiacPhase, Ventricle},
+            priming::PrimingStage,
+        },
+        loop_control::{
+            config::Circuit,
+            error::FillError,
+            fill::{ChamberLevels, Chambers, FillOperation},
+            preset::{Preset, PresetName},
+            sensor::SensorCalibration,
+        },
+    };
+
+    /// COBS frame of `message` as the host sends it, without the delimiter
+    fn frame<T: Serialize>(message: &T, buf: &mut [u8]) -> usize {
+        let len = postcard::to_slice_cobs(message, buf).unwrap().len();
+        assert_eq!(buf[len - 1], 0);
+
+        len - 1
+    }
+
+    #[test]
+    fn test_heart_config_command() {
+        let command = HostCommand::HeartConfig(HeartConfig {
+            ventricles: Some(VentricleSetpoint {
+                left_systole_ratio: 0.4,
+                right_systole_ratio: 0.35,
+                interventricular_delay: Time::new::<millisecond>(40.0),
+            }),
+            ..Default::default()
+        });
+        let mut buf = [0u8; FRAME_BYTES];
+
+        let len = frame(&(EXTENSION_TAG, EXTENSION_VERSION, &command), &mut buf);
+
+        assert!(is_extension_frame(&buf[..len]));
+        assert_eq!(deserialize_command(&mut buf[..len]), Ok(command));
+    }
+
+    #[test]
+    fn test_loop_config_command() {
+        let mut config = LoopConfig::default();
+        config.sensors.systemic_flow = SensorCalibration {
+            offset: -0.4,
+            span: 31.2,
+        };
+        let command = HostCommand::LoopConfig(config);
+        let mut buf = [0u8; FRAME_BYTES];
+
+        let len = frame(&(EXTENSION_TAG, EXTENSION_VERSION, &command), &mut buf);
+
+        assert_eq!(deserialize_command(&mut buf[..len]), Ok(command));
+    }
+
+    #[test]
+    fn test_setpoint_limits_command() {
+        let command = HostCommand::SetpointLimits(SetpointLimits {
+            policy: ValidationPolicy::Clamp,
+            ..SetpointLimits::default()
+        });
+        let mut buf = [0u8; FRAME_BYTES];
+
+        let len = frame(&(EXTENSION_TAG, EXTENSION_VERSION, &command), &mut buf);
+
+        assert_eq!(deserialize_command(&mut buf[..len]), Ok(command));
+    }
+
+    #[test]
+    fn test_flow_setpoint_command() {
+        let command = HostCommand::FlowSetpoint(VolumeRate::new::<liter_per_minute>(4.5));
+        let mut buf = [0u8; FRAME_BYTES];
+
+        let len = frame(&(EXTENSION_TAG, EXTENSION_VERSION, &command), &mut buf);
+
+        assert_eq!(deserialize_command(&mut buf[..len]), Ok(command));
+    }
+
+    #[test]
+    fn test_actuator_profile_command() {
+        let [_, regulator] = ActuatorProfile::built_in();
+        let command = HostCommand::ActuatorProfile(ProfileCommand::Store(ActuatorProfile {
+            name: ProfileName::try_from("prototype 2").unwrap(),
+            ..regulator
+        }));
+        let mut buf = [0u8; FRAME_BYTES];
+
+        let len = frame(&(EXTENSION_TAG, EXTENSION_VERSION, &command), &mut buf);
+
+        assert_eq!(deserialize_command(&mut buf[..len]), Ok(command));
+    }
+
+    #[test]
+    fn test_preset_command() {
+        let [_, hypertension, ..] = Preset::built_in();
+        let commands = [
+            HostCommand::Preset(PresetCommand::Select {
+                name: PresetName::try_from("hfref").unwrap(),
+                transition: Duration::from_secs(30),
+            }),
+            HostCommand::Preset(PresetCommand::Store(Preset {
+                name: PresetName::try_from("sepsis").unwrap(),
+                ..hypertension
+            })),
+        ];
+        let mut buf = [0u8; FRAME_BYTES];
+
+        for command in commands {
+            let len = frame(&(EXTENSION_TAG, EXTENSION_VERSION, &command), &mut buf);
+
+            assert_eq!(deserialize_command(&mut buf[..len]), Ok(command));
+        }
+    }
+
+    #[test]
+    fn test_fill_command() {
+        let command = HostCommand::Fill(FillCommand::Fill {
+            chambers: Chambers::Pulmonary,
+            level: Length::new::<millimeter>(18.0),
+        });
+        let mut buf = [0u8; FRAME_BYTES];
+
+        let len = frame(&(EXTENSION_TAG, EXTENSION_VERSION, &command), &mut buf);
+
+        assert_eq!(deserialize_command(&mut buf[..len]), Ok(command));
+    }
+
+    #[test]
+    fn test_love_letter_frames_are_not_extensions() {
+        let mut buf = [0u8; 32];
+
+        // Messages starting with `None` or `Some`, as every love-letter message does
+        let len = frame(&(None::<f32>, EXTENSION_TAG), &mut buf);
+        assert!(!is_extension_frame(&buf[..len]));
+        let len = frame(&(Some(EXTENSION_TAG), EXTENSION_TAG), &mut buf);
+        assert!(!is_extension_frame(&buf[..len]));
+
+        assert!(!is_extension_frame(&[]));
+    }
+
+    #[test]
+    fn test_status_report() {
+        let statuses = [
+            StatusReport::RrInterval(RrInterval {
+                beat: 3,
+                interval: Duration::from_micros(857_142),
+            }),
+            StatusReport::BeatTiming(BeatTiming {
+                beats: 1_000,
+                max_jitter: Duration::from_micros(85),
+                resyncs: 1,
+                ..Default::default()
+            }),
+            StatusReport::Priming(PrimingStatus {
+                stage: PrimingStage::Ramp,
+                progress: 0.75,
+            }),
+            StatusReport::AirVolume(BeatVolumes {
+                beat: 12,
+                systole: PhaseVolumes {
+                    pressure_line: Volume::new::<milliliter>(62.5),
+                    vacuum_line: Volume::new::<milliliter>(0.5),
+                },
+                diastole: PhaseVolumes {
+                    pressure_line: Volume::new::<milliliter>(0.0),
+                    vacuum_line: Volume::new::<milliliter>(61.0),
+                },
+            }),
+            StatusReport::Resistance(ResistanceStatus {
+                circuit: Circuit::Pulmonary,
+                beat: 12,
+                setpoint: 0.1,
+                measured: 0.12,
+                trim: -2.5,
+            }),
+            StatusReport::ActivePreset(Some(ActivePreset {
+                name: PresetName::try_from("healthy-rest").unwrap(),
+                version: 1,
+                progress: 0.25,
+            })),
+            StatusReport::Fill(FillStatus::Failed {
+                operation: FillOperation::Drain,
+                chambers: Chambers::All,
+                error: FillError::Timeout,
+            }),
+            StatusReport::FillProgress(FillProgress {
+                operation: FillOperation::Fill,
+                chambers: Chambers::Systemic,
+                levels: Some(ChamberLevels {
+                    systemic: Length::new::<millimeter>(12.5),
+                    pulmonary: Length::new::<millimeter>(14.0),
+                }),
+                elapsed: Duration::from_millis(4_200),
+            }),
+            StatusReport::Compliance(ComplianceStatus {
+                circuit: Circuit::Systemic,
+                beat: 12,
+                setpoint: 1.2,
+                measured: 1.05,
+                trim: 0.04,
+            }),
+            StatusReport::FlowControllerFlow(VolumeRate::new::<liter_per_minute>(4.5)),
+        ];
+        let mut buf = [0u8; STATUS_BYTES];
+
+        for status in statuses {
+            let len = serialize_status(&status, &mut buf).unwrap().len();
+
+            assert!(is_extension_frame(&buf[..len - 1]));
+            assert_eq!(
+                postcard::from_bytes_cobs(&mut buf[..len]),
+                Ok((EXTENSION_TAG, EXTENSION_VERSION, status))
+            );
+        }
+    }
+
+    #[test]
+    fn test_beat_event_status() {
+        let event = |ended_phase_duration| {
+            StatusReport::BeatEvent(BeatEvent {
+                beat: 1,
+                ventricle: Ventricle::Right,
+                phase: CardiacPhase::Diastole,
+                profile_phase: 1,
+                timestamp: 1_250_000,
+                ended_phase_duration,
+            })
+        };
+        let mut buf = [0u8; STATUS_BYTES];
+
+        for status in [event(None), event(Some(Duration::from_millis(250)))] {
+            let len = serialize_status(&status, &mut buf).unwrap().len();
+
+            assert_eq!(
+                postcard::from_bytes_cobs(&mut buf[..len]),
+                Ok((EXTENSION_TAG, EXTENSION_VERSION, status))
+            );
+        }
+    }
+
+    #[test]
+    fn test_wrong_tag_rejected() {
+        let mut buf = [0u8; FRAME_BYTES];
+
+        let len = frame(
+            &(
+                EXTENSION_TAG + 1,
+                EXTENSION_VERSION,
+                HostCommand::HeartConfig(HeartConfig::default()),
+            ),
+            &mut buf,
+        );
+
+        assert!(deserialize_command(&mut buf[..len]).is_err());
+    }
+
+    #[test]
+    fn test_wrong_version_rejected() {
+        let mut buf = [0u8; FRAME_BYTES];
+
+        let len = frame(
+            &(
+                EXTENSION_TAG,
+                EXTENSION_VERSION + 1,
+                HostCommand::HeartConfig(HeartConfig::default()),
+            ),
+            &mut buf,
+        );
+
+        assert!(deserialize_command(&mut buf[..len]).is_err());
+    }
+}
@@ -1,2 +1,5 @@
 pub mod connection_state;
+pub mod message;
+#[cfg(target_os = "none")]
 pub mod task;
+pub mod validation;
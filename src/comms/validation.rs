@@ -0,0 +1,445 @@
+//! Host setpoint validation
+//! Checks every deserialised [`Setpoint`] against physical limits before it reaches the controllers,
+//! and tells which field failed and why. The system flow setpoint, not part of the love-letter
+//! [`Setpoint`], is checked the same way
+
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, watch::Watch};
+use love_letter::Setpoint;
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    f32::{Frequency, Pressure, VolumeRate},
+    frequency::cycle_per_minute,
+    pressure::bar,
+    volume_rate::liter_per_minute,
+};
+
+use crate::dac::setpoint::{FlowControllerSetpoint, RegulatorSetpoint};
+
+/// Limits host setpoints are validated against, defaults until one is received
+pub static SETPOINT_LIMITS_WATCH: Watch<Cs, SetpointLimits, 2> = Watch::new();
+
+/// Number of validated setpoint fields
+pub const SETPOINT_FIELDS: usize = 8;
+
+/// Validated field of a [`Setpoint`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum SetpointField {
+    HeartRate,
+    HeartPressure,
+    SystoleRatio,
+    SystemicCompliance,
+    PulmonaryCompliance,
+    SystemicResistance,
+    PulmonaryResistance,
+    SystemFlow,
+}
+
+/// Why a field failed validation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum Violation {
+    /// NaN or infinite, never clamped
+    NotFinite,
+    BelowMinimum,
+    AboveMaximum,
+}
+
+/// A field outside of its limits
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct FieldViolation {
+    pub field: SetpointField,
+    pub violation: Violation,
+    /// Value as received, in the unit of its [`Limit`]
+    pub value: f32,
+}
+
+/// Outcome of validating a setpoint that did not pass as is
+#[derive(Debug, Clone, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub enum SetpointValidation {
+    /// The setpoint is dropped, the controllers keep the previous one
+    Rejected(FieldViolation),
+    /// The setpoint is forwarded with these fields clamped to their limits
+    Clamped(heapless::Vec<FieldViolation, SETPOINT_FIELDS>),
+}
+
+/// What to do with a setpoint outside of the limits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum ValidationPolicy {
+    /// Drop the whole setpoint
+    #[default]
+    Reject,
+    /// Clamp offending fields to their limits, values that are not finite are still rejected
+    Clamp,
+}
+
+/// Inclusive range of valid values
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct Limit {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Limit {
+    pub const fn new(min: f32, max: f32) -> Self {
+        Self { min, max }
+    }
+
+    pub fn check(&self, value: f32) -> Result<(), Violation> {
+        if !value.is_finite() {
+            Err(Violation::NotFinite)
+        } else if value < self.min {
+            Err(Violation::BelowMinimum)
+        } else if value > self.max {
+            Err(Violation::AboveMaximum)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Per field limits of host setpoints
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct SetpointLimits {
+    pub policy: ValidationPolicy,
+    /// Heart rate in beats per minute, 0 stops the heart
+    pub heart_rate: Limit,
+    /// Driveline pressure in bar
+    pub heart_pressure: Limit,
+    /// Fraction of the cardiac cycle spent in systole, neither systole nor diastole may vanish
+    pub systole_ratio: Limit,
+    /// Systemic and pulmonary compliance in mL/mmHg
+    pub compliance: Limit,
+    /// Systemic and pulmonary resistance in mmHg·s/mL
+    pub resistance: Limit,
+    /// System flow through the mass-flow controller in L/min
+    pub flow: Limit,
+}
+
+impl Default for SetpointLimits {
+    fn default() -> Self {
+        Self {
+            policy: ValidationPolicy::Reject,
+            heart_rate: Limit::new(0.0, 240.0),
+            heart_pressure: Limit::new(
+                RegulatorSetpoint::REGULATOR_MIN_PRESSURE_BAR,
+                RegulatorSetpoint::REGULATOR_MAX_PRESSURE_BAR,
+            ),
+            systole_ratio: Limit::new(0.05, 0.95),
+            compliance: Limit::new(0.0, 10.0),
+            resistance: Limit::new(0.0, 10.0),
+            flow: Limit::new(0.0, FlowControllerSetpoint::FLOW_CONTROLLER_MAX_FLOW_LPM),
+        }
+    }
+}
+
+impl SetpointLimits {
+    /// Check `setpoint` against the limits, clamping it in place when the policy allows
+    /// Returns `None` if the setpoint passed as is, a rejected setpoint must not be forwarded
+    pub fn validate(&self, setpoint: &mut Setpoint) -> Option<SetpointValidation> {
+        self.outcome(|validator| self.check_fields(validator, setpoint))
+    }
+
+    /// Check a system `flow` setpoint against the limits, like [`SetpointLimits::validate`]
+    pub fn validate_flow(&self, flow: &mut VolumeRate) -> Option<SetpointValidation> {
+        self.outcome(|validator| {
+            let lpm = flow.get::<liter_per_minute>();
+            let checked = validator.check(SetpointField::SystemFlow, &self.flow, lpm)?;
+            if checked != lpm {
+                *flow = VolumeRate::new::<liter_per_minute>(checked);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn outcome(
+        &self,
+        check: impl FnOnce(&mut Validator) -> Result<(), FieldViolation>,
+    ) -> Option<SetpointValidation> {
+        let mut validator = Validator::new(self.policy);
+
+        match check(&mut validator) {
+            Err(violation) => Some(SetpointValidation::Rejected(violation)),
+            Ok(()) if validator.clamped.is_empty() => None,
+            Ok(()) => Some(SetpointValidation::Clamped(validator.clamped)),
+        }
+    }
+
+    fn check_fields(
+        &self,
+        validator: &mut Validator,
+        setpoint: &mut Setpoint,
+    ) -> Result<(), FieldViolation> {
+        if let Some(heart) = &mut setpoint.heart_controller_setpoint {
+            // Quantities are only written back when clamped, converting units is not lossless
+            let bpm = heart.heart_rate.get::<cycle_per_minute>();
+            let heart_rate = validator.check(SetpointField::HeartRate, &self.heart_rate, bpm)?;
+            if heart_rate != bpm {
+                heart.heart_rate = Frequency::new::<cycle_per_minute>(heart_rate);
+            }
+
+            let pressure_bar = heart.pressure.get::<bar>();
+            let pressure = validator.check(
+                SetpointField::HeartPressure,
+                &self.heart_pressure,
+                pressure_bar,
+            )?;
+            if pressure != pressure_bar {
+                heart.pressure = Pressure::new::<bar>(pressure);
+            }
+
+            heart.systole_ratio = validator.check(
+                SetpointField::SystoleRatio,
+                &self.systole_ratio,
+                heart.systole_ratio,
+            )?;
+        }
+
+        if let Some(mockloop) = &mut setpoint.mockloop_setpoint {
+            mockloop.systemic_afterload_compliance = validator.check(
+                SetpointField::SystemicCompliance,
+                &self.compliance,
+                mockloop.systemic_afterload_compliance,
+            )?;
+            mockloop.pulmonary_afterload_compliance = validator.check(
+                SetpointField::PulmonaryCompliance,
+                &self.compliance,
+                mockloop.pulmonary_afterload_compliance,
+            )?;
+            mockloop.systemic_resistance = validator.check(
+                SetpointField::SystemicResistance,
+                &self.resistance,
+                mockloop.systemic_resistance,
+            )?;
+            mockloop.pulmonary_resistance = validator.check(
+                SetpointField::PulmonaryResistance,
+                &self.resistance,
+                mockloop.pulmonary_resistance,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Applies the validation policy field by field, collecting the clamped fields
+struct Validator {
+    policy: ValidationPolicy,
+    clamped: heapless::Vec<FieldViolation, SETPOINT_FIELDS>,
+}
+
+impl Validator {
+    fn new(policy: ValidationPolicy) -> Self {
+        Self {
+            policy,
+            clamped: heapless::Vec::new(),
+        }
+    }
+
+    /// Value to use for `field`, or the violation rejecting the setpoint
+    fn check(
+        &mut self,
+        field: SetpointField,
+        limit: &Limit,
+        value: f32,
+    ) -> Result<f32, FieldViolation> {
+        let Err(violation) = limit.check(value) else {
+            return Ok(value);
+        };
+        let field_violation = FieldViolation {
+            field,
+            violation,
+            value,
+        };
+
+        match (self.policy, violation) {
+            (ValidationPolicy::Clamp, Violation::BelowMinimum | Violation::AboveMaximum) => {
+                // Holds one entry per field
+                let _ = self.clamped.push(field_violation);
+
+                Ok(value.clamp(limit.min, limit.max))
+            }
+            _ => Err(field_violation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use love_letter::{HeartControllerSetpoint, MockloopSetpoint};
+
+    #[test]
+    fn test_limit() {
+        let limit = Limit::new(0.0, 1.0);
+
+        assert_eq!(limit.check(0.0), Ok(()));
+        assert_eq!(limit.check(1.0), Ok(()));
+        assert_eq!(limit.check(-0.1), Err(Violation::BelowMinimum));
+        assert_eq!(limit.check(1.1), Err(Violation::AboveMaximum));
+        assert_eq!(limit.check(f32::NAN), Err(Violation::NotFinite));
+        assert_eq!(limit.check(f32::INFINITY), Err(Violation::NotFinite));
+    }
+
+    #[test]
+    fn test_reject_policy() {
+        let mut validator = Validator::new(ValidationPolicy::Reject);
+        let limit = Limit::new(0.0, 1.0);
+
+        assert_eq!(
+            validator.check(SetpointField::SystoleRatio, &limit, 0.35),
+            Ok(0.35)
+        );
+        assert_eq!(
+            validator.check(SetpointField::SystoleRatio, &limit, 1.5),
+            Err(FieldViolation {
+                field: SetpointField::SystoleRatio,
+                violation: Violation::AboveMaximum,
+                value: 1.5,
+            })
+        );
+        assert!(validator.clamped.is_empty());
+    }
+
+    #[test]
+    fn test_clamp_policy() {
+        let mut validator = Validator::new(ValidationPolicy::Clamp);
+        let limits = SetpointLimits::default();
+
+        assert_eq!(
+            validator.check(SetpointField::HeartRate, &limits.heart_rate, 300.0),
+            Ok(240.0)
+        );
+        assert_eq!(
+            validator.check(SetpointField::HeartPressure, &limits.heart_pressure, -0.5),
+            Ok(0.0)
+        );
+        assert_eq!(
+            validator
+                .clamped
+                .iter()
+                .map(|v| v.field)
+                .collect::<heapless::Vec<_, 2>>(),
+            [SetpointField::HeartRate, SetpointField::HeartPressure]
+        );
+
+        // There is no sensible value to clamp NaN to
+        assert_eq!(
+            validator
+                .check(SetpointField::SystoleRatio, &limits.systole_ratio, f32::NAN)
+                .map_err(|violation| violation.violation),
+            Err(Violation::NotFinite)
+        );
+    }
+
+    fn setpoint(bpm: f32, systole_ratio: f32, resistance: f32) -> Setpoint {
+        Setpoint {
+            heart_controller_setpoint: Some(HeartControllerSetpoint {
+                heart_rate: Frequency::new::<cycle_per_minute>(bpm),
+                systole_ratio,
+                pressure: Pressure::new::<bar>(1.0),
+            }),
+            mockloop_setpoint: Some(MockloopSetpoint {
+                systemic_resistance: resistance,
+                pulmonary_resistance: 0.1,
+                systemic_afterload_compliance: 0.15,
+                pulmonary_afterload_compliance: 0.2,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_validate_setpoint() {
+        let limits = SetpointLimits::default();
+
+        let mut valid = setpoint(70.0, 0.35, 1.0);
+        assert_eq!(limits.validate(&mut valid), None);
+        assert_eq!(valid, setpoint(70.0, 0.35, 1.0));
+
+        // The first offending field rejects the whole setpoint
+        assert_eq!(
+            limits.validate(&mut setpoint(300.0, 0.35, 12.0)),
+            Some(SetpointValidation::Rejected(FieldViolation {
+                field: SetpointField::HeartRate,
+                violation: Violation::AboveMaximum,
+                value: 300.0,
+            }))
+        );
+        assert_eq!(
+            limits
+                .validate(&mut Setpoint {
+                    heart_controller_setpoint: None,
+                    ..setpoint(70.0, 0.35, f32::NAN)
+                })
+                .map(|validation| matches!(validation, SetpointValidation::Rejected(_))),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_validate_clamps_setpoint() {
+        let limits = SetpointLimits {
+            policy: ValidationPolicy::Clamp,
+            ..SetpointLimits::default()
+        };
+        let mut clamped = setpoint(300.0, 0.35, 12.0);
+
+        let Some(SetpointValidation::Clamped(violations)) = limits.validate(&mut clamped) else {
+            panic!("setpoint not clamped");
+        };
+        assert_eq!(
+            violations
+                .iter()
+                .map(|v| v.field)
+                .collect::<heapless::Vec<_, 2>>(),
+            [SetpointField::HeartRate, SetpointField::SystemicResistance]
+        );
+        assert_eq!(clamped, setpoint(240.0, 0.35, 10.0));
+    }
+
+    #[test]
+    fn test_validate_flow() {
+        let lpm = VolumeRate::new::<liter_per_minute>;
+        let mut limits = SetpointLimits::default();
+
+        let mut flow = lpm(4.5);
+        assert_eq!(limits.validate_flow(&mut flow), None);
+        assert_eq!(flow, lpm(4.5));
+
+        // A negative flow cannot be set, the controller only opens one way
+        assert_eq!(
+            limits.validate_flow(&mut lpm(-1.0)),
+            Some(SetpointValidation::Rejected(FieldViolation {
+                field: SetpointField::SystemFlow,
+                violation: Violation::BelowMinimum,
+                value: -1.0,
+            }))
+        );
+
+        limits.policy = ValidationPolicy::Clamp;
+        let mut flow = lpm(12.0);
+        assert_eq!(
+            limits
+                .validate_flow(&mut flow)
+                .map(|validation| matches!(validation, SetpointValidation::Clamped(_))),
+            Some(true)
+        );
+        assert_eq!(flow, lpm(10.0));
+    }
+
+    #[test]
+    fn test_systole_ratio_leaves_both_phases() {
+        let limits = SetpointLimits::default();
+
+        for systole_ratio in [0.0, 1.0] {
+            assert_eq!(
+                limits
+                    .validate(&mut setpoint(70.0, systole_ratio, 1.0))
+                    .map(|validation| match validation {
+                        SetpointValidation::Rejected(violation) => violation.field,
+                        SetpointValidation::Clamped(_) => panic!("setpoint clamped"),
+                    }),
+                Some(SetpointField::SystoleRatio)
+            );
+        }
+    }
+}
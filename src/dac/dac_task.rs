@@ -1,24 +1,26 @@
 use defmt::*;
-use embassy_futures::select::select3;
+use embassy_futures::select::select4;
 use embassy_stm32::{
     dac::{Ch1, Ch2, DacChannel},
     mode::Async,
-    peripherals::{DAC1, DAC2},
+    peripherals::{DAC1, DAC2, DAC4},
 };
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, watch::Watch};
-use uom::si::f32::Pressure;
+use uom::si::f32::{Pressure, VolumeRate};
 
 use crate::dac::endpoint::{DacEndpoint, DacId, handle_endpoint};
 
 pub static DAC_HEART_PRESSURE_WATCH: Watch<Cs, Pressure, 1> = Watch::new();
 pub static DAC_SYSTEMIC_COMPLIANCE_WATCH: Watch<Cs, Pressure, 1> = Watch::new();
 pub static DAC_PULMONARY_COMPLIANCE_WATCH: Watch<Cs, Pressure, 1> = Watch::new();
+pub static DAC_FLOW_CONTROLLER_WATCH: Watch<Cs, VolumeRate, 1> = Watch::new();
 
 #[embassy_executor::task]
 pub async fn write_dac(
     heart_pressure_dac: DacChannel<'static, DAC1, Ch1, Async>,
     systemic_compliance_dac: DacChannel<'static, DAC1, Ch2, Async>,
     pulmonary_compliance_dac: DacChannel<'static, DAC2, Ch1, Async>,
+    flow_controller_dac: DacChannel<'static, DAC4, Ch1, Async>,
 ) {
     info!("starting DAC task");
 
@@ -46,12 +48,21 @@ pub async fn write_dac(
             .expect("increase pulmonary compliance pressure N"),
     };
 
+    let mut flow_controller_endpoint = DacEndpoint {
+        id: DacId::FlowController,
+        dac: flow_controller_dac,
+        rx: DAC_FLOW_CONTROLLER_WATCH
+            .receiver()
+            .expect("increase flow controller flow N"),
+    };
+
     info!("starting DAC loop");
     loop {
-        select3(
+        select4(
             handle_endpoint(&mut heart_endpoint),
             handle_endpoint(&mut systemic_endpoint),
             handle_endpoint(&mut pulmonary_endpoint),
+            handle_endpoint(&mut flow_controller_endpoint),
         )
         .await;
     }
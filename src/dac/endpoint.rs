@@ -1,14 +1,22 @@
-use defmt::info;
+use defmt::debug;
 use embassy_stm32::{dac, mode::Async};
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, watch};
-use uom::si::{f32::Pressure, pressure::bar};
+use uom::si::{
+    f32::{Pressure, VolumeRate},
+    pressure::bar,
+    volume_rate::liter_per_minute,
+};
 
-use crate::dac::setpoint::RegulatorSetpoint;
+use crate::dac::setpoint::{FlowControllerSetpoint, RegulatorSetpoint};
 
-pub struct DacEndpoint<T: embassy_stm32::dac::Instance, C: embassy_stm32::dac::Channel + 'static> {
+pub struct DacEndpoint<
+    T: embassy_stm32::dac::Instance,
+    C: embassy_stm32::dac::Channel + 'static,
+    S: DacSetpoint = Pressure,
+> {
     pub id: DacId,
     pub dac: embassy_stm32::dac::DacChannel<'static, T, C, Async>,
-    pub rx: watch::Receiver<'static, Cs, Pressure, 1>,
+    pub rx: watch::Receiver<'static, Cs, S, 1>,
 }
 
 #[derive(defmt::Format)]
@@ -16,24 +24,58 @@ pub enum DacId {
     Heart,
     Systemic,
     Pulmonary,
+    FlowController,
 }
 
-pub async fn handle_endpoint<T, C>(endpoint: &mut DacEndpoint<T, C>)
+/// Quantity an analog output is set to
+pub trait DacSetpoint: Clone {
+    /// Right aligned 12 bit DAC value
+    fn dac_value(&self) -> u16;
+
+    fn log(&self, id: &DacId);
+}
+
+/// Pressure regulators
+impl DacSetpoint for Pressure {
+    fn dac_value(&self) -> u16 {
+        RegulatorSetpoint::from_pressure(*self).pressure
+    }
+
+    fn log(&self, id: &DacId) {
+        debug!(
+            "DAC: setting {:?} pressure to {:?}bar",
+            id,
+            self.get::<bar>()
+        );
+    }
+}
+
+/// Mass-flow controller
+impl DacSetpoint for VolumeRate {
+    fn dac_value(&self) -> u16 {
+        FlowControllerSetpoint::from_flow(*self).flow
+    }
+
+    fn log(&self, id: &DacId) {
+        debug!(
+            "DAC: setting {:?} flow to {:?}L/min",
+            id,
+            self.get::<liter_per_minute>()
+        );
+    }
+}
+
+pub async fn handle_endpoint<T, C, S>(endpoint: &mut DacEndpoint<T, C, S>)
 where
     T: dac::Instance,
     C: dac::Channel,
+    S: DacSetpoint,
 {
     let setpoint = endpoint.rx.changed().await;
 
-    info!(
-        "DAC: setting {:?} pressure to {:?}bar",
-        endpoint.id,
-        setpoint.get::<bar>()
-    );
-
-    let setpoint = RegulatorSetpoint::from_pressure(setpoint);
+    setpoint.log(&endpoint.id);
 
     endpoint
         .dac
-        .set(embassy_stm32::dac::Value::Bit12Right(setpoint.pressure));
+        .set(embassy_stm32::dac::Value::Bit12Right(setpoint.dac_value()));
 }
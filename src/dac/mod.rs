@@ -1,3 +1,5 @@
+#[cfg(target_os = "none")]
 pub mod dac_task;
+#[cfg(target_os = "none")]
 pub mod endpoint;
 pub mod setpoint;
@@ -1,5 +1,9 @@
 use defmt::trace;
-use uom::si::{f32::Pressure, pressure::bar};
+use uom::si::{
+    f32::{Pressure, VolumeRate},
+    pressure::bar,
+    volume_rate::liter_per_minute,
+};
 
 #[derive(Debug, defmt::Format)]
 pub struct RegulatorSetpoint {
@@ -7,8 +11,8 @@ pub struct RegulatorSetpoint {
 }
 
 impl RegulatorSetpoint {
-    const REGULATOR_MAX_PRESSURE_BAR: f32 = 2.0;
-    const REGULATOR_MIN_PRESSURE_BAR: f32 = 0.0;
+    pub const REGULATOR_MAX_PRESSURE_BAR: f32 = 2.0;
+    pub const REGULATOR_MIN_PRESSURE_BAR: f32 = 0.0;
     const REGULATOR_MAX_VALUE: f32 = ((1 << 13) - 1) as f32;
     const REGULATOR_MIN_VALUE: f32 = 0.0;
 
@@ -48,3 +52,34 @@ impl RegulatorSetpoint {
         pressure
     }
 }
+
+#[derive(Debug, defmt::Format)]
+pub struct FlowControllerSetpoint {
+    pub flow: u16,
+}
+
+impl FlowControllerSetpoint {
+    /// Flow at the top of the analog setpoint range of the Festo VEMD mass-flow controller
+    pub const FLOW_CONTROLLER_MAX_FLOW_LPM: f32 = 10.0;
+    const FLOW_CONTROLLER_MAX_VALUE: f32 = ((1 << 12) - 1) as f32;
+
+    // Convert a given flow into a DAC Setpoint, no flow or less closes the controller
+    pub fn from_flow(flow: VolumeRate) -> Self {
+        let from = flow.get::<liter_per_minute>();
+
+        let converted: f32 = ((from / Self::FLOW_CONTROLLER_MAX_FLOW_LPM)
+            * Self::FLOW_CONTROLLER_MAX_VALUE)
+            .clamp(0.0, Self::FLOW_CONTROLLER_MAX_VALUE);
+
+        let setpoint = FlowControllerSetpoint {
+            flow: converted as u16,
+        };
+
+        trace!(
+            "converted flow: {:?}L/min into DAC setpoint: {:?}",
+            from, setpoint,
+        );
+
+        setpoint
+    }
+}
@@ -1,52 +1,103 @@
 use defmt::*;
 
+use embassy_futures::select::{Either, select};
 use embassy_sync::{
     blocking_mutex::raw::ThreadModeRawMutex as Cs,
+    channel::Channel,
     pipe::{self, Pipe},
     watch,
 };
 use embedded_io_async::Write;
 use love_letter::{Report, Setpoint};
 
+use crate::{
+    comms::{
+        message::{self, FRAME_BYTES, HostCommand, STATUS_BYTES, StatusReport},
+        validation::{SETPOINT_LIMITS_WATCH, SetpointLimits, SetpointValidation},
+    },
+    heart_control::heart_controller::{
+        ACTUATOR_PROFILE_CHANNEL, HEART_CONFIG_WATCH, PRIMING_SIGNAL,
+    },
+    loop_control::{
+        fill_controller::FILL_CHANNEL,
+        loop_controller::{FLOW_SETPOINT_WATCH, LOOP_CONFIG_WATCH},
+        preset_controller::PRESET_CHANNEL,
+    },
+};
+
+/// Status reports waiting to be sent to the host, see [`publish_status`]
+pub static STATUS_REPORT_CHANNEL: Channel<Cs, StatusReport, 32> = Channel::new();
+
+/// Queue a [`StatusReport`] for the host, never waits so a slow link does not hold up the caller
+pub fn publish_status(status: StatusReport) {
+    if STATUS_REPORT_CHANNEL.try_send(status).is_err() {
+        warn!("FRAMING - publish_status: status report queue full, dropping a status report");
+    }
+}
+
 #[embassy_executor::task]
-/// Deserialise the [`Report`]s collected from the control task into a UART byte stream to be
-/// picked up by the comms task
+/// Deserialise the [`Report`]s collected from the control task, and the [`StatusReport`]s queued
+/// by any task, into a UART byte stream to be picked up by the comms task
 pub async fn serialise_reports(
     mut report_receiver: watch::Receiver<'static, Cs, Report, 1>,
     mut report_pipe_tx: pipe::Writer<'static, Cs, { love_letter::REPORT_BYTES * 4 }>,
 ) {
     let mut buf = [0u8; love_letter::REPORT_BYTES * 2];
+    let mut status_buf = [0u8; STATUS_BYTES];
     loop {
-        // Get latest report from the control task
-        let report = report_receiver.changed().await;
-
-        // Serialize it
-        match love_letter::serialize_report(report.clone(), &mut buf) {
-            Ok(serialised) => {
-                // Push serialised report into pipe for consumption in comms task
-                info!(
-                    "FRAMING - serialize_report: serialised report: {:?}",
-                    serialised
-                );
-                let _ = report_pipe_tx.write_all(serialised).await;
-            }
-            Err(err) => {
-                error!(
-                    "FRAMING - serialise_reports: {} - Unable to serialise report {:?}, skipping...",
-                    err, report
-                );
+        // Get latest report from the control task, or the next status report
+        match select(report_receiver.changed(), STATUS_REPORT_CHANNEL.receive()).await {
+            Either::First(report) => {
+                // Serialize it
+                match love_letter::serialize_report(report.clone(), &mut buf) {
+                    Ok(serialised) => {
+                        // Push serialised report into pipe for consumption in comms task
+                        info!(
+                            "FRAMING - serialize_report: serialised report: {:?}",
+                            serialised
+                        );
+                        let _ = report_pipe_tx.write_all(serialised).await;
+                    }
+                    Err(err) => {
+                        error!(
+                            "FRAMING - serialise_reports: {} - Unable to serialise report {:?}, skipping...",
+                            err, report
+                        );
+                    }
+                }
             }
+            Either::Second(status) => match message::serialize_status(&status, &mut status_buf) {
+                Ok(serialised) => {
+                    trace!(
+                        "FRAMING - serialise_reports: serialised status report: {:?}",
+                        serialised
+                    );
+                    let _ = report_pipe_tx.write_all(serialised).await;
+                }
+                Err(err) => {
+                    error!(
+                        "FRAMING - serialise_reports: {} - Unable to serialise status report, skipping...",
+                        err
+                    );
+                }
+            },
         }
     }
 }
 
 #[embassy_executor::task]
-/// Frame the Pipe containing the UART byte stream from the comms task into [`Setpoint`]s and notify the control task
+/// Frame the Pipe containing the UART byte stream from the comms task into [`Setpoint`]s, validate
+/// them and notify the control task
+/// Extension frames hold a [`HostCommand`] instead, these are handed to the task they are meant for
 pub async fn frame_and_serialise_setpoints(
-    setpoint_sender: watch::Sender<'static, Cs, Setpoint, 3>,
+    setpoint_sender: watch::Sender<'static, Cs, Setpoint, 4>,
     setpoint_pipe_tx: pipe::Reader<'static, Cs, { love_letter::SETPOINT_BYTES * 4 }>,
 ) {
-    let mut framing_buf = heapless::Vec::<u8, { love_letter::SETPOINT_BYTES * 4 }>::new();
+    let mut limits_rx = SETPOINT_LIMITS_WATCH
+        .receiver()
+        .expect("Update SETPOINT_LIMITS_WATCH N");
+
+    let mut framing_buf = heapless::Vec::<u8, FRAME_BYTES>::new();
 
     let mut buf = [0u8; 1];
     loop {
@@ -73,20 +124,40 @@ pub async fn frame_and_serialise_setpoints(
                     );
 
                     // COBS delimiter byte: process frame
-                    match love_letter::deserialize_setpoint(&mut framing_buf) {
-                        Ok(setpoint) => {
-                            info!(
-                                "FRAMING - frame_setpoints: COBS delimeter detected & Deserialise succes: {:?}",
-                                setpoint
-                            );
-                            // Happy path - Send deserialised setpoint to control task
-                            setpoint_sender.send(setpoint);
+                    if message::is_extension_frame(&framing_buf) {
+                        match message::deserialize_command(&mut framing_buf) {
+                            Ok(command) => {
+                                handle_command(command, &limits_rx.try_get().unwrap_or_default())
+                            }
+                            Err(err) => {
+                                error!(
+                                    "FRAMING - frame_setpoints: Unable to deserialise framing buffer into a host command. Err: {}",
+                                    err
+                                );
+                            }
                         }
-                        Err(err) => {
-                            error!(
-                                "FRAMING - frame_setpoints: Unable to deserialise framing buffer into a report. Err: {} - buffer: {:?}",
-                                err, framing_buf
-                            );
+                    } else {
+                        match love_letter::deserialize_setpoint(&mut framing_buf) {
+                            Ok(mut setpoint) => {
+                                info!(
+                                    "FRAMING - frame_setpoints: COBS delimeter detected & Deserialise succes: {:?}",
+                                    setpoint
+                                );
+
+                                // Check the setpoint against the physical limits before anything acts
+                                // on it
+                                let limits = limits_rx.try_get().unwrap_or_default();
+                                if forward_validated(limits.validate(&mut setpoint)) {
+                                    // Happy path - Send deserialised setpoint to control task
+                                    setpoint_sender.send(setpoint);
+                                }
+                            }
+                            Err(err) => {
+                                error!(
+                                    "FRAMING - frame_setpoints: Unable to deserialise framing buffer into a report. Err: {} - buffer: {:?}",
+                                    err, framing_buf
+                                );
+                            }
                         }
                     }
                     // Reset current frame
@@ -113,3 +184,76 @@ pub async fn frame_and_serialise_setpoints(
         }
     }
 }
+
+/// Report a setpoint that did not pass validation as is, returns whether it may be forwarded
+fn forward_validated(validation: Option<SetpointValidation>) -> bool {
+    match validation {
+        None => true,
+        Some(SetpointValidation::Clamped(clamped)) => {
+            warn!(
+                "FRAMING - frame_setpoints: clamped setpoint fields {:?}",
+                clamped
+            );
+            publish_status(StatusReport::SetpointValidation(
+                SetpointValidation::Clamped(clamped),
+            ));
+            true
+        }
+        Some(SetpointValidation::Rejected(violation)) => {
+            error!(
+                "FRAMING - frame_setpoints: rejected setpoint, {:?}",
+                violation
+            );
+            publish_status(StatusReport::SetpointValidation(
+                SetpointValidation::Rejected(violation),
+            ));
+            false
+        }
+    }
+}
+
+/// Hand a [`HostCommand`] to the task it is meant for, setpoints are checked against `limits`
+fn handle_command(command: HostCommand, limits: &SetpointLimits) {
+    match command {
+        HostCommand::HeartConfig(config) => {
+            info!("FRAMING - frame_setpoints: received a new heart configuration");
+            HEART_CONFIG_WATCH.sender().send(config);
+        }
+        HostCommand::LoopConfig(config) => {
+            info!("FRAMING - frame_setpoints: received a new mockloop configuration");
+            LOOP_CONFIG_WATCH.sender().send(config);
+        }
+        HostCommand::SetpointLimits(limits) => {
+            info!("FRAMING - frame_setpoints: received new setpoint limits");
+            SETPOINT_LIMITS_WATCH.sender().send(limits);
+        }
+        HostCommand::FlowSetpoint(mut flow) => {
+            info!("FRAMING - frame_setpoints: received a system flow setpoint");
+            if forward_validated(limits.validate_flow(&mut flow)) {
+                FLOW_SETPOINT_WATCH.sender().send(flow);
+            }
+        }
+        HostCommand::ActuatorProfile(command) => {
+            info!("FRAMING - frame_setpoints: received an actuator profile command");
+            if ACTUATOR_PROFILE_CHANNEL.try_send(command).is_err() {
+                warn!("FRAMING - frame_setpoints: actuator profile commands pending, dropping one");
+            }
+        }
+        HostCommand::Preset(command) => {
+            info!("FRAMING - frame_setpoints: received a preset command");
+            if PRESET_CHANNEL.try_send(command).is_err() {
+                warn!("FRAMING - frame_setpoints: preset commands pending, dropping one");
+            }
+        }
+        HostCommand::Fill(command) => {
+            info!("FRAMING - frame_setpoints: received a chamber fill command");
+            if FILL_CHANNEL.try_send(command).is_err() {
+                warn!("FRAMING - frame_setpoints: chamber fill commands pending, dropping one");
+            }
+        }
+        HostCommand::StartPriming => {
+            info!("FRAMING - frame_setpoints: received a start-up sequence request");
+            PRIMING_SIGNAL.signal(());
+        }
+    }
+}
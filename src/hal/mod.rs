@@ -16,3 +16,7 @@ pub use stm32g474re::AdcChannels;
 pub use stm32g474re::Hal;
 #[cfg(feature = "stm32g474re")]
 pub use stm32g474re::NUM_ADC_INPUTS;
+#[cfg(feature = "stm32g474re")]
+pub use stm32g474re::PinchValveTimer;
+#[cfg(feature = "stm32g474re")]
+pub use stm32g474re::SyncTimer;
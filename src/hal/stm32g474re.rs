@@ -1,8 +1,13 @@
 use embassy_stm32::adc::{Adc, SampleTime};
 use embassy_stm32::dac::{Ch1, Ch2, Dac, DacChannel};
-use embassy_stm32::gpio::{Input, Level, Output, Pull, Speed};
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::{Input, Level, Output, OutputType, Pull, Speed};
 use embassy_stm32::mode::Async;
+use embassy_stm32::opamp::{OpAmp, OpAmpSpeed};
 use embassy_stm32::rtc::{Rtc, RtcConfig};
+use embassy_stm32::time::khz;
+use embassy_stm32::timer::low_level::CountingMode;
+use embassy_stm32::timer::simple_pwm::{PwmPin, SimplePwm};
 use embassy_stm32::usart::{self, BufferedUart};
 use embassy_stm32::{
     Peri, Peripherals, bind_interrupts,
@@ -10,12 +15,15 @@ use embassy_stm32::{
 };
 use static_cell::StaticCell;
 
+use crate::{chamber_valve_task::ChamberValveOutputs, sync_output_task::SyncOutput};
+
 bind_interrupts!(struct Irqs {
     USART2 => usart::BufferedInterruptHandler<peripherals::USART2>;
 });
 
 static RX_BUF: StaticCell<[u8; 2048]> = StaticCell::new();
 static TX_BUF: StaticCell<[u8; 2048]> = StaticCell::new();
+static FLOW_CONTROLLER_OPAMP: StaticCell<OpAmp<'static, OPAMP4>> = StaticCell::new();
 
 /// Concrete HAL for STM32G474RE
 pub struct Hal {
@@ -24,18 +32,35 @@ pub struct Hal {
     pub heart_pressure_dac: DacChannel<'static, DAC1, Ch1, Async>,
     pub systemic_compliance_dac: DacChannel<'static, DAC1, Ch2, Async>,
     pub pulmonary_compliance_dac: DacChannel<'static, DAC2, Ch1, Async>,
+    /// Setpoint of the Festo VEMD mass-flow controller, DAC4 has no pins of its own
+    pub flow_controller_dac: DacChannel<'static, DAC4, Ch1, Async>,
     pub left_valve: Output<'static>,
     pub right_valve: Output<'static>,
     pub dma: Peri<'static, DMA1_CH1>,
+    /// DMA channel of the ADC2 conversion sequence
+    pub adc2_dma: Peri<'static, DMA1_CH2>,
     pub led: Output<'static>,
     pub adc_channels: AdcChannels,
     pub button: Input<'static>,
+    /// External beat trigger, e.g. from an ECG R-wave detector
+    pub external_trigger: ExtiInput<'static>,
+    /// Sync pulse output for external instruments
+    pub sync_output: SyncOutput<'static, SyncTimer>,
+    /// Systemic and pulmonary resistance pinch valves on channel 1 and 2
+    pub pinch_valve_pwm: SimplePwm<'static, PinchValveTimer>,
+    /// Fill and drain solenoid valves of the compliance chamber pairs
+    pub chamber_valves: ChamberValveOutputs,
     pub uart: BufferedUart<'static>,
     pub rtc: Rtc,
 }
 
+/// Timer generating the sync output pulses, on channel 1
+pub type SyncTimer = TIM4;
+/// Timer driving the resistance pinch valves
+pub type PinchValveTimer = TIM1;
+
 /// Number of adc inputs, this could be a fancy macro but I decided against the complexity
-pub const NUM_ADC_INPUTS: usize = 7;
+pub const NUM_ADC_INPUTS: usize = 11;
 
 pub struct AdcChannels {
     pub regulator_actual_pressure: Peri<'static, PA0>,
@@ -45,6 +70,17 @@ pub struct AdcChannels {
     pub systemic_afterload_pressure: Peri<'static, PB0>,
     pub pulmonary_preload_pressure: Peri<'static, PB1>,
     pub pulmonary_afterload_pressure: Peri<'static, PB11>,
+    /// Festo air flow sensor on the driveline pressure line
+    pub pressure_air_flow: Peri<'static, PA3>,
+    /// Festo air flow sensor on the driveline vacuum line
+    pub vacuum_air_flow: Peri<'static, PC1>,
+    /// Fluid level sensor of the systemic compliance chambers
+    pub systemic_chamber_level: Peri<'static, PF0>,
+    /// Fluid level sensor of the pulmonary compliance chambers
+    pub pulmonary_chamber_level: Peri<'static, PB14>,
+    /// Actual flow feedback of the Festo VEMD mass-flow controller, on ADC2 as ADC1 has no inputs
+    /// left
+    pub flow_controller_flow: Peri<'static, PC4>,
 }
 
 impl Hal {
@@ -66,11 +102,28 @@ impl Hal {
             systemic_afterload_pressure: p.PB0,
             pulmonary_preload_pressure: p.PB1,
             pulmonary_afterload_pressure: p.PB11,
+            pressure_air_flow: p.PA3,
+            vacuum_air_flow: p.PC1,
+            systemic_chamber_level: p.PF0,
+            pulmonary_chamber_level: p.PB14,
+            flow_controller_flow: p.PC4,
         };
 
         let dma = p.DMA1_CH1;
+        let adc2_dma = p.DMA1_CH2;
 
         let button = Input::new(p.PC13, Pull::Down);
+        let external_trigger = ExtiInput::new(p.PC6, p.EXTI6, Pull::Down);
+        let sync_output = SyncOutput::new(p.TIM4, p.PB6);
+        let pinch_valve_pwm = SimplePwm::new(
+            p.TIM1,
+            Some(PwmPin::new(p.PA8, OutputType::PushPull)),
+            Some(PwmPin::new(p.PA9, OutputType::PushPull)),
+            None,
+            None,
+            khz(1),
+            CountingMode::EdgeAlignedUp,
+        );
 
         // Construct the BufferedUart, a structure allows us to process received uart bytes from a
         // ring buffer that is continously filled by DMA, and send uart bytes using a software FIFO
@@ -90,20 +143,41 @@ impl Hal {
         let (heart_pressure_dac, systemic_compliance_dac) =
             Dac::new(p.DAC1, p.DMA1_CH3, p.DMA1_CH4, p.PA4, p.PA5).split();
         let pulmonary_compliance_dac = DacChannel::new(p.DAC2, p.DMA1_CH5, p.PA6);
+        let flow_controller_dac = DacChannel::new_internal(p.DAC4, p.DMA1_CH6);
+        // Follow the flow controller DAC onto PB12, dropping the output would disable the opamp
+        core::mem::forget(
+            FLOW_CONTROLLER_OPAMP
+                .init(OpAmp::new(p.OPAMP4, OpAmpSpeed::Normal))
+                .buffer_dac(p.PB12),
+        );
 
         let left_valve = Output::new(p.PC2, Level::Low, Speed::Low);
         let right_valve = Output::new(p.PC3, Level::Low, Speed::Low);
 
+        // Normally closed solenoid valves, closed until the fill sequencer opens them
+        let chamber_valves = ChamberValveOutputs {
+            systemic_fill: Output::new(p.PC7, Level::Low, Speed::Low),
+            systemic_drain: Output::new(p.PC8, Level::Low, Speed::Low),
+            pulmonary_fill: Output::new(p.PC9, Level::Low, Speed::Low),
+            pulmonary_drain: Output::new(p.PC10, Level::Low, Speed::Low),
+        };
+
         Self {
             adc1,
             adc2,
             heart_pressure_dac,
             systemic_compliance_dac,
             pulmonary_compliance_dac,
+            flow_controller_dac,
             dma,
+            adc2_dma,
             led,
             adc_channels,
             button,
+            external_trigger,
+            sync_output,
+            pinch_valve_pwm,
+            chamber_valves,
             uart,
             rtc,
             left_valve,
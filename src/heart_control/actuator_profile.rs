@@ -0,0 +1,399 @@
+//! Per-prototype actuator safety limits
+//! Hybrid Heart prototypes tolerate very different driveline pressures, the active
+//! [`ActuatorProfile`] bounds whatever the host asks of the heart controller. Profiles are kept by
+//! name in [`ActuatorProfiles`] and only switched by an explicit host [`ProfileCommand`].
+
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    f32::{Frequency, Pressure, Time},
+    frequency::hertz,
+    pressure::bar,
+    time::second,
+};
+
+use crate::{
+    dac::setpoint::RegulatorSetpoint,
+    heart_control::{config::HeartConfig, error::ProfileError, state_machine::HeartParameters},
+};
+
+/// Longest profile name
+pub const PROFILE_NAME_LEN: usize = 16;
+/// Number of profiles stored on the device, the built-in profile included
+pub const ACTUATOR_PROFILES: usize = 8;
+
+pub type ProfileName = heapless::String<PROFILE_NAME_LEN>;
+
+/// Safety limits of one actuator prototype
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActuatorProfile {
+    pub name: ProfileName,
+    /// Highest driveline pressure setpoint
+    pub max_pressure: Pressure,
+    /// Lowest driveline pressure setpoint, shaped waveforms still start from zero
+    pub min_pressure: Pressure,
+    pub max_heart_rate: Frequency,
+    /// Largest pressure change per second, `None` for no limit
+    pub max_pressure_slew: Option<Pressure>,
+    /// Shortest time per beat on vacuum, for the actuator membrane to retract
+    pub min_vacuum: Time,
+}
+
+impl ActuatorProfile {
+    /// Name of the built-in profile safe for any prototype, active after a reset
+    pub const CONSERVATIVE: &'static str = "conservative";
+    /// Name of the built-in profile only bounded by the pressure regulator range
+    pub const REGULATOR: &'static str = "regulator";
+
+    /// Built-in profiles, from the most to the least conservative
+    pub fn built_in() -> [Self; 2] {
+        let name = |name| ProfileName::try_from(name).unwrap_or_default();
+
+        [
+            Self {
+                name: name(Self::CONSERVATIVE),
+                max_pressure: Pressure::new::<bar>(0.5),
+                min_pressure: Pressure::new::<bar>(RegulatorSetpoint::REGULATOR_MIN_PRESSURE_BAR),
+                max_heart_rate: Frequency::new::<hertz>(1.5),
+                max_pressure_slew: Some(Pressure::new::<bar>(0.5)),
+                min_vacuum: Time::new::<second>(0.2),
+            },
+            Self {
+                name: name(Self::REGULATOR),
+                max_pressure: Pressure::new::<bar>(RegulatorSetpoint::REGULATOR_MAX_PRESSURE_BAR),
+                min_pressure: Pressure::new::<bar>(RegulatorSetpoint::REGULATOR_MIN_PRESSURE_BAR),
+                max_heart_rate: Frequency::new::<hertz>(4.0),
+                max_pressure_slew: None,
+                min_vacuum: Time::new::<second>(0.0),
+            },
+        ]
+    }
+
+    /// Whether `name` is one of the built-in profiles, these can not be replaced
+    pub fn is_built_in(name: &str) -> bool {
+        [Self::CONSERVATIVE, Self::REGULATOR].contains(&name)
+    }
+
+    /// `parameters` with the pressure and heart rate bounded by this profile
+    pub fn limit(&self, parameters: &HeartParameters) -> HeartParameters {
+        let max_pressure = self.max_pressure.get::<bar>();
+        let pressure = parameters
+            .pressure
+            .get::<bar>()
+            .min(max_pressure)
+            .max(self.min_pressure.get::<bar>().min(max_pressure));
+        let heart_rate = parameters
+            .heart_rate
+            .get::<hertz>()
+            .min(self.max_heart_rate.get::<hertz>());
+
+        HeartParameters {
+            heart_rate: Frequency::new::<hertz>(heart_rate),
+            pressure: Pressure::new::<bar>(pressure),
+            ..*parameters
+        }
+    }
+
+    /// `config` restricted to this profile
+    /// The pressure slew is limited by the tighter of both, the systole model keeps at least the
+    /// minimum time on vacuum as diastole
+    pub fn restrict(&self, config: &HeartConfig) -> HeartConfig {
+        let mut config = config.clone();
+
+        if let Some(profile_slew) = self.max_pressure_slew {
+            let slew = config
+                .transition
+                .max_pressure_slew
+                .map_or(profile_slew.get::<bar>(), |slew| {
+                    slew.get::<bar>().abs().min(profile_slew.get::<bar>().abs())
+                });
+            config.transition.max_pressure_slew = Some(Pressure::new::<bar>(slew));
+        }
+
+        let min_diastole = config
+            .systole_model
+            .min_diastole
+            .get::<second>()
+            .max(self.min_vacuum.get::<second>());
+        config.systole_model.min_diastole = Time::new::<second>(min_diastole);
+
+        config
+    }
+}
+
+/// Host command managing the actuator profiles
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ProfileCommand {
+    /// Make the named profile the active one
+    Select(ProfileName),
+    /// Add a profile, or replace the stored profile of the same name
+    Store(ActuatorProfile),
+}
+
+/// Acknowledgement of a [`ProfileCommand`]
+#[derive(Debug, Clone, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct ProfileAck {
+    /// Profile the command applied to
+    pub name: ProfileName,
+    pub result: Result<(), ProfileError>,
+}
+
+/// Profiles stored on the device and the active one
+/// Profiles are kept in RAM until reset, only the built-in profiles are there from the start. A
+/// reset falls back to the conservative profile, so a prototype the host has not selected a
+/// profile for since is never driven at the full regulator range.
+#[derive(Debug, Clone)]
+pub struct ActuatorProfiles {
+    profiles: heapless::Vec<ActuatorProfile, ACTUATOR_PROFILES>,
+    active: usize,
+}
+
+impl Default for ActuatorProfiles {
+    fn default() -> Self {
+        Self {
+            profiles: ActuatorProfile::built_in().into_iter().collect(),
+            // The most conservative built-in profile
+            active: 0,
+        }
+    }
+}
+
+impl ActuatorProfiles {
+    pub fn active(&self) -> &ActuatorProfile {
+        &self.profiles[self.active]
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ActuatorProfile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    /// Carry out `command`, profiles are only changed while the heart controller is disabled
+    pub fn handle(&mut self, command: ProfileCommand, heart_enabled: bool) -> ProfileAck {
+        let name = match &command {
+            ProfileCommand::Select(name) => name.clone(),
+            ProfileCommand::Store(profile) => profile.name.clone(),
+        };
+        let result = if heart_enabled {
+            Err(ProfileError::HeartEnabled)
+        } else {
+            match command {
+                ProfileCommand::Select(name) => self.select(&name),
+                ProfileCommand::Store(profile) => self.store(profile),
+            }
+        };
+
+        ProfileAck { name, result }
+    }
+
+    fn select(&mut self, name: &str) -> Result<(), ProfileError> {
+        self.active = self
+            .profiles
+            .iter()
+            .position(|profile| profile.name == name)
+            .ok_or(ProfileError::UnknownProfile)?;
+
+        Ok(())
+    }
+
+    fn store(&mut self, profile: ActuatorProfile) -> Result<(), ProfileError> {
+        if ActuatorProfile::is_built_in(&profile.name) {
+            return Err(ProfileError::BuiltIn);
+        }
+        let valid = [
+            profile.max_pressure.get::<bar>(),
+            profile.min_pressure.get::<bar>(),
+            profile.max_heart_rate.get::<hertz>(),
+            profile.min_vacuum.get::<second>(),
+        ]
+        .iter()
+        .all(|value| value.is_finite() && *value >= 0.0)
+            && profile.min_pressure <= profile.max_pressure
+            && profile
+                .max_pressure_slew
+                .is_none_or(|slew| slew.get::<bar>().is_finite());
+        if !valid {
+            return Err(ProfileError::InvalidLimits);
+        }
+
+        match self
+            .profiles
+            .iter_mut()
+            .find(|stored| stored.name == profile.name)
+        {
+            Some(stored) => *stored = profile,
+            None => self
+                .profiles
+                .push(profile)
+                .map_err(|_| ProfileError::Full)?,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uom::si::{frequency::cycle_per_minute, time::millisecond};
+
+    fn prototype(name: &str) -> ActuatorProfile {
+        ActuatorProfile {
+            name: ProfileName::try_from(name).unwrap(),
+            max_pressure: Pressure::new::<bar>(0.75),
+            min_pressure: Pressure::new::<bar>(0.25),
+            max_heart_rate: Frequency::new::<cycle_per_minute>(120.0),
+            max_pressure_slew: Some(Pressure::new::<bar>(0.5)),
+            min_vacuum: Time::new::<millisecond>(250.0),
+        }
+    }
+
+    fn parameters(bpm: f32, pressure_bar: f32) -> HeartParameters {
+        HeartParameters {
+            heart_rate: Frequency::new::<cycle_per_minute>(bpm),
+            pressure: Pressure::new::<bar>(pressure_bar),
+            left_systole_ratio: 0.35,
+            right_systole_ratio: 0.35,
+            interventricular_delay: Time::new::<second>(0.0),
+        }
+    }
+
+    #[test]
+    fn test_limit() {
+        let profile = prototype("pump");
+
+        let limited = profile.limit(&parameters(180.0, 1.5));
+        assert_eq!(limited.pressure, Pressure::new::<bar>(0.75));
+        assert!((limited.heart_rate.get::<cycle_per_minute>() - 120.0).abs() < 0.001);
+
+        assert_eq!(
+            profile.limit(&parameters(60.0, 0.0)).pressure,
+            Pressure::new::<bar>(0.25)
+        );
+        assert_eq!(profile.limit(&parameters(60.0, 0.5)), parameters(60.0, 0.5));
+    }
+
+    #[test]
+    fn test_restrict_config() {
+        let profile = prototype("pump");
+
+        let config = profile.restrict(&HeartConfig::default());
+        assert_eq!(
+            config.transition.max_pressure_slew,
+            Some(Pressure::new::<bar>(0.5))
+        );
+        assert_eq!(
+            config.systole_model.min_diastole,
+            Time::new::<millisecond>(250.0)
+        );
+
+        // Tighter configuration limits are kept
+        let mut config = HeartConfig::default();
+        config.transition.max_pressure_slew = Some(Pressure::new::<bar>(0.25));
+        config.systole_model.min_diastole = Time::new::<millisecond>(400.0);
+        assert_eq!(profile.restrict(&config), config);
+    }
+
+    #[test]
+    fn test_select_and_store() {
+        let mut profiles = ActuatorProfiles::default();
+        let name = |name| ProfileName::try_from(name).unwrap();
+
+        assert_eq!(profiles.active().name, ActuatorProfile::CONSERVATIVE);
+        assert_eq!(
+            profiles
+                .handle(ProfileCommand::Select(name("pump")), false)
+                .result,
+            Err(ProfileError::UnknownProfile)
+        );
+
+        assert_eq!(
+            profiles.handle(ProfileCommand::Store(prototype("pump")), false),
+            ProfileAck {
+                name: name("pump"),
+                result: Ok(()),
+            }
+        );
+        // Storing does not select
+        assert_eq!(profiles.active().name, ActuatorProfile::CONSERVATIVE);
+
+        // Never while beating
+        assert_eq!(
+            profiles
+                .handle(ProfileCommand::Select(name("pump")), true)
+                .result,
+            Err(ProfileError::HeartEnabled)
+        );
+        assert_eq!(profiles.active().name, ActuatorProfile::CONSERVATIVE);
+
+        assert_eq!(
+            profiles
+                .handle(ProfileCommand::Select(name("pump")), false)
+                .result,
+            Ok(())
+        );
+        assert_eq!(profiles.active(), &prototype("pump"));
+    }
+
+    #[test]
+    fn test_store_checks_profile() {
+        let mut profiles = ActuatorProfiles::default();
+
+        let mut inverted = prototype("inverted");
+        inverted.min_pressure = Pressure::new::<bar>(1.0);
+        assert_eq!(
+            profiles
+                .handle(ProfileCommand::Store(inverted), false)
+                .result,
+            Err(ProfileError::InvalidLimits)
+        );
+        assert_eq!(
+            profiles
+                .handle(
+                    ProfileCommand::Store(prototype(ActuatorProfile::REGULATOR)),
+                    false
+                )
+                .result,
+            Err(ProfileError::BuiltIn)
+        );
+
+        for i in ActuatorProfile::built_in().len()..ACTUATOR_PROFILES {
+            let mut name = ProfileName::new();
+            let _ = core::fmt::write(&mut name, format_args!("prototype {i}"));
+            assert_eq!(
+                profiles
+                    .handle(ProfileCommand::Store(prototype(&name)), false)
+                    .result,
+                Ok(())
+            );
+        }
+        assert_eq!(
+            profiles
+                .handle(ProfileCommand::Store(prototype("one too many")), false)
+                .result,
+            Err(ProfileError::Full)
+        );
+        // Replacing a stored profile still works
+        assert_eq!(
+            profiles
+                .handle(ProfileCommand::Store(prototype("prototype 2")), false)
+                .result,
+            Ok(())
+        );
+        assert!(profiles.get("prototype 2").is_some());
+    }
+
+    #[test]
+    fn test_conservative_after_reset() {
+        let profiles = ActuatorProfiles::default();
+        let [conservative, regulator] = ActuatorProfile::built_in();
+
+        assert_eq!(profiles.active(), &conservative);
+        assert!(conservative.max_pressure <= regulator.max_pressure);
+        assert!(conservative.max_heart_rate <= regulator.max_heart_rate);
+        assert!(conservative.min_vacuum >= regulator.min_vacuum);
+
+        // Beating at the regulator range takes an explicit selection
+        let limited = profiles.active().limit(&parameters(180.0, 2.0));
+        assert_eq!(limited.pressure, Pressure::new::<bar>(0.5));
+        assert!(profiles.get(ActuatorProfile::REGULATOR).is_some());
+    }
+}
@@ -0,0 +1,331 @@
+//! Actuator air volume estimation
+//! Integrates the air flow on the pressure and vacuum lines per cardiac phase, the air pushed into
+//! the actuator during a beat tracks its displacement
+
+use embassy_time::Instant;
+use uom::si::{
+    f32::{Time, Volume, VolumeRate},
+    time::microsecond,
+    volume::milliliter,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::heart_control::{beat::BeatEvent, phase::CardiacPhase};
+
+/// Air flow on both driveline supply lines at one instant
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AirFlowSample {
+    /// Flow from the pressure regulator towards the actuator
+    pub pressure_line: VolumeRate,
+    /// Flow from the actuator towards the vacuum source
+    pub vacuum_line: VolumeRate,
+    pub timestamp: Instant,
+}
+
+impl AirFlowSample {
+    /// Flow at `at`, linearly interpolated between this sample and the `next` one
+    fn interpolate(&self, next: &Self, at: Instant) -> Self {
+        let span = next
+            .timestamp
+            .saturating_duration_since(self.timestamp)
+            .as_micros();
+        let fraction = match span {
+            0 => 1.0,
+            span => at.saturating_duration_since(self.timestamp).as_micros() as f32 / span as f32,
+        };
+
+        Self {
+            pressure_line: self.pressure_line
+                + (next.pressure_line - self.pressure_line) * fraction,
+            vacuum_line: self.vacuum_line + (next.vacuum_line - self.vacuum_line) * fraction,
+            timestamp: at,
+        }
+    }
+}
+
+/// Air volume through both lines during one cardiac phase
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PhaseVolumes {
+    pub pressure_line: Volume,
+    pub vacuum_line: Volume,
+}
+
+impl Default for PhaseVolumes {
+    fn default() -> Self {
+        Self {
+            pressure_line: Volume::new::<milliliter>(0.0),
+            vacuum_line: Volume::new::<milliliter>(0.0),
+        }
+    }
+}
+
+/// Air volumes of a single beat
+/// Flow on the pressure line during diastole, or on the vacuum line during systole, points at a
+/// leaking valve
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BeatVolumes {
+    /// Beat the volumes belong to, as counted by the heart controller
+    pub beat: u32,
+    pub systole: PhaseVolumes,
+    pub diastole: PhaseVolumes,
+}
+
+impl BeatVolumes {
+    /// Air pushed into the actuator over the whole beat
+    pub fn air_in(&self) -> Volume {
+        self.systole.pressure_line + self.diastole.pressure_line
+    }
+
+    /// Air drawn out of the actuator over the whole beat
+    pub fn air_out(&self) -> Volume {
+        self.systole.vacuum_line + self.diastole.vacuum_line
+    }
+
+    fn phase_mut(&mut self, phase: CardiacPhase) -> &mut PhaseVolumes {
+        match phase {
+            CardiacPhase::Systole => &mut self.systole,
+            CardiacPhase::Diastole => &mut self.diastole,
+        }
+    }
+}
+
+/// Trapezoidal integration of the air flow samples into [`BeatVolumes`]
+/// The interval around a phase switch is split at the moment of the switch, with the flow
+/// interpolated between the samples on either side. The beat the integration starts in is
+/// incomplete and never published.
+#[derive(Debug, Clone, Default)]
+pub struct AirVolumeIntegrator {
+    last_sample: Option<AirFlowSample>,
+    /// Beat and phase the ventricle was in at the last sample
+    phase: Option<(u32, CardiacPhase)>,
+    /// Beat being integrated, `None` until the first complete beat starts
+    current: Option<BeatVolumes>,
+}
+
+impl AirVolumeIntegrator {
+    /// Add `sample`, `switch` is the latest phase switch of the ventricle at the time
+    /// Returns the volumes of the previous beat once a new one starts, `switch` is `None` while
+    /// the heart is not beating
+    pub fn update(
+        &mut self,
+        switch: Option<&BeatEvent>,
+        sample: AirFlowSample,
+    ) -> Option<BeatVolumes> {
+        let last_sample = self.last_sample.replace(sample);
+
+        let Some(switch) = switch else {
+            self.phase = None;
+            self.current = None;
+            return None;
+        };
+        let Some(last_sample) = last_sample else {
+            self.phase = Some((switch.beat, switch.phase));
+            return None;
+        };
+
+        if self.phase == Some((switch.beat, switch.phase)) {
+            self.integrate(&last_sample, &sample);
+            return None;
+        }
+
+        let switch_at = Instant::from_micros(switch.timestamp);
+        if switch_at >= sample.timestamp {
+            // Published after the sample was taken, the sample still belongs to the phase before
+            self.integrate(&last_sample, &sample);
+            None
+        } else if switch_at > last_sample.timestamp {
+            let at_switch = last_sample.interpolate(&sample, switch_at);
+            self.integrate(&last_sample, &at_switch);
+            let completed = self.enter(switch.beat, switch.phase);
+            self.integrate(&at_switch, &sample);
+            completed
+        } else {
+            let completed = self.enter(switch.beat, switch.phase);
+            self.integrate(&last_sample, &sample);
+            completed
+        }
+    }
+
+    /// Switch to `phase` of `beat`, returns the previous beat if it is complete
+    fn enter(&mut self, beat: u32, phase: CardiacPhase) -> Option<BeatVolumes> {
+        match self.phase.replace((beat, phase)) {
+            Some((previous_beat, _)) if previous_beat != beat => {
+                self.current.replace(BeatVolumes {
+                    beat,
+                    systole: PhaseVolumes::default(),
+                    diastole: PhaseVolumes::default(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Add the air flow between `from` and `to` to the current phase
+    fn integrate(&mut self, from: &AirFlowSample, to: &AirFlowSample) {
+        let (Some(current), Some((_, phase))) = (&mut self.current, self.phase) else {
+            return;
+        };
+        let dt = Time::new::<microsecond>(
+            to.timestamp
+                .saturating_duration_since(from.timestamp)
+                .as_micros() as f32,
+        );
+        let volumes = current.phase_mut(phase);
+
+        volumes.pressure_line += (from.pressure_line + to.pressure_line) * dt / 2.0;
+        volumes.vacuum_line += (from.vacuum_line + to.vacuum_line) * dt / 2.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_time::Duration;
+    use uom::si::volume_rate::liter_per_minute;
+
+    use crate::heart_control::phase::Ventricle;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_secs(1) + Duration::from_millis(ms)
+    }
+
+    fn sample(ms: u64, pressure_lpm: f32, vacuum_lpm: f32) -> AirFlowSample {
+        AirFlowSample {
+            pressure_line: VolumeRate::new::<liter_per_minute>(pressure_lpm),
+            vacuum_line: VolumeRate::new::<liter_per_minute>(vacuum_lpm),
+            timestamp: at(ms),
+        }
+    }
+
+    fn switch(beat: u32, phase: CardiacPhase, ms: u64) -> BeatEvent {
+        BeatEvent {
+            beat,
+            ventricle: Ventricle::Left,
+            phase,
+            profile_phase: 0,
+            timestamp: at(ms).as_micros(),
+            ended_phase_duration: None,
+        }
+    }
+
+    fn assert_ml(volume: Volume, ml: f32) {
+        let actual = volume.get::<milliliter>();
+        assert!(
+            (actual - ml).abs() < 0.01,
+            "expected {ml}mL, got {actual}mL"
+        );
+    }
+
+    #[test]
+    fn test_beat_volumes() {
+        let mut integrator = AirVolumeIntegrator::default();
+        let first_diastole = switch(1, CardiacPhase::Diastole, 0);
+        let systole = switch(2, CardiacPhase::Systole, 150);
+        let diastole = switch(2, CardiacPhase::Diastole, 450);
+        let next_beat = switch(3, CardiacPhase::Systole, 1150);
+
+        // 6 L/min is 100 mL/s and 3 L/min is 50 mL/s, on both lines throughout
+        let mut update = |switch, ms| integrator.update(Some(switch), sample(ms, 6.0, 3.0));
+
+        // Joining halfway through beat 1
+        assert_eq!(update(&first_diastole, 0), None);
+        assert_eq!(update(&first_diastole, 100), None);
+
+        // The switches fall between samples, the volumes follow the switches, not the samples
+        assert_eq!(update(&systole, 200), None);
+        for ms in (300..=400).step_by(100) {
+            assert_eq!(update(&systole, ms), None);
+        }
+        for ms in (500..=1100).step_by(100) {
+            assert_eq!(update(&diastole, ms), None);
+        }
+
+        let volumes = update(&next_beat, 1200).unwrap();
+        assert_eq!(volumes.beat, 2);
+        assert_ml(volumes.systole.pressure_line, 30.0);
+        assert_ml(volumes.systole.vacuum_line, 15.0);
+        assert_ml(volumes.diastole.pressure_line, 70.0);
+        assert_ml(volumes.diastole.vacuum_line, 35.0);
+        assert_ml(volumes.air_in(), 100.0);
+        assert_ml(volumes.air_out(), 50.0);
+    }
+
+    #[test]
+    fn test_switch_interpolates_flow() {
+        let mut integrator = AirVolumeIntegrator::default();
+        let diastole = switch(1, CardiacPhase::Diastole, 0);
+        let systole = switch(2, CardiacPhase::Systole, 50);
+
+        integrator.update(Some(&diastole), sample(0, 0.0, 0.0));
+        integrator.update(Some(&diastole), sample(40, 0.0, 0.0));
+        // The flow ramps from 0 to 6 L/min between the samples around the switch
+        integrator.update(Some(&systole), sample(60, 6.0, 0.0));
+        integrator.update(Some(&systole), sample(100, 6.0, 0.0));
+
+        let volumes = integrator
+            .update(
+                Some(&switch(3, CardiacPhase::Systole, 1000)),
+                sample(1010, 6.0, 0.0),
+            )
+            .unwrap();
+        // 3 to 6 L/min over the first 10ms of systole, 6 L/min over the remaining 940ms
+        assert_ml(volumes.systole.pressure_line, 0.75 + 94.0);
+        assert_ml(volumes.diastole.pressure_line, 0.0);
+    }
+
+    #[test]
+    fn test_switch_after_sample() {
+        let mut integrator = AirVolumeIntegrator::default();
+        let diastole = switch(1, CardiacPhase::Diastole, 0);
+        let systole = switch(2, CardiacPhase::Systole, 200);
+        let next_beat = switch(3, CardiacPhase::Systole, 400);
+
+        integrator.update(Some(&diastole), sample(0, 6.0, 0.0));
+        integrator.update(Some(&diastole), sample(95, 6.0, 0.0));
+        // The switch is published after the sample at 195ms was taken, but before it is integrated
+        assert_eq!(
+            integrator.update(Some(&systole), sample(195, 6.0, 0.0)),
+            None
+        );
+        assert_eq!(
+            integrator.update(Some(&systole), sample(295, 6.0, 0.0)),
+            None
+        );
+        assert_eq!(
+            integrator.update(Some(&systole), sample(395, 6.0, 0.0)),
+            None
+        );
+
+        let volumes = integrator
+            .update(Some(&next_beat), sample(495, 6.0, 0.0))
+            .unwrap();
+        assert_eq!(volumes.beat, 2);
+        assert_ml(volumes.systole.pressure_line, 20.0);
+    }
+
+    #[test]
+    fn test_stopping_drops_beat() {
+        let mut integrator = AirVolumeIntegrator::default();
+        let systole = |beat, ms| switch(beat, CardiacPhase::Systole, ms);
+
+        integrator.update(Some(&systole(1, 0)), sample(0, 6.0, 0.0));
+        integrator.update(Some(&systole(2, 100)), sample(100, 6.0, 0.0));
+        assert_eq!(integrator.update(None, sample(200, 0.0, 0.0)), None);
+
+        // Restarting counts beats from 1 again, the first beat is partial
+        assert_eq!(
+            integrator.update(Some(&systole(1, 250)), sample(300, 6.0, 0.0)),
+            None
+        );
+        assert_eq!(
+            integrator.update(Some(&systole(2, 350)), sample(400, 6.0, 0.0)),
+            None
+        );
+        let volumes = integrator
+            .update(Some(&systole(3, 450)), sample(500, 6.0, 0.0))
+            .unwrap();
+        assert_eq!(volumes.beat, 2);
+        assert_ml(volumes.air_in(), 10.0);
+    }
+}
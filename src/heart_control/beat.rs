@@ -0,0 +1,196 @@
+//! Beat reporting of the heart controller
+//! Phase switches and RR intervals as they happen, so the host can segment its signals per beat,
+//! and the scheduling statistics used to monitor timing during long running experiments
+
+use embassy_time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    comms::message::{duration_us, option_duration_us},
+    heart_control::phase::{CardiacPhase, Ventricle},
+};
+
+/// Phase switch of a single ventricle, lets the host segment its signals per beat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub struct BeatEvent {
+    /// Beat the switch belongs to, counted from 1 since the controller was enabled
+    pub beat: u32,
+    pub ventricle: Ventricle,
+    /// Cardiac phase the ventricle switched to
+    pub phase: CardiacPhase,
+    /// Position of the profile phase switched to in the
+    /// [`CycleProfile`](crate::heart_control::profile::CycleProfile)
+    pub profile_phase: u8,
+    /// Device time of the switch in microseconds, same clock as the report measurements
+    pub timestamp: u64,
+    /// Actual duration of the phase that just ended, `None` for the first phase after enabling
+    #[serde(with = "option_duration_us")]
+    pub ended_phase_duration: Option<Duration>,
+}
+
+/// Phase switches of a single step, at most one per ventricle
+pub type BeatEvents = heapless::Vec<BeatEvent, 2>;
+
+/// Time between the start of a beat and the start of the beat before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub struct RrInterval {
+    /// Beat that started, counted from 1 since the controller was enabled
+    pub beat: u32,
+    #[serde(with = "duration_us")]
+    pub interval: Duration,
+}
+
+/// Beat scheduling statistics, used to monitor timing during long running experiments
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub struct BeatTiming {
+    /// Beats started since the controller was enabled
+    pub beats: u32,
+    /// Time between the start of the two most recent beats
+    #[serde(with = "duration_us")]
+    pub last_rr_interval: Duration,
+    /// Phase switches since the controller was enabled
+    pub switches: u32,
+    /// Lateness of the most recent phase switch with respect to its deadline
+    #[serde(with = "duration_us")]
+    pub last_jitter: Duration,
+    /// Largest phase switch lateness seen
+    #[serde(with = "duration_us")]
+    pub max_jitter: Duration,
+    /// Sum of all phase switch lateness, divide by `switches` for the mean
+    #[serde(with = "duration_us")]
+    pub accumulated_jitter: Duration,
+    /// Times the schedule fell a whole phase behind and was re-anchored to the current time
+    pub resyncs: u32,
+    /// Total time the beat schedule was shifted by resyncs
+    #[serde(with = "duration_us")]
+    pub drift: Duration,
+}
+
+impl BeatTiming {
+    pub const fn new() -> Self {
+        Self {
+            beats: 0,
+            last_rr_interval: Duration::from_ticks(0),
+            switches: 0,
+            last_jitter: Duration::from_ticks(0),
+            max_jitter: Duration::from_ticks(0),
+            accumulated_jitter: Duration::from_ticks(0),
+            resyncs: 0,
+            drift: Duration::from_ticks(0),
+        }
+    }
+
+    pub fn record_jitter(&mut self, lateness: Duration) {
+        self.switches = self.switches.wrapping_add(1);
+        self.last_jitter = lateness;
+        self.max_jitter = self.max_jitter.max(lateness);
+        self.accumulated_jitter += lateness;
+    }
+
+    pub fn record_beat(&mut self, rr_interval: Duration) {
+        self.beats = self.beats.wrapping_add(1);
+        self.last_rr_interval = rr_interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_time::Instant;
+
+    use crate::heart_control::state_machine::{
+        HeartController,
+        tests::{dyssynchronous, parameters},
+    };
+
+    #[test]
+    fn test_phase_switch_events() {
+        let mut controller = HeartController::new();
+        let params = dyssynchronous(0.25, 0.25, 125.0);
+        let start = Instant::from_secs(1);
+        let event = |beat, ventricle, phase, at: Instant, ended_phase_duration| BeatEvent {
+            beat,
+            ventricle,
+            phase,
+            profile_phase: match phase {
+                CardiacPhase::Systole => 0,
+                CardiacPhase::Diastole => 1,
+            },
+            timestamp: at.as_micros(),
+            ended_phase_duration,
+        };
+
+        // Enabling reports the starting phase of both ventricles
+        let step = controller.step(start, Some(&params));
+        assert_eq!(
+            step.events,
+            [
+                event(1, Ventricle::Left, CardiacPhase::Systole, start, None),
+                event(1, Ventricle::Right, CardiacPhase::Diastole, start, None),
+            ]
+        );
+
+        // A late wake-up shows in the actual phase durations
+        let late = start + Duration::from_millis(129);
+        let step = controller.step(late, Some(&params));
+        assert_eq!(
+            step.events,
+            [event(
+                1,
+                Ventricle::Right,
+                CardiacPhase::Systole,
+                late,
+                Some(Duration::from_millis(129))
+            )]
+        );
+
+        // A waveform update or a repeated setpoint switches nothing
+        let step = controller.step(start + Duration::from_millis(200), Some(&params));
+        assert!(step.events.is_empty());
+
+        controller.step(start + Duration::from_millis(250), Some(&params));
+        controller.step(start + Duration::from_millis(375), Some(&params));
+        let next_beat = start + Duration::from_secs(1);
+        let step = controller.step(next_beat, Some(&params));
+        assert_eq!(
+            step.events,
+            [event(
+                2,
+                Ventricle::Left,
+                CardiacPhase::Systole,
+                next_beat,
+                Some(Duration::from_millis(750))
+            )]
+        );
+    }
+
+    #[test]
+    fn test_rr_interval_of_every_beat() {
+        let mut controller = HeartController::new();
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // The first beat has no interval before it
+        assert_eq!(controller.step(start, Some(&params)).rr_interval, None);
+        assert_eq!(controller.step(at(250), Some(&params)).rr_interval, None);
+
+        assert_eq!(
+            controller.step(at(1000), Some(&params)).rr_interval,
+            Some(RrInterval {
+                beat: 2,
+                interval: Duration::from_secs(1),
+            })
+        );
+
+        // Waking up a whole phase late starts a new, longer beat right away
+        assert_eq!(
+            controller.step(at(2625), Some(&params)).rr_interval,
+            Some(RrInterval {
+                beat: 3,
+                interval: Duration::from_millis(1625),
+            })
+        );
+        assert_eq!(controller.step(at(2875), Some(&params)).rr_interval, None);
+    }
+}
@@ -0,0 +1,173 @@
+//! Heart controller configuration that is not part of the love-letter [`love_letter::Setpoint`]
+//! These are experiment settings that rarely change, they default to the behaviour of a plain
+//! host setpoint
+
+use embassy_time::Duration;
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    f32::{Frequency, Pressure, Time},
+    frequency::hertz,
+    pressure::bar,
+};
+
+use crate::heart_control::{
+    phase::SystoleModel, pressure_loop::PressureLoopConfig, priming::PrimingConfig,
+    profile::CycleProfile, regulator_supervisor::RegulatorSupervisorConfig, rhythm::RhythmConfig,
+    state_machine::HeartParameters, sync_output::SyncOutputConfig, waveform::PressureWaveform,
+};
+
+/// Firmware side heart controller configuration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeartConfig {
+    /// Independent ventricle timing, `None` drives both ventricles with the host systole ratio
+    pub ventricles: Option<VentricleSetpoint>,
+    /// Shape of the driveline pressure during systole
+    pub waveform: PressureWaveform,
+    /// Actuation sequence within systole and diastole
+    pub profile: CycleProfile,
+    /// Duration of systole as a function of the heart rate
+    pub systole_model: SystoleModel,
+    /// Beat to beat variation of the RR interval
+    pub rhythm: RhythmConfig,
+    /// Closed-loop driveline pressure control, `None` drives the regulator open-loop
+    pub pressure_loop: Option<PressureLoopConfig>,
+    /// Fault detection on the regulator feedback, `None` for setups without a feedback sensor
+    /// Supervises with the default limits until the host configures it
+    pub regulator_supervisor: Option<RegulatorSupervisorConfig>,
+    /// How the heart controller moves to a new setpoint
+    pub transition: SetpointTransition,
+    /// Start-up sequence whenever the heart controller is enabled, `None` starts beating at the
+    /// setpoint right away
+    pub priming: Option<PrimingConfig>,
+    /// Switching delay of the left ventricle valve
+    pub left_valve_latency: ValveLatency,
+    /// Switching delay of the right ventricle valve
+    pub right_valve_latency: ValveLatency,
+    /// How beats are started, internally or from the external trigger input
+    pub pacing: PacingMode,
+    /// Pulse on the sync output at selected phase switches, `None` keeps the output idle
+    pub sync_output: Option<SyncOutputConfig>,
+}
+
+impl Default for HeartConfig {
+    fn default() -> Self {
+        Self {
+            ventricles: None,
+            waveform: PressureWaveform::default(),
+            profile: CycleProfile::default(),
+            systole_model: SystoleModel::default(),
+            rhythm: RhythmConfig::default(),
+            pressure_loop: None,
+            regulator_supervisor: Some(RegulatorSupervisorConfig::default()),
+            transition: SetpointTransition::default(),
+            priming: None,
+            left_valve_latency: ValveLatency::default(),
+            right_valve_latency: ValveLatency::default(),
+            pacing: PacingMode::default(),
+            sync_output: None,
+        }
+    }
+}
+
+/// Timing of the left and right ventricle, allows emulating dyssynchrony like a bundle branch block
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct VentricleSetpoint {
+    /// Fraction of the cardiac cycle the left ventricle spends in systole
+    pub left_systole_ratio: f32,
+    /// Fraction of the cardiac cycle the right ventricle spends in systole
+    pub right_systole_ratio: f32,
+    /// Delay of right ventricle systole after left ventricle systole, negative if the right
+    /// ventricle leads
+    pub interventricular_delay: Time,
+}
+
+/// Mechanical switching delays of a 5/2 ventricle valve, measured from the command until the
+/// driveline pressure follows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValveLatency {
+    /// Delay of switching to pressure, the start of systole
+    pub pressure: Time,
+    /// Delay of switching to vacuum, the end of systole
+    pub vacuum: Time,
+}
+
+/// Source of the heart beats, modelled after pacemaker modes
+/// Externally started beats keep the systole duration set by the heart rate and systole ratio.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum PacingMode {
+    /// Beat at the setpoint heart rate, external triggers are ignored
+    #[default]
+    Internal,
+    /// Start a beat on every external trigger, and only then
+    Triggered,
+    /// Start a beat on every external trigger, and beat internally whenever no trigger arrives
+    /// within the escape interval after the previous beat
+    Demand { escape_interval: Time },
+    /// Beat internally only when no external trigger arrives within the escape interval, an
+    /// external trigger postpones the next beat instead of starting one
+    Inhibited { escape_interval: Time },
+}
+
+/// When a new setpoint takes effect within the beat
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum SetpointTiming {
+    /// Reschedule the current beat right away
+    #[default]
+    Immediate,
+    /// Finish the current beat with the old setpoint
+    NextBeat,
+}
+
+/// Policy for moving between operating points
+/// Slew limits are applied as parameters are applied, so with [`SetpointTiming::NextBeat`] the
+/// heart rate and pressure step towards the setpoint beat by beat
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SetpointTransition {
+    pub timing: SetpointTiming,
+    /// Largest heart rate change per second, `None` for no limit
+    pub max_heart_rate_slew: Option<Frequency>,
+    /// Largest pressure change per second, `None` for no limit
+    pub max_pressure_slew: Option<Pressure>,
+}
+
+impl SetpointTransition {
+    /// Parameters moved from `from` towards `to` by at most the slew limits over `elapsed`
+    /// Systole ratios and the interventricular delay are not slew limited
+    pub fn slew(
+        &self,
+        from: &HeartParameters,
+        to: &HeartParameters,
+        elapsed: Duration,
+    ) -> HeartParameters {
+        let seconds = elapsed.as_micros() as f32 / 1_000_000.0;
+
+        let heart_rate = approach(
+            from.heart_rate.get::<hertz>(),
+            to.heart_rate.get::<hertz>(),
+            self.max_heart_rate_slew
+                .map(|slew| slew.get::<hertz>() * seconds),
+        );
+        let pressure = approach(
+            from.pressure.get::<bar>(),
+            to.pressure.get::<bar>(),
+            self.max_pressure_slew
+                .map(|slew| slew.get::<bar>() * seconds),
+        );
+
+        HeartParameters {
+            heart_rate: Frequency::new::<hertz>(heart_rate),
+            pressure: Pressure::new::<bar>(pressure),
+            ..*to
+        }
+    }
+}
+
+/// `to`, or `from` moved towards it by at most `max_step`
+fn approach(from: f32, to: f32, max_step: Option<f32>) -> f32 {
+    match max_step {
+        Some(max_step) if (to - from).abs() > max_step.abs() => {
+            from + max_step.abs().copysign(to - from)
+        }
+        _ => to,
+    }
+}
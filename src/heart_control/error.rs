@@ -1,7 +1,31 @@
-#[derive(thiserror::Error, Debug)]
+use serde::{Deserialize, Serialize};
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum ControlError {
     #[error("Unable to communicate with the Pressure Regulator")]
     Regulator,
     #[error("Unable to communicate with a Solenoid Valve")]
     Valve,
 }
+
+#[derive(
+    thiserror::Error, Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize,
+)]
+pub enum ProfileError {
+    #[error("No actuator profile of that name is stored")]
+    UnknownProfile,
+    #[error("Actuator profiles are only changed while the heart controller is disabled")]
+    HeartEnabled,
+    #[error("The built-in actuator profile can not be replaced")]
+    BuiltIn,
+    #[error("Actuator profile limits are negative, not finite or inverted")]
+    InvalidLimits,
+    #[error("No room to store another actuator profile")]
+    Full,
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SyncError {
+    #[error("Sync pulse ends too far ahead for the sync timer")]
+    TooLong,
+}
@@ -1,34 +1,80 @@
+use core::future::pending;
 use defmt::*;
-use embassy_futures::select::{Either, select};
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, watch};
-use embassy_time::{Duration, Instant, Timer};
+use embassy_futures::select::{Either4, select4};
+use embassy_sync::{
+    blocking_mutex::raw::ThreadModeRawMutex as Cs,
+    channel::Channel,
+    signal::Signal,
+    watch::{self, Watch},
+};
+use embassy_time::{Instant, Timer};
 use love_letter::{AppState, Setpoint};
 use uom::si::{f32::Pressure, pressure::bar};
 
 use crate::{
-    comms::task::CONNECTION_STATE,
-    dac::dac_task::DAC_HEART_PRESSURE_WATCH,
-    heart_control::phase::CardiacPhase,
-    valve_task::{LEFT_VALVE_WATCH, RIGHT_VALVE_WATCH, ValveState},
+    APPSTATE_WATCH,
+    comms::{message::StatusReport, task::CONNECTION_STATE},
+    framing_task::publish_status,
+    heart_control::{
+        actuator_profile::{ActuatorProfiles, ProfileCommand},
+        beat::{BeatEvent, BeatTiming},
+        config::HeartConfig,
+        error::ControlError,
+        phase::{ValveState, Ventricle},
+        pressure_controller::REGULATOR_TARGET_WATCH,
+        priming::{PrimingSequence, PrimingStatus},
+        state_machine::{ActuatorCommands, HeartController, HeartParameters},
+        sync_output::SyncSchedule,
+    },
+    sync_output_task::SYNC_COMMAND_CHANNEL,
+    trigger_task::EXTERNAL_TRIGGER_SIGNAL,
+    valve_task::{LEFT_VALVE_WATCH, RIGHT_VALVE_WATCH},
 };
 
+/// Firmware side heart configuration, see [`HeartConfig`]
+pub static HEART_CONFIG_WATCH: Watch<Cs, HeartConfig, 3> = Watch::new();
+/// Beat scheduling statistics of the heart controller
+pub static BEAT_TIMING_WATCH: Watch<Cs, BeatTiming, 1> = Watch::new();
+/// Latest phase switch of the left ventricle, empty while the heart is not beating
+pub static LEFT_PHASE_WATCH: Watch<Cs, BeatEvent, 3> = Watch::new();
+/// Latest control fault, `None` while there is none
+/// A fault sends the heart to the safe state until the host disables the heart controller
+pub static CONTROL_FAULT_WATCH: Watch<Cs, Option<ControlError>, 2> = Watch::new();
+/// Progress of the start-up sequence, only published while one is configured
+pub static PRIMING_WATCH: Watch<Cs, PrimingStatus, 1> = Watch::new();
+/// Host requests to restart the start-up sequence of the beating heart
+pub static PRIMING_SIGNAL: Signal<Cs, ()> = Signal::new();
+/// Host commands selecting or storing actuator profiles, each is acknowledged to the host
+pub static ACTUATOR_PROFILE_CHANNEL: Channel<Cs, ProfileCommand, 2> = Channel::new();
+
 /// Pneumatic heart controller routine
+/// Thin embassy wrapper around the [`HeartController`] state machine: feeds it the time and the
+/// latest setpoint, actuates its commands and sleeps until the next phase deadline
 #[embassy_executor::task]
-pub async fn heart_control_loop(mut setpoint_rx: watch::Receiver<'static, Cs, Setpoint, 3>) {
+pub async fn heart_control_loop(mut setpoint_rx: watch::Receiver<'static, Cs, Setpoint, 4>) {
     info!("starting HEART CONTROL task");
 
-    // Time spent in current cardiac phase
-    let mut time_in_phase = Duration::from_micros(0);
-    // Current cardiac phase of the heart
-    let mut current_phase = CardiacPhase::Systole;
+    // Cardiac phase state machine
+    let mut controller = HeartController::new();
 
     let connection_state_rx = CONNECTION_STATE
         .receiver()
         .expect("Update CONNECTION_STATE N");
 
-    let regulator_pressure_tx = DAC_HEART_PRESSURE_WATCH.sender();
+    let regulator_pressure_tx = REGULATOR_TARGET_WATCH.sender();
     let valve_left_tx = LEFT_VALVE_WATCH.sender();
     let valve_right_tx = RIGHT_VALVE_WATCH.sender();
+    let timing_tx = BEAT_TIMING_WATCH.sender();
+    let left_phase_tx = LEFT_PHASE_WATCH.sender();
+    let mut config_rx = HEART_CONFIG_WATCH
+        .receiver()
+        .expect("Update HEART_CONFIG_WATCH N");
+    let mut fault_rx = CONTROL_FAULT_WATCH
+        .receiver()
+        .expect("Update CONTROL_FAULT_WATCH N");
+    let fault_tx = CONTROL_FAULT_WATCH.sender();
+    let appstate_tx = APPSTATE_WATCH.sender();
+    let priming_tx = PRIMING_WATCH.sender();
 
     info!("HEART CONTROL: Moving mockloop into safe state");
     to_safe_heart_state(&regulator_pressure_tx, &valve_left_tx, &valve_right_tx);
@@ -36,120 +82,254 @@ pub async fn heart_control_loop(mut setpoint_rx: watch::Receiver<'static, Cs, Se
     info!("HEART CONTROL: Waiting for initial setpoint");
     // Current setpoint
     let mut setpoint = setpoint_rx.changed().await;
-
-    // Previous time state used to calculate time spent in current cardiac phase
-    let mut prev_time = Instant::now();
+    // Current heart configuration, defaults until one is received
+    let mut config = config_rx.try_get().unwrap_or_default();
+    // Actuator safety limits, the conservative built-in profile until the host selects another
+    let mut profiles = ActuatorProfiles::default();
+    configure(&mut controller, &profiles.active().restrict(&config));
+    // Latched fault, keeps the heart in the safe state
+    let mut fault = None;
+    // Start-up sequence, from enabling the heart controller until it is disabled again
+    let mut priming: Option<PrimingSequence> = None;
+    // Sync output pulses armed ahead of their phase switch
+    let mut sync_schedule = SyncSchedule::default();
 
     info!("HEART CONTROL: starting loop");
     loop {
-        // Only control the heart if the heart controller is enabled
-        if let Some(ref heart_setpoint) = setpoint.heart_controller_setpoint {
-            // Update time spent in current phase
-            time_in_phase += Instant::now() - prev_time;
-            debug!("HEART CONTROL: time spent in phase: {}", time_in_phase);
-
-            // Calculate total time we need to spend in current phase
-            let mut total_phase_time = current_phase
-                .get_total_phase_time(heart_setpoint.heart_rate, heart_setpoint.systole_ratio);
-
-            debug!(
-                "HEART CONTROL: Total to spend in phase: {}",
-                total_phase_time
-            );
-
-            // Are we ready to switch to a new cardiac phase?
-            if time_in_phase >= total_phase_time {
-                // We are, make the switch
-                current_phase = current_phase.switch();
-                debug!(
-                    "HEART CONTROL: switching cardiac phase to {:?}",
-                    current_phase
-                );
+        let now = Instant::now();
+        let target = HeartParameters::from_setpoint(&setpoint, &config)
+            .map(|target| profiles.active().limit(&target));
+
+        if target.is_none() {
+            // Disabling the heart controller acknowledges the fault and ends the start-up sequence
+            if fault.take().is_some() {
+                info!("HEART CONTROL: fault acknowledged");
+                fault_tx.send(None);
+                appstate_tx.send(AppState::StandBy);
+            }
+            if priming.take().is_some() {
+                priming_tx.clear();
+            }
+        } else if fault.is_none()
+            && !controller.is_enabled()
+            && let Some(priming_config) = config.priming
+        {
+            info!("HEART CONTROL: starting the start-up sequence");
+            priming = Some(PrimingSequence::new(priming_config, now));
+        }
 
-                // We entered a new phase: redo the total phase time calculation
-                total_phase_time = current_phase
-                    .get_total_phase_time(heart_setpoint.heart_rate, heart_setpoint.systole_ratio);
+        let parameters = match (&target, &priming) {
+            _ if fault.is_some() => None,
+            (Some(target), Some(priming)) => Some(priming.parameters(target, now)),
+            (target, _) => *target,
+        };
+        if let Some(priming) = &mut priming {
+            priming_tx.send(priming.status(now));
+            if let Some(status) = priming.report(now) {
+                publish_status(StatusReport::Priming(status));
+            }
+        }
+
+        // Let the state machine decide what the actuators should be doing right now
+        let step = controller.step(now, parameters.as_ref());
+
+        // Control actuators to effect current cardiac phase, or the safe state when disabled
+        actuate_heart(
+            &step.commands,
+            &regulator_pressure_tx,
+            &valve_left_tx,
+            &valve_right_tx,
+        );
+
+        // Publish beat scheduling statistics, and every RR interval to the host
+        timing_tx.send(controller.timing());
+        if let Some(rr_interval) = step.rr_interval {
+            publish_status(StatusReport::RrInterval(rr_interval));
+        }
+
+        // Keep track of the left ventricle phase for the air volume integration
+        if let Some(event) = step
+            .events
+            .iter()
+            .find(|event| event.ventricle == Ventricle::Left)
+        {
+            left_phase_tx.send(*event);
+        } else if !controller.is_enabled() && left_phase_tx.contains_value() {
+            left_phase_tx.clear();
+        }
 
-                // Reset time spend in current phase
-                time_in_phase = Duration::from_micros(0);
+        // Mark selected phase switches on the sync output, armed ahead of the next deadline
+        let next_events = config
+            .sync_output
+            .and(step.deadline)
+            .map(|deadline| controller.preview(deadline, parameters.as_ref()));
+        for command in sync_schedule.update(
+            config.sync_output.as_ref(),
+            now,
+            &step.events,
+            next_events.as_deref(),
+        ) {
+            if SYNC_COMMAND_CHANNEL.try_send(command).is_err() {
+                warn!("HEART CONTROL: sync output busy, dropping {:?}", command);
             }
+        }
 
-            // Control actuators to effect current cardiac phase
-            actuate_cardiac_phase(
-                &current_phase,
-                heart_setpoint.pressure,
-                &regulator_pressure_tx,
-                &valve_left_tx,
-                &valve_right_tx,
-            )
-            .await;
-
-            // Timekeeping
-            prev_time = Instant::now();
-
-            // Now wait until either:
-            // A: We are ready to switch cardiac phase again
-            let wait_for_next_phase = Timer::after(total_phase_time);
-            // B: We receive a new setpoint
-            match select(wait_for_next_phase, setpoint_rx.changed()).await {
-                // A: ready to switch cardiac phase
-                Either::First(_) => {
-                    // time for next phase: continue
+        // Report phase switches to the host, the status queue never holds up the heart
+        for event in step.events {
+            debug!("HEART CONTROL: beat event: {:?}", event);
+            publish_status(StatusReport::BeatEvent(event));
+        }
+
+        // Now wait until either:
+        // A: We are ready to switch cardiac phase again, the deadline is absolute so a late
+        //    wake-up does not shift the next phase. Without a deadline the controller is disabled
+        //    or idle and only waits for B, C or D
+        //    The start-up sequence also wakes us up to ramp the parameters
+        let deadline = match (
+            step.deadline,
+            priming
+                .as_ref()
+                .and_then(|priming| priming.next_update(now)),
+        ) {
+            (Some(deadline), Some(update)) => Some(deadline.min(update)),
+            (deadline, update) => deadline.or(update),
+        };
+        let wait_for_next_phase = async {
+            match deadline {
+                Some(deadline) => Timer::at(deadline).await,
+                None => pending::<()>().await,
+            }
+        };
+        // B: We receive a new setpoint
+        // C: We receive a new heart configuration
+        // D: The external trigger input fires
+        // E: A fault is raised
+        // F: The host sends an actuator profile command
+        // G: The host restarts the start-up sequence
+        let event = select4(
+            select4(
+                wait_for_next_phase,
+                setpoint_rx.changed(),
+                config_rx.changed(),
+                EXTERNAL_TRIGGER_SIGNAL.wait(),
+            ),
+            fault_rx.changed(),
+            ACTUATOR_PROFILE_CHANNEL.receive(),
+            PRIMING_SIGNAL.wait(),
+        )
+        .await;
+        let event = match event {
+            Either4::First(event) => event,
+            // E: Fault; the next step moves to the safe state
+            Either4::Second(new_fault) => {
+                if let Some(new_fault) = new_fault {
+                    error!("HEART CONTROL: {}, moving to safe state", new_fault);
+                    fault = Some(new_fault);
+                    appstate_tx.send(AppState::Fault);
+                    if let Some(priming) = &mut priming {
+                        priming.abort();
+                    }
                 }
-                // B: Received a new setpoint; cancel wait and redo above calculations
-                Either::Second(new_setpoint) => {
-                    debug!(
-                        "HEART CONTROL: Received a new setpoint from host: {:?}",
-                        new_setpoint
-                    );
-                    // update current setpoint and continue
-                    setpoint = new_setpoint;
+                continue;
+            }
+            // F: Profile command; always acknowledged, even when refused
+            Either4::Third(command) => {
+                let ack = profiles.handle(command, setpoint.heart_controller_setpoint.is_some());
+                match ack.result {
+                    Ok(()) => {
+                        info!("HEART CONTROL: actuator profile command applied: {:?}", ack);
+                        configure(&mut controller, &profiles.active().restrict(&config));
+                    }
+                    Err(err) => warn!("HEART CONTROL: actuator profile command refused: {}", err),
                 }
+                publish_status(StatusReport::ProfileAck(ack));
+                continue;
+            }
+            // G: Start-up request; only a beating heart without a fault is primed again
+            Either4::Fourth(()) => {
+                match config.priming {
+                    Some(priming_config) if controller.is_enabled() && fault.is_none() => {
+                        info!("HEART CONTROL: restarting the start-up sequence");
+                        priming = Some(PrimingSequence::new(priming_config, Instant::now()));
+                    }
+                    Some(_) => warn!(
+                        "HEART CONTROL: start-up sequence requested while not beating, ignored"
+                    ),
+                    None => {
+                        warn!("HEART CONTROL: start-up sequence requested but none is configured")
+                    }
+                }
+                continue;
+            }
+        };
+        match event {
+            // A: ready to switch cardiac phase
+            Either4::First(_) => {
+                // time for next phase: continue
+            }
+            // B: Received a new setpoint; cancel wait and step the state machine again
+            Either4::Second(new_setpoint) => {
+                debug!(
+                    "HEART CONTROL: Received a new setpoint from host: {:?}",
+                    new_setpoint
+                );
+                // update current setpoint and continue
+                setpoint = new_setpoint;
+            }
+            // C: Received a new configuration; cancel wait and step the state machine again
+            Either4::Third(new_config) => {
+                debug!("HEART CONTROL: Received a new heart configuration");
+                configure(&mut controller, &profiles.active().restrict(&new_config));
+                config = new_config;
+            }
+            // D: External trigger; the state machine handles it according to the pacing mode
+            Either4::Fourth(at) => {
+                controller.trigger(at);
             }
-        } else {
-            // Heart Controller is disabled: Set the valves and pressure regulator into safe state
-            debug!("HEART CONTROL: DISABLED -> Moving to safe state and ready for more action");
-
-            to_safe_heart_state(&regulator_pressure_tx, &valve_left_tx, &valve_right_tx);
-
-            // Await a new setpoint
-            setpoint = setpoint_rx.changed().await;
         }
     }
 }
 
-/// Heart Controller is enabled: Actuate the valve and pressure regulator
-async fn actuate_cardiac_phase(
-    current_phase: &CardiacPhase,
-    heart_pressure: Pressure,
+/// Hand the heart configuration to the [`HeartController`]
+fn configure(controller: &mut HeartController, config: &HeartConfig) {
+    controller.set_waveform(config.waveform.clone());
+    controller.set_profile(config.profile.clone());
+    controller.set_systole_model(config.systole_model);
+    controller.set_rhythm(config.rhythm);
+    controller.set_transition(config.transition);
+    controller.set_valve_latency(Ventricle::Left, &config.left_valve_latency);
+    controller.set_valve_latency(Ventricle::Right, &config.right_valve_latency);
+    controller.set_pacing(config.pacing);
+}
+
+/// Actuate the valves and pressure regulator as commanded by the [`HeartController`]
+fn actuate_heart(
+    commands: &ActuatorCommands,
     heart_pressure_tx: &watch::Sender<'static, Cs, Pressure, 1>,
     valve_left_tx: &watch::Sender<'static, Cs, ValveState, 1>,
     valve_right_tx: &watch::Sender<'static, Cs, ValveState, 1>,
 ) {
-    // Find out the required solenoid valve states
-    let left_valve_setpoint = get_valve_state_for_cardiac_phase(current_phase);
-    let right_valve_setpoint = get_valve_state_for_cardiac_phase(current_phase);
-
     debug!(
-        "HEART CONTROL: Enabled - actuating valves ({:?}, {:?}) and pressure regulator ({:?}bar)",
-        left_valve_setpoint,
-        right_valve_setpoint,
-        heart_pressure.get::<bar>()
+        "HEART CONTROL: actuating valves ({:?}, {:?}) and pressure regulator ({:?}bar)",
+        commands.left_valve,
+        commands.right_valve,
+        commands.regulator_pressure.get::<bar>()
     );
 
     // Actuate the pressure regulator
-    control_pressure_regulator(heart_pressure, heart_pressure_tx);
+    control_pressure_regulator(commands.regulator_pressure, heart_pressure_tx);
 
     // Actuate the ventricle valves according to the current cardiac phase
     control_ventricle_valves(
-        left_valve_setpoint,
-        right_valve_setpoint,
+        commands.left_valve,
+        commands.right_valve,
         valve_left_tx,
         valve_right_tx,
     )
 }
 
-/// Set pressure regulator to the latest setpoint received for it
+/// Set pressure regulator to the latest setpoint received for it, the pressure control loop
+/// forwards it to the regulator
 fn control_pressure_regulator(pressure: Pressure, tx: &watch::Sender<'static, Cs, Pressure, 1>) {
     trace!(
         "Controlling regulator pressure to: {:?}bar",
@@ -180,20 +360,11 @@ fn to_safe_heart_state(
     valve_left_tx: &watch::Sender<'static, Cs, ValveState, 1>,
     valve_right_tx: &watch::Sender<'static, Cs, ValveState, 1>,
 ) {
-    /// 0 bar pressure seems like the safest state for the solenoid
-    const HEART_REGULATOR_SAFE_PRESSURE_BAR: f32 = 0.0;
-    /// Safest solenoid state. Alternative is Vacuum which seems less safe
-    const SAFE_SOLENOID_STATE: ValveState = ValveState::Pressure;
-
     trace!("HEART CONTROL: to SAFE state",);
 
-    control_pressure_regulator(
-        Pressure::new::<bar>(HEART_REGULATOR_SAFE_PRESSURE_BAR),
+    actuate_heart(
+        &ActuatorCommands::safe(),
         heart_pressure_tx,
-    );
-    control_ventricle_valves(
-        SAFE_SOLENOID_STATE,
-        SAFE_SOLENOID_STATE,
         valve_left_tx,
         valve_right_tx,
     )
@@ -203,10 +374,3 @@ fn to_safe_heart_state(
 fn calculate_appstate() -> AppState {
     AppState::StandBy
 }
-
-fn get_valve_state_for_cardiac_phase(phase: &CardiacPhase) -> ValveState {
-    match phase {
-        CardiacPhase::Systole => ValveState::Pressure,
-        CardiacPhase::Diastole => ValveState::Vacuum,
-    }
-}
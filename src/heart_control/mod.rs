@@ -1,3 +1,20 @@
+pub mod actuator_profile;
+pub mod air_volume;
+pub mod beat;
+pub mod config;
 pub mod error;
+#[cfg(target_os = "none")]
 pub mod heart_controller;
+pub mod pacing;
 pub mod phase;
+#[cfg(target_os = "none")]
+pub mod pressure_controller;
+pub mod pressure_loop;
+pub mod priming;
+pub mod profile;
+pub mod regulator_supervisor;
+pub mod rhythm;
+pub mod schedule;
+pub mod state_machine;
+pub mod sync_output;
+pub mod waveform;
@@ -0,0 +1,211 @@
+//! Pacing of the heart controller
+//! Decides what starts the next beat: the internal rhythm, an external trigger, or whichever comes
+//! first, see [`PacingMode`]
+
+use defmt::debug;
+use embassy_time::Instant;
+use uom::si::{f32::Time, time::microsecond};
+
+use crate::heart_control::config::PacingMode;
+
+/// Beat source of the heart controller, keeps track of the external triggers
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    mode: PacingMode,
+    /// External trigger waiting to be handled
+    trigger: Option<Instant>,
+    /// Latest external trigger that inhibited a beat
+    sensed: Option<Instant>,
+}
+
+impl Pacer {
+    pub const fn new(mode: PacingMode) -> Self {
+        Self {
+            mode,
+            trigger: None,
+            sensed: None,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: PacingMode) {
+        self.mode = mode;
+    }
+
+    /// Register an edge of the external trigger input at `at`, handled by the next
+    /// [`Pacer::take_trigger`]
+    pub fn trigger(&mut self, at: Instant) {
+        self.trigger = Some(at);
+    }
+
+    /// Forget all external triggers
+    pub fn reset(&mut self) {
+        self.trigger = None;
+        self.sensed = None;
+    }
+
+    /// Handle the pending external trigger according to the pacing mode, returns its moment if it
+    /// starts a beat
+    pub fn take_trigger(&mut self) -> Option<Instant> {
+        let trigger = self.trigger.take()?;
+
+        match self.mode {
+            PacingMode::Internal => None,
+            PacingMode::Triggered | PacingMode::Demand { .. } => {
+                debug!("HEART CONTROL: external trigger, starting a beat");
+                Some(trigger)
+            }
+            PacingMode::Inhibited { .. } => {
+                debug!("HEART CONTROL: external trigger, inhibiting the next beat");
+                self.sensed = Some(trigger);
+                None
+            }
+        }
+    }
+
+    /// Start of the next internally paced beat in microseconds since `anchor`, `None` if only an
+    /// external trigger starts it
+    /// The current beat started `beat_start_us` after `anchor` and lasts `rr_us` by itself.
+    pub fn next_beat_us(
+        &self,
+        anchor: Option<Instant>,
+        beat_start_us: u64,
+        rr_us: u64,
+    ) -> Option<u64> {
+        let escape_us =
+            |escape_interval: Time| (escape_interval.get::<microsecond>().max(0.0) as u64).max(1);
+
+        match self.mode {
+            PacingMode::Internal => Some(beat_start_us + rr_us),
+            PacingMode::Triggered => None,
+            PacingMode::Demand { escape_interval } => {
+                Some(beat_start_us + escape_us(escape_interval))
+            }
+            PacingMode::Inhibited { escape_interval } => {
+                // The escape interval restarts at the latest sensed trigger
+                let sensed_us = match (self.sensed, anchor) {
+                    (Some(sensed), Some(anchor)) => {
+                        sensed.saturating_duration_since(anchor).as_micros()
+                    }
+                    _ => 0,
+                };
+
+                Some(beat_start_us.max(sensed_us) + escape_us(escape_interval))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_time::Duration;
+    use uom::si::time::millisecond;
+
+    use crate::heart_control::{
+        phase::{CardiacPhase, VentriclePhases},
+        state_machine::{HeartController, tests::parameters},
+    };
+
+    #[test]
+    fn test_triggered_pacing() {
+        let mut controller = HeartController::new();
+        controller.set_pacing(PacingMode::Triggered);
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        controller.step(start, Some(&params));
+        let step = controller.step(at(250), Some(&params));
+        assert_eq!(step.deadline, None);
+
+        // No beat starts by itself, however long the diastole lasts
+        controller.step(at(3000), Some(&params));
+        assert_eq!(controller.beat_epoch(), Some(start));
+
+        // A trigger starts a beat from the moment of the edge
+        controller.trigger(at(3500));
+        let step = controller.step(at(3502), Some(&params));
+        assert_eq!(controller.beat_epoch(), Some(at(3500)));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(step.deadline, Some(at(3750)));
+        assert_eq!(controller.timing().beats, 2);
+        assert_eq!(
+            controller.timing().last_rr_interval,
+            Duration::from_millis(3500)
+        );
+    }
+
+    #[test]
+    fn test_demand_pacing() {
+        let mut controller = HeartController::new();
+        controller.set_pacing(PacingMode::Demand {
+            escape_interval: Time::new::<millisecond>(1500.0),
+        });
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        controller.step(start, Some(&params));
+        let step = controller.step(at(250), Some(&params));
+        assert_eq!(step.deadline, Some(at(1500)));
+
+        // Without a trigger the escape interval paces the next beat
+        controller.step(at(1500), Some(&params));
+        assert_eq!(controller.beat_epoch(), Some(at(1500)));
+
+        // A trigger within the escape interval starts the beat instead, and restarts the interval
+        controller.trigger(at(2500));
+        controller.step(at(2500), Some(&params));
+        assert_eq!(controller.beat_epoch(), Some(at(2500)));
+        let step = controller.step(at(2750), Some(&params));
+        assert_eq!(step.deadline, Some(at(4000)));
+        assert_eq!(controller.timing().beats, 3);
+    }
+
+    #[test]
+    fn test_inhibited_pacing() {
+        let mut controller = HeartController::new();
+        controller.set_pacing(PacingMode::Inhibited {
+            escape_interval: Time::new::<millisecond>(1500.0),
+        });
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        controller.step(start, Some(&params));
+        controller.step(at(250), Some(&params));
+
+        // A sensed trigger does not start a beat but postpones the next one
+        controller.trigger(at(1000));
+        let step = controller.step(at(1000), Some(&params));
+        assert_eq!(controller.beat_epoch(), Some(start));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Diastole)
+        );
+        assert_eq!(step.deadline, Some(at(2500)));
+
+        controller.step(at(2500), Some(&params));
+        assert_eq!(controller.beat_epoch(), Some(at(2500)));
+        assert_eq!(
+            controller.timing().last_rr_interval,
+            Duration::from_millis(2500)
+        );
+    }
+
+    #[test]
+    fn test_internal_pacing_ignores_triggers() {
+        let mut controller = HeartController::new();
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+
+        controller.step(start, Some(&params));
+        controller.trigger(start + Duration::from_millis(500));
+        let step = controller.step(start + Duration::from_millis(500), Some(&params));
+        assert_eq!(controller.beat_epoch(), Some(start));
+        assert_eq!(step.deadline, Some(start + Duration::from_secs(1)));
+    }
+}
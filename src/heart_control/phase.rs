@@ -1,89 +1,315 @@
-use defmt::debug;
+use core::marker::PhantomData;
 use embassy_time::Duration;
-use uom::si::{f32::Frequency, frequency::hertz};
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    f32::{Frequency, Time},
+    frequency::hertz,
+    time::second,
+};
 
 /// Phases of the heart ventricles
 /// Systole = ventricle contraction, Diastole = ventricle relaxation
-#[derive(Debug, defmt::Format)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
 pub enum CardiacPhase {
     Systole,
     Diastole,
 }
 
+/// Position of a ventricle's driveline valve, connecting it to the pressure or the vacuum
+/// supply
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum ValveState {
+    Pressure,
+    Vacuum,
+}
+
 impl CardiacPhase {
-    pub fn switch(self) -> Self {
+    /// Offset from the start of the beat at which this phase ends
+    /// Offsets are computed from the beat start rather than summed per phase, so rounding to
+    /// timer ticks never accumulates over consecutive phases
+    pub fn get_phase_end(&self, cycle_period_us: u64, systole_ratio: f32) -> Duration {
         match self {
-            CardiacPhase::Systole => CardiacPhase::Diastole,
-            CardiacPhase::Diastole => CardiacPhase::Systole,
+            CardiacPhase::Systole => Duration::from_micros(
+                (cycle_period_us as f32 * systole_ratio.clamp(0.0, 1.0)) as u64,
+            ),
+            CardiacPhase::Diastole => Duration::from_micros(cycle_period_us),
         }
     }
+}
 
-    pub fn get_total_phase_time(&self, heart_rate: Frequency, systole_ratio: f32) -> Duration {
-        debug!(
-            "getting total phase time for {}hz and {} sys/diastole ratio",
-            heart_rate.get::<hertz>(),
-            systole_ratio
-        );
+/// Ventricle of the pneumatic heart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum Ventricle {
+    Left,
+    Right,
+}
 
-        const US_IN_SEC: f32 = 1_000_000.0;
+/// Cardiac phase of each ventricle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct VentriclePhases {
+    pub left: CardiacPhase,
+    pub right: CardiacPhase,
+}
 
-        let heart_rate_hz = heart_rate.get::<hertz>();
+impl VentriclePhases {
+    pub const fn both(phase: CardiacPhase) -> Self {
+        Self {
+            left: phase,
+            right: phase,
+        }
+    }
 
-        let full_cycle_period_us = 1.0 / heart_rate_hz * US_IN_SEC;
+    pub fn get(&self, ventricle: Ventricle) -> CardiacPhase {
+        match ventricle {
+            Ventricle::Left => self.left,
+            Ventricle::Right => self.right,
+        }
+    }
+}
 
-        debug!("full_cycle_period_us: {}", full_cycle_period_us);
+/// Relation between the heart rate and the duration of systole
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum SystoleRelation {
+    /// Systole lasts the setpoint systole ratio of the cycle
+    #[default]
+    FixedRatio,
+    /// Linear regression of the ejection time on the heart rate, e.g. Weissler's
+    /// LVET = 413ms - 1.7ms/bpm * HR
+    Linear {
+        /// Systole duration extrapolated to a heart rate of 0
+        intercept: Time,
+        /// Shortening of systole per beat per minute
+        slope: Time,
+    },
+    /// Systole scales with the square root of the cycle period like Bazett's QT formula,
+    /// systole = k * sqrt(RR / 1s)
+    Bazett {
+        /// Systole duration at a cycle period of 1s, 60bpm
+        k: Time,
+    },
+}
 
-        let ratio = match self {
-            CardiacPhase::Systole => systole_ratio,
-            CardiacPhase::Diastole => 1.0 - systole_ratio,
-        };
+/// Systole duration model, diastole absorbs the remainder of the cycle
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SystoleModel {
+    pub relation: SystoleRelation,
+    /// Shortest diastole the model leaves, systole is cut short to keep it
+    pub min_diastole: Time,
+}
+
+impl SystoleModel {
+    /// Systole lasts the setpoint systole ratio of the cycle
+    pub const DEFAULT: Self = Self {
+        relation: SystoleRelation::FixedRatio,
+        min_diastole: Time {
+            dimension: PhantomData,
+            units: PhantomData,
+            value: 0.0,
+        },
+    };
+}
+
+impl Default for SystoleModel {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
 
-        if full_cycle_period_us == f32::INFINITY {
-            debug!("full_cycle_period_us == INF -> {}", Duration::MAX);
-            Duration::MAX
-        } else {
-            let out = (full_cycle_period_us * ratio) as u64;
-            debug!("full_cycle_period_us != INF -> {}", out);
-            Duration::from_micros(out)
+impl SystoleModel {
+    /// Fraction of a cycle of `cycle_period_us` spent in systole
+    /// `systole_ratio` is the setpoint ratio, only used by [`SystoleRelation::FixedRatio`]
+    pub fn systole_ratio(&self, cycle_period_us: u64, systole_ratio: f32) -> f32 {
+        let period_s = cycle_period_us as f32 / 1_000_000.0;
+        if period_s <= 0.0 {
+            return systole_ratio.clamp(0.0, 1.0);
         }
+
+        let systole_s = match self.relation {
+            SystoleRelation::FixedRatio => period_s * systole_ratio,
+            SystoleRelation::Linear { intercept, slope } => {
+                intercept.get::<second>() - slope.get::<second>() * 60.0 / period_s
+            }
+            SystoleRelation::Bazett { k } => k.get::<second>() * sqrt(period_s),
+        };
+        let max_systole_s = (period_s - self.min_diastole.get::<second>()).max(0.0);
+
+        systole_s.clamp(0.0, max_systole_s) / period_s
+    }
+}
+
+/// Square root by Newton's method, no libm needed
+/// Converges to f32 precision for the cycle periods we see, 0.1s up to a few seconds
+fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+
+    let mut root = if x > 1.0 { x } else { 1.0 };
+    for _ in 0..8 {
+        root = 0.5 * (root + x / root);
+    }
+
+    root
+}
+
+/// Period of a full cardiac cycle in whole microseconds, `None` if the heart is not beating
+pub fn get_cycle_period_us(heart_rate: Frequency) -> Option<u64> {
+    const US_IN_SEC: f32 = 1_000_000.0;
+
+    let full_cycle_period_us = US_IN_SEC / heart_rate.get::<hertz>();
+
+    if full_cycle_period_us.is_finite() && full_cycle_period_us >= 1.0 {
+        Some(full_cycle_period_us as u64)
+    } else {
+        None
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use uom::si::{f32::Frequency, frequency::cycle_per_minute};
+    use embassy_time::Instant;
+    use uom::si::{f32::Frequency, frequency::cycle_per_minute, time::millisecond};
+
+    use crate::heart_control::state_machine::{HeartController, tests::parameters};
 
     #[test]
     fn test_phase_timing() {
-        // Heart rate: 60 bpm = 1 Hz
-        let hr = Frequency::new::<cycle_per_minute>(60.0);
+        // Heart rate: 60 bpm = 1 Hz → 1s period
+        let period_us = get_cycle_period_us(Frequency::new::<cycle_per_minute>(60.0)).unwrap();
         let systole_ratio = 0.3;
 
-        // Full cycle: 1 second
         // Systole: 0.3s
         assert_eq!(
-            CardiacPhase::Systole.get_total_phase_time(hr, systole_ratio),
-            Duration::from_nanos(300000000)
+            CardiacPhase::Systole.get_phase_end(period_us, systole_ratio),
+            Duration::from_millis(300)
         );
-        // Diastole: 0.7s
+        // Diastole: the remaining 0.7s, ending with the beat
         assert_eq!(
-            CardiacPhase::Diastole.get_total_phase_time(hr, systole_ratio),
-            Duration::from_secs(700000000)
+            CardiacPhase::Diastole.get_phase_end(period_us, systole_ratio)
+                - CardiacPhase::Systole.get_phase_end(period_us, systole_ratio),
+            Duration::from_millis(700)
         );
 
         // More realistic heart rate: 120 bpm = 2 Hz → 0.5s period
-        let hr_fast = Frequency::new::<cycle_per_minute>(120.0);
+        let period_fast_us =
+            get_cycle_period_us(Frequency::new::<cycle_per_minute>(120.0)).unwrap();
 
         // Systole: 0.5 * 0.3 = 0.15s
         assert_eq!(
-            CardiacPhase::Systole.get_total_phase_time(hr_fast, systole_ratio),
-            Duration::from_nanos(150000000)
+            CardiacPhase::Systole.get_phase_end(period_fast_us, systole_ratio),
+            Duration::from_millis(150)
         );
         // Diastole: 0.5 * 0.7 = 0.35s
         assert_eq!(
-            CardiacPhase::Diastole.get_total_phase_time(hr_fast, systole_ratio),
-            Duration::from_nanos(350000000)
+            CardiacPhase::Diastole.get_phase_end(period_fast_us, systole_ratio)
+                - CardiacPhase::Systole.get_phase_end(period_fast_us, systole_ratio),
+            Duration::from_millis(350)
         );
     }
+
+    #[test]
+    fn test_phase_end() {
+        // Heart rate: 60 bpm = 1 Hz → 1s period
+        let period_us = get_cycle_period_us(Frequency::new::<cycle_per_minute>(60.0)).unwrap();
+        assert_eq!(period_us, 1_000_000);
+
+        // Systole ends a quarter into the beat, diastole at the end of the beat
+        assert_eq!(
+            CardiacPhase::Systole.get_phase_end(period_us, 0.25),
+            Duration::from_millis(250)
+        );
+        assert_eq!(
+            CardiacPhase::Diastole.get_phase_end(period_us, 0.25),
+            Duration::from_secs(1)
+        );
+
+        // A heart that does not beat has no period
+        assert_eq!(
+            get_cycle_period_us(Frequency::new::<cycle_per_minute>(0.0)),
+            None
+        );
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.0001,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn test_fixed_ratio_systole() {
+        let model = SystoleModel::default();
+        assert_close(model.systole_ratio(1_000_000, 0.35), 0.35);
+
+        // The minimum diastole still applies
+        let model = SystoleModel {
+            min_diastole: Time::new::<second>(0.8),
+            ..model
+        };
+        assert_close(model.systole_ratio(1_000_000, 0.35), 0.2);
+    }
+
+    #[test]
+    fn test_linear_systole() {
+        let model = SystoleModel {
+            relation: SystoleRelation::Linear {
+                intercept: Time::new::<second>(0.413),
+                slope: Time::new::<second>(0.0017),
+            },
+            min_diastole: Time::new::<second>(0.1),
+        };
+
+        // 60bpm: 413ms - 102ms
+        assert_close(model.systole_ratio(1_000_000, 0.5), 0.311);
+        // 120bpm: 413ms - 204ms of a 500ms cycle
+        assert_close(model.systole_ratio(500_000, 0.5), 0.418);
+        // 200bpm: 73ms of a 300ms cycle
+        assert_close(model.systole_ratio(300_000, 0.5), 0.073 / 0.3);
+        // 40bpm: systole lengthens far less than the cycle
+        assert_close(model.systole_ratio(1_500_000, 0.5), 0.345 / 1.5);
+    }
+
+    #[test]
+    fn test_bazett_systole() {
+        let model = SystoleModel {
+            relation: SystoleRelation::Bazett {
+                k: Time::new::<second>(0.4),
+            },
+            min_diastole: Time::new::<second>(0.15),
+        };
+
+        assert_close(model.systole_ratio(1_000_000, 0.5), 0.4);
+        // 150bpm: 0.4 * sqrt(0.4) = 253ms, cut to leave 150ms of diastole
+        assert_close(model.systole_ratio(400_000, 0.5), 0.25 / 0.4);
+        // 30bpm: 0.4 * sqrt(2) = 566ms
+        assert_close(model.systole_ratio(2_000_000, 0.5), 0.565_685 / 2.0);
+    }
+
+    #[test]
+    fn test_systole_model_follows_heart_rate() {
+        let mut controller = HeartController::new();
+        controller.set_systole_model(SystoleModel {
+            relation: SystoleRelation::Bazett {
+                k: Time::new::<millisecond>(500.0),
+            },
+            min_diastole: Time::new::<millisecond>(100.0),
+        });
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // 60bpm: the setpoint systole ratio is ignored
+        let step = controller.step(start, Some(&parameters(60.0, 0.25, 1.0)));
+        assert_eq!(step.deadline, Some(at(500)));
+
+        // 240bpm: systole would last the whole 250ms cycle, it is cut short to keep the minimum
+        // diastole
+        let params = parameters(240.0, 0.25, 1.0);
+        let step = controller.step(at(1000), Some(&params));
+        assert_eq!(controller.beat_epoch(), Some(at(1000)));
+        assert_eq!(step.deadline, Some(at(1150)));
+        let step = controller.step(at(1150), Some(&params));
+        assert_eq!(step.deadline, Some(at(1250)));
+    }
 }
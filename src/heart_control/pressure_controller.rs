@@ -0,0 +1,139 @@
+use defmt::*;
+use embassy_futures::select::{Either3, select3};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, watch::Watch};
+use embassy_time::{Instant, Ticker};
+use uom::si::{f32::Pressure, pressure::bar};
+
+use crate::{
+    adc_task::REGULATOR_PRESSURE_WATCH,
+    dac::dac_task::DAC_HEART_PRESSURE_WATCH,
+    heart_control::{
+        heart_controller::{CONTROL_FAULT_WATCH, HEART_CONFIG_WATCH},
+        pressure_loop::{PRESSURE_LOOP_PERIOD, PressureLoop, PressureLoopStatus},
+        regulator_supervisor::RegulatorSupervisor,
+    },
+};
+
+/// Regulator pressure requested by the heart controller, before closed-loop trimming
+pub static REGULATOR_TARGET_WATCH: Watch<Cs, Pressure, 1> = Watch::new();
+/// Tracking of the closed-loop pressure control, only published while the loop is enabled
+pub static PRESSURE_LOOP_WATCH: Watch<Cs, PressureLoopStatus, 1> = Watch::new();
+
+/// Driveline pressure control routine
+/// Forwards the heart controller pressure to the regulator DAC, trimmed at a fixed rate by the
+/// [`PressureLoop`] on the regulator feedback when one is configured. The
+/// [`RegulatorSupervisor`] checks the feedback against the same pressure.
+#[embassy_executor::task]
+pub async fn pressure_control_loop() {
+    info!("starting PRESSURE CONTROL task");
+
+    let mut target_rx = REGULATOR_TARGET_WATCH
+        .receiver()
+        .expect("Update REGULATOR_TARGET_WATCH N");
+    let mut measured_rx = REGULATOR_PRESSURE_WATCH
+        .receiver()
+        .expect("Update REGULATOR_PRESSURE_WATCH N");
+    let mut config_rx = HEART_CONFIG_WATCH
+        .receiver()
+        .expect("Update HEART_CONFIG_WATCH N");
+    let regulator_pressure_tx = DAC_HEART_PRESSURE_WATCH.sender();
+    let status_tx = PRESSURE_LOOP_WATCH.sender();
+    let fault_tx = CONTROL_FAULT_WATCH.sender();
+
+    // Heart configuration, defaults until one is received
+    let config = config_rx.try_get().unwrap_or_default();
+    // Closed-loop control, `None` while driving the regulator open-loop
+    let mut pressure_loop = config.pressure_loop.map(PressureLoop::new);
+    // Regulator fault detection, `None` while disabled
+    let mut supervisor = config.regulator_supervisor.map(RegulatorSupervisor::new);
+    // Whether the supervisor raised a fault for the ongoing deviation
+    let mut faulted = false;
+    // Until the heart controller asks for pressure the regulator stays vented
+    let mut target = Pressure::new::<bar>(0.0);
+    let mut last_update = Instant::now();
+    let mut ticker = Ticker::every(PRESSURE_LOOP_PERIOD);
+
+    info!("PRESSURE CONTROL: starting loop");
+    loop {
+        // Wait until either:
+        // A: The loop period elapsed, time to supervise the regulator and, while controlling
+        //    closed-loop, to trim the command
+        // B: The heart controller requests a new pressure
+        // C: We receive a new heart configuration
+        let ticked = match select3(ticker.next(), target_rx.changed(), config_rx.changed()).await {
+            Either3::First(_) => true,
+            Either3::Second(new_target) => {
+                target = new_target;
+                false
+            }
+            Either3::Third(config) => {
+                match (&mut pressure_loop, config.pressure_loop) {
+                    (Some(pressure_loop), Some(loop_config)) => {
+                        pressure_loop.set_config(loop_config)
+                    }
+                    (pressure_loop, loop_config) => {
+                        debug!(
+                            "PRESSURE CONTROL: closed-loop control enabled: {}",
+                            loop_config.is_some()
+                        );
+                        *pressure_loop = loop_config.map(PressureLoop::new);
+                    }
+                }
+                match (&mut supervisor, config.regulator_supervisor) {
+                    (Some(supervisor), Some(supervisor_config)) => {
+                        supervisor.set_config(supervisor_config)
+                    }
+                    (supervisor, supervisor_config) => {
+                        *supervisor = supervisor_config.map(RegulatorSupervisor::new);
+                    }
+                }
+                false
+            }
+        };
+
+        let now = Instant::now();
+
+        // The heart controller moves to the safe state on a fault, raise it once per deviation
+        if ticked
+            && let (Some(supervisor), Some(measured)) = (&mut supervisor, measured_rx.try_get())
+        {
+            match supervisor.update(target, measured, now) {
+                Ok(()) => faulted = false,
+                Err(fault) if !faulted => {
+                    error!(
+                        "PRESSURE CONTROL: regulator at {:?}bar does not follow {:?}bar",
+                        measured.get::<bar>(),
+                        target.get::<bar>()
+                    );
+                    fault_tx.send(Some(fault));
+                    faulted = true;
+                }
+                Err(_) => {}
+            }
+        }
+
+        if ticked && pressure_loop.is_none() {
+            continue;
+        }
+
+        let dt = now - last_update;
+        last_update = now;
+
+        // Without feedback there is nothing to close the loop on, drive the regulator open-loop
+        let command = match (&mut pressure_loop, measured_rx.try_get()) {
+            (Some(pressure_loop), Some(measured)) => {
+                let status = pressure_loop.update(target, measured, dt);
+                trace!(
+                    "PRESSURE CONTROL: tracking error {:?}bar",
+                    status.error.get::<bar>()
+                );
+                status_tx.send(status);
+
+                status.command
+            }
+            _ => target,
+        };
+
+        regulator_pressure_tx.send(command);
+    }
+}
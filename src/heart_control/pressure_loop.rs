@@ -0,0 +1,287 @@
+//! Closed-loop driveline pressure control
+//! Trims the pressure regulator command with a PID loop on the regulator feedback channel, so the
+//! measured driveline pressure tracks the heart pressure setpoint
+
+use embassy_time::Duration;
+use serde::{Deserialize, Serialize};
+use uom::si::{f32::Pressure, pressure::bar};
+
+use crate::dac::setpoint::RegulatorSetpoint;
+
+/// Rate at which the pressure loop trims the regulator command, matches the ADC sample rate
+pub const PRESSURE_LOOP_PERIOD: Duration = Duration::from_millis(10);
+
+/// PID gains of the pressure loop, acting on the pressure error in bar
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct PidGains {
+    /// Proportional gain, bar of trim per bar of error
+    pub kp: f32,
+    /// Integral gain, bar of trim per bar of error per second
+    pub ki: f32,
+    /// Derivative gain, bar of trim per bar per second change of the measured pressure
+    pub kd: f32,
+}
+
+/// Pressure loop configuration
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PressureLoopConfig {
+    pub gains: PidGains,
+    /// Largest correction the loop may add to or take from the setpoint
+    pub max_trim: Pressure,
+}
+
+/// Outcome of a single pressure loop update
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PressureLoopStatus {
+    /// Pressure the heart controller asked for
+    pub target: Pressure,
+    /// Latest regulator feedback
+    pub measured: Pressure,
+    /// Tracking error, `target - measured`
+    pub error: Pressure,
+    /// Regulator command after trimming and clamping
+    pub command: Pressure,
+}
+
+/// PID loop trimming the regulator command
+/// The setpoint is fed forward, the loop only adds a bounded correction on top. The integral term
+/// stops integrating while the output saturates in the direction of the error, so it does not
+/// wind up during long systoles the regulator cannot reach.
+#[derive(Debug, Clone)]
+pub struct PressureLoop {
+    config: PressureLoopConfig,
+    /// Integral contribution to the trim in bar
+    integral: f32,
+    /// Measured pressure in bar at the previous update, for the derivative term
+    previous_measured: Option<f32>,
+}
+
+impl PressureLoop {
+    pub const fn new(config: PressureLoopConfig) -> Self {
+        Self {
+            config,
+            integral: 0.0,
+            previous_measured: None,
+        }
+    }
+
+    pub fn config(&self) -> PressureLoopConfig {
+        self.config
+    }
+
+    /// Change the gains without a bump: the integral is kept as a pressure so it carries over
+    pub fn set_config(&mut self, config: PressureLoopConfig) {
+        let max_trim = config.max_trim.get::<bar>().abs();
+
+        self.config = config;
+        self.integral = self.integral.clamp(-max_trim, max_trim);
+    }
+
+    /// Forget the loop history, used whenever the regulator is sent to its safe state
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.previous_measured = None;
+    }
+
+    /// Trimmed regulator command for `target`, given the `measured` driveline pressure and the time
+    /// elapsed since the previous update
+    /// A zero target is the safe state: it is passed through untrimmed and resets the loop
+    pub fn update(
+        &mut self,
+        target: Pressure,
+        measured: Pressure,
+        dt: Duration,
+    ) -> PressureLoopStatus {
+        let target_bar = target.get::<bar>();
+        let measured_bar = measured.get::<bar>();
+        let error_bar = target_bar - measured_bar;
+
+        if target_bar <= 0.0 {
+            self.reset();
+
+            return PressureLoopStatus {
+                target,
+                measured,
+                error: target - measured,
+                command: target,
+            };
+        }
+
+        let gains = self.config.gains;
+        let max_trim = self.config.max_trim.get::<bar>().abs();
+        let dt_s = dt.as_micros() as f32 / 1_000_000.0;
+
+        // Derivative on the measurement rather than the error, the target steps at every phase
+        // switch and would kick the output
+        let derivative = match self.previous_measured {
+            Some(previous) if dt_s > 0.0 => -(measured_bar - previous) / dt_s,
+            _ => 0.0,
+        };
+        self.previous_measured = Some(measured_bar);
+
+        let integral = self.integral + gains.ki * error_bar * dt_s;
+        let unclamped = gains.kp * error_bar + integral + gains.kd * derivative;
+        let trim = unclamped.clamp(-max_trim, max_trim);
+        let command = (target_bar + trim).clamp(
+            RegulatorSetpoint::REGULATOR_MIN_PRESSURE_BAR,
+            RegulatorSetpoint::REGULATOR_MAX_PRESSURE_BAR,
+        );
+
+        // Anti-windup: integrate only if the output is not pushed further into saturation
+        let saturated_high = unclamped > max_trim
+            || target_bar + unclamped > RegulatorSetpoint::REGULATOR_MAX_PRESSURE_BAR;
+        let saturated_low = unclamped < -max_trim
+            || target_bar + unclamped < RegulatorSetpoint::REGULATOR_MIN_PRESSURE_BAR;
+        if !(saturated_high && error_bar > 0.0 || saturated_low && error_bar < 0.0) {
+            self.integral = integral.clamp(-max_trim, max_trim);
+        }
+
+        PressureLoopStatus {
+            target,
+            measured,
+            error: target - measured,
+            command: Pressure::new::<bar>(command),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pressure_loop(kp: f32, ki: f32, kd: f32, max_trim_bar: f32) -> PressureLoop {
+        PressureLoop::new(PressureLoopConfig {
+            gains: PidGains { kp, ki, kd },
+            max_trim: Pressure::new::<bar>(max_trim_bar),
+        })
+    }
+
+    fn bar_of(pressure: Pressure) -> f32 {
+        pressure.get::<bar>()
+    }
+
+    #[test]
+    fn test_proportional_trim() {
+        let mut pressure_loop = pressure_loop(0.5, 0.0, 0.0, 0.5);
+
+        let status = pressure_loop.update(
+            Pressure::new::<bar>(1.0),
+            Pressure::new::<bar>(0.75),
+            PRESSURE_LOOP_PERIOD,
+        );
+        assert_eq!(bar_of(status.error), 0.25);
+        assert_eq!(bar_of(status.command), 1.125);
+
+        // The trim is bounded
+        let status = pressure_loop.update(
+            Pressure::new::<bar>(1.0),
+            Pressure::new::<bar>(0.0),
+            PRESSURE_LOOP_PERIOD,
+        );
+        assert_eq!(bar_of(status.command), 1.5);
+    }
+
+    #[test]
+    fn test_integral_removes_regulator_offset() {
+        let mut pressure_loop = pressure_loop(0.2, 10.0, 0.0, 0.5);
+        let target = Pressure::new::<bar>(1.0);
+
+        // The regulator delivers only 80% of its command, with a first order lag
+        let mut measured = 0.0;
+        for _ in 0..1000 {
+            let status =
+                pressure_loop.update(target, Pressure::new::<bar>(measured), PRESSURE_LOOP_PERIOD);
+            measured += (0.8 * bar_of(status.command) - measured) * 0.2;
+        }
+
+        assert!((measured - 1.0).abs() < 0.001, "measured {measured}bar");
+    }
+
+    #[test]
+    fn test_anti_windup() {
+        let mut pressure_loop = pressure_loop(0.0, 10.0, 0.0, 0.25);
+        let target = Pressure::new::<bar>(1.0);
+
+        // A disconnected driveline never reaches the target: the trim saturates
+        for _ in 0..1000 {
+            let status =
+                pressure_loop.update(target, Pressure::new::<bar>(0.0), PRESSURE_LOOP_PERIOD);
+            assert!(bar_of(status.command) <= 1.25);
+        }
+
+        // Overshooting unwinds the trim right away instead of after the accumulated error
+        let status = pressure_loop.update(target, Pressure::new::<bar>(2.0), PRESSURE_LOOP_PERIOD);
+        assert!(bar_of(status.command) < 1.25);
+    }
+
+    #[test]
+    fn test_command_stays_within_regulator_range() {
+        let mut pressure_loop = pressure_loop(10.0, 0.0, 0.0, 1.0);
+
+        let status = pressure_loop.update(
+            Pressure::new::<bar>(1.8),
+            Pressure::new::<bar>(1.0),
+            PRESSURE_LOOP_PERIOD,
+        );
+        assert_eq!(
+            bar_of(status.command),
+            RegulatorSetpoint::REGULATOR_MAX_PRESSURE_BAR
+        );
+
+        let status = pressure_loop.update(
+            Pressure::new::<bar>(0.2),
+            Pressure::new::<bar>(1.0),
+            PRESSURE_LOOP_PERIOD,
+        );
+        assert_eq!(bar_of(status.command), 0.0);
+    }
+
+    #[test]
+    fn test_derivative_on_measurement() {
+        let mut pressure_loop = pressure_loop(0.0, 0.0, 0.01, 0.5);
+        let measured = Pressure::new::<bar>(0.5);
+
+        // A step of the target does not kick the output
+        pressure_loop.update(Pressure::new::<bar>(0.5), measured, PRESSURE_LOOP_PERIOD);
+        let status =
+            pressure_loop.update(Pressure::new::<bar>(1.0), measured, PRESSURE_LOOP_PERIOD);
+        assert_eq!(bar_of(status.command), 1.0);
+
+        // A rising pressure is damped
+        let status = pressure_loop.update(
+            Pressure::new::<bar>(1.0),
+            Pressure::new::<bar>(0.6),
+            PRESSURE_LOOP_PERIOD,
+        );
+        assert!(bar_of(status.command) < 1.0);
+    }
+
+    #[test]
+    fn test_zero_target_is_not_trimmed() {
+        let mut pressure_loop = pressure_loop(1.0, 10.0, 0.0, 0.5);
+
+        for _ in 0..10 {
+            pressure_loop.update(
+                Pressure::new::<bar>(1.0),
+                Pressure::new::<bar>(0.5),
+                PRESSURE_LOOP_PERIOD,
+            );
+        }
+
+        // Venting the driveline ignores the accumulated trim
+        let status = pressure_loop.update(
+            Pressure::new::<bar>(0.0),
+            Pressure::new::<bar>(-0.1),
+            PRESSURE_LOOP_PERIOD,
+        );
+        assert_eq!(bar_of(status.command), 0.0);
+
+        // And starts the next pressurisation without history
+        let status = pressure_loop.update(
+            Pressure::new::<bar>(1.0),
+            Pressure::new::<bar>(1.0),
+            PRESSURE_LOOP_PERIOD,
+        );
+        assert_eq!(bar_of(status.command), 1.0);
+    }
+}
@@ -0,0 +1,362 @@
+//! Start-up sequence of the heart actuator
+//! Rather than going from the safe state straight into full pressure beating, the heart first beats
+//! slowly at a low pressure and then ramps up to the setpoint
+
+use embassy_time::{Duration, Instant};
+use love_letter::AppState;
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    f32::{Frequency, Pressure, Time},
+    frequency::hertz,
+    pressure::bar,
+    time::microsecond,
+};
+
+use crate::heart_control::{error::ControlError, state_machine::HeartParameters};
+
+/// Rate at which the heart parameters are updated while ramping up
+pub const PRIMING_UPDATE_PERIOD: Duration = Duration::from_millis(100);
+
+/// Start-up sequence configuration
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PrimingConfig {
+    /// Heart rate of the slow priming beats, never above the setpoint
+    pub heart_rate: Frequency,
+    /// Pressure of the priming beats, never above the setpoint
+    pub pressure: Pressure,
+    /// How long the heart beats slowly before ramping up
+    pub slow_beats: Time,
+    /// Duration of the ramp from the priming beats to the setpoint
+    pub ramp: Time,
+}
+
+/// Stage of the start-up sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum PrimingStage {
+    /// Slow beats at low pressure
+    SlowBeats,
+    /// Ramping up to the setpoint
+    Ramp,
+    /// Beating at the setpoint
+    Done,
+    /// A fault sent the heart to the safe state
+    Aborted,
+}
+
+/// Progress of the start-up sequence
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct PrimingStatus {
+    pub stage: PrimingStage,
+    /// Fraction of the whole sequence completed, between 0 and 1
+    pub progress: f32,
+}
+
+/// Start-up sequence started when the heart controller is enabled, or when the host restarts it
+#[derive(Debug, Clone)]
+pub struct PrimingSequence {
+    config: PrimingConfig,
+    started_at: Instant,
+    aborted: bool,
+    /// Stage and moment of the latest status reported to the host
+    reported: Option<(PrimingStage, Instant)>,
+}
+
+impl PrimingSequence {
+    pub fn new(config: PrimingConfig, now: Instant) -> Self {
+        Self {
+            config,
+            started_at: now,
+            aborted: false,
+            reported: None,
+        }
+    }
+
+    /// Stop the sequence for good, the heart controller moves to the safe state
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+
+    pub fn status(&self, now: Instant) -> PrimingStatus {
+        let (slow_beats, ramp) = self.durations();
+        let elapsed = now.saturating_duration_since(self.started_at);
+
+        let stage = if self.aborted {
+            PrimingStage::Aborted
+        } else if elapsed < slow_beats {
+            PrimingStage::SlowBeats
+        } else if elapsed < slow_beats + ramp {
+            PrimingStage::Ramp
+        } else {
+            PrimingStage::Done
+        };
+        let total_us = (slow_beats + ramp).as_micros();
+        let progress = if total_us == 0 {
+            1.0
+        } else {
+            (elapsed.as_micros() as f32 / total_us as f32).min(1.0)
+        };
+
+        PrimingStatus { stage, progress }
+    }
+
+    /// Status to report to the host at `now`, if one is due: every [`PRIMING_UPDATE_PERIOD`]
+    /// while the heart beats slowly or ramps up, and once for the stage the sequence ends in
+    pub fn report(&mut self, now: Instant) -> Option<PrimingStatus> {
+        let status = self.status(now);
+        let running = matches!(status.stage, PrimingStage::SlowBeats | PrimingStage::Ramp);
+
+        let due = match self.reported {
+            None => true,
+            Some((stage, at)) => {
+                stage != status.stage || (running && now >= at + PRIMING_UPDATE_PERIOD)
+            }
+        };
+        if due {
+            self.reported = Some((status.stage, now));
+        }
+
+        due.then_some(status)
+    }
+
+    /// Parameters to beat at instead of `target`
+    pub fn parameters(&self, target: &HeartParameters, now: Instant) -> HeartParameters {
+        let (slow_beats, ramp) = self.durations();
+        let elapsed = now.saturating_duration_since(self.started_at);
+
+        // Fraction of the ramp completed
+        let ramped = if elapsed < slow_beats {
+            0.0
+        } else if ramp.as_ticks() == 0 {
+            1.0
+        } else {
+            ((elapsed - slow_beats).as_micros() as f32 / ramp.as_micros() as f32).min(1.0)
+        };
+
+        let heart_rate = lerp(
+            self.config
+                .heart_rate
+                .get::<hertz>()
+                .min(target.heart_rate.get::<hertz>()),
+            target.heart_rate.get::<hertz>(),
+            ramped,
+        );
+        let pressure = lerp(
+            self.config
+                .pressure
+                .get::<bar>()
+                .min(target.pressure.get::<bar>()),
+            target.pressure.get::<bar>(),
+            ramped,
+        );
+
+        HeartParameters {
+            heart_rate: Frequency::new::<hertz>(heart_rate),
+            pressure: Pressure::new::<bar>(pressure),
+            ..*target
+        }
+    }
+
+    /// Next moment the parameters change, `None` once the sequence has ended
+    pub fn next_update(&self, now: Instant) -> Option<Instant> {
+        let (slow_beats, ramp) = self.durations();
+        let ramp_start = self.started_at + slow_beats;
+
+        if self.aborted || now >= ramp_start + ramp {
+            None
+        } else if now < ramp_start {
+            Some(ramp_start)
+        } else {
+            Some((now + PRIMING_UPDATE_PERIOD).min(ramp_start + ramp))
+        }
+    }
+
+    fn durations(&self) -> (Duration, Duration) {
+        let to_duration =
+            |time: Time| Duration::from_micros(time.get::<microsecond>().max(0.0) as u64);
+
+        (
+            to_duration(self.config.slow_beats),
+            to_duration(self.config.ramp),
+        )
+    }
+}
+
+/// Application state reported to the host, given the latest control fault and start-up sequence
+/// status. The heart is running from the start of its start-up sequence, the heart controller
+/// clears the status once the sequence ends by disabling the heart
+pub fn app_state(fault: Option<ControlError>, priming: Option<PrimingStatus>) -> AppState {
+    match (fault, priming) {
+        (Some(_), _) => AppState::Fault,
+        (None, Some(priming)) if priming.stage == PrimingStage::Aborted => AppState::Fault,
+        (None, Some(_)) => AppState::Running,
+        (None, None) => AppState::StandBy,
+    }
+}
+
+fn lerp(from: f32, to: f32, fraction: f32) -> f32 {
+    from + (to - from) * fraction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uom::si::{frequency::cycle_per_minute, time::second};
+
+    fn config() -> PrimingConfig {
+        PrimingConfig {
+            heart_rate: Frequency::new::<cycle_per_minute>(30.0),
+            pressure: Pressure::new::<bar>(0.25),
+            slow_beats: Time::new::<second>(4.0),
+            ramp: Time::new::<second>(2.0),
+        }
+    }
+
+    fn target(bpm: f32, pressure_bar: f32) -> HeartParameters {
+        HeartParameters {
+            heart_rate: Frequency::new::<cycle_per_minute>(bpm),
+            pressure: Pressure::new::<bar>(pressure_bar),
+            left_systole_ratio: 0.35,
+            right_systole_ratio: 0.35,
+            interventricular_delay: Time::new::<second>(0.0),
+        }
+    }
+
+    fn assert_bpm_bar(parameters: &HeartParameters, bpm: f32, pressure_bar: f32) {
+        let actual = (
+            parameters.heart_rate.get::<cycle_per_minute>(),
+            parameters.pressure.get::<bar>(),
+        );
+        assert!(
+            (actual.0 - bpm).abs() < 0.001 && (actual.1 - pressure_bar).abs() < 0.001,
+            "expected ({bpm}bpm, {pressure_bar}bar), got {actual:?}"
+        );
+    }
+
+    #[test]
+    fn test_slow_beats_then_ramp() {
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+        let priming = PrimingSequence::new(config(), start);
+        let target = target(90.0, 1.25);
+
+        assert_bpm_bar(&priming.parameters(&target, start), 30.0, 0.25);
+        assert_bpm_bar(&priming.parameters(&target, at(3999)), 30.0, 0.25);
+        assert_eq!(priming.status(at(2000)).stage, PrimingStage::SlowBeats);
+        assert_eq!(priming.next_update(start), Some(at(4000)));
+
+        // Halfway through the ramp
+        assert_bpm_bar(&priming.parameters(&target, at(5000)), 60.0, 0.75);
+        assert_eq!(
+            priming.status(at(5000)),
+            PrimingStatus {
+                stage: PrimingStage::Ramp,
+                progress: 5.0 / 6.0,
+            }
+        );
+        assert_eq!(priming.next_update(at(5000)), Some(at(5100)));
+        assert_eq!(priming.next_update(at(5950)), Some(at(6000)));
+
+        // Done, the setpoint passes through
+        assert_eq!(priming.parameters(&target, at(6000)), target);
+        assert_eq!(priming.status(at(6000)).stage, PrimingStage::Done);
+        assert_eq!(priming.next_update(at(6000)), None);
+    }
+
+    #[test]
+    fn test_priming_never_exceeds_setpoint() {
+        let start = Instant::from_secs(1);
+        let priming = PrimingSequence::new(config(), start);
+        let target = target(20.0, 0.125);
+
+        assert_bpm_bar(&priming.parameters(&target, start), 20.0, 0.125);
+    }
+
+    #[test]
+    fn test_abort() {
+        let start = Instant::from_secs(1);
+        let mut priming = PrimingSequence::new(config(), start);
+
+        priming.abort();
+        assert_eq!(priming.status(start).stage, PrimingStage::Aborted);
+        assert_eq!(priming.next_update(start), None);
+    }
+
+    #[test]
+    fn test_report_progress() {
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut priming = PrimingSequence::new(config(), start);
+
+        assert_eq!(
+            priming.report(start),
+            Some(PrimingStatus {
+                stage: PrimingStage::SlowBeats,
+                progress: 0.0,
+            })
+        );
+        // At most every update period while running
+        assert_eq!(priming.report(at(50)), None);
+        assert_eq!(
+            priming.report(at(100)).map(|s| s.stage),
+            Some(PrimingStage::SlowBeats)
+        );
+        // Every stage change right away
+        assert_eq!(
+            priming.report(at(4000)).map(|s| s.stage),
+            Some(PrimingStage::Ramp)
+        );
+
+        // Done and aborted are reported once
+        assert_eq!(
+            priming.report(at(6000)).map(|s| s.stage),
+            Some(PrimingStage::Done)
+        );
+        assert_eq!(priming.report(at(7000)), None);
+        priming.abort();
+        assert_eq!(
+            priming.report(at(7000)).map(|s| s.stage),
+            Some(PrimingStage::Aborted)
+        );
+        assert_eq!(priming.report(at(8000)), None);
+    }
+
+    #[test]
+    fn test_disable_returns_to_standby() {
+        let start = Instant::from_secs(1);
+        let priming = PrimingSequence::new(config(), start);
+
+        // Running from the start of the sequence, also once it is done
+        assert!(matches!(
+            app_state(None, Some(priming.status(start))),
+            AppState::Running
+        ));
+        assert!(matches!(
+            app_state(None, Some(priming.status(start + Duration::from_secs(10)))),
+            AppState::Running
+        ));
+
+        // Disabling the heart ends the sequence and clears its status
+        assert!(matches!(app_state(None, None), AppState::StandBy));
+    }
+
+    #[test]
+    fn test_acknowledged_abort_returns_to_standby() {
+        let start = Instant::from_secs(1);
+        let mut priming = PrimingSequence::new(config(), start);
+
+        // A fault aborts the sequence
+        priming.abort();
+        assert!(matches!(
+            app_state(Some(ControlError::Regulator), Some(priming.status(start))),
+            AppState::Fault
+        ));
+        // The aborted sequence keeps the fault until it is acknowledged
+        assert!(matches!(
+            app_state(None, Some(priming.status(start))),
+            AppState::Fault
+        ));
+
+        // Disabling the heart acknowledges the fault and clears both
+        assert!(matches!(app_state(None, None), AppState::StandBy));
+    }
+}
@@ -0,0 +1,282 @@
+//! Actuation profile of the cardiac cycle
+//! Splits systole and diastole into a sequence of profile phases, each with its own valve state and
+//! regulator pressure. This allows actuation sequences like pressurise, hold, vent to atmosphere
+//! and vacuum without firmware changes.
+
+use embassy_time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use crate::heart_control::phase::{CardiacPhase, ValveState};
+
+/// Maximum number of profile phases within systole or diastole
+pub const MAX_PROFILE_PHASES: usize = 4;
+
+/// A single step of the actuation sequence
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ProfilePhase {
+    /// Share of the cardiac phase this step lasts, relative to the other steps of that phase
+    pub fraction: f32,
+    /// State of the ventricle valve
+    pub valve: ValveState,
+    /// Regulator pressure as a fraction of the heart pressure setpoint, 0 vents the driveline to
+    /// atmosphere
+    pub pressure: f32,
+}
+
+impl ProfilePhase {
+    /// Plain cardiac phase: the whole phase at the setpoint pressure
+    pub fn plain(phase: CardiacPhase) -> Self {
+        let valve = match phase {
+            CardiacPhase::Systole => ValveState::Pressure,
+            CardiacPhase::Diastole => ValveState::Vacuum,
+        };
+
+        Self {
+            fraction: 1.0,
+            valve,
+            pressure: 1.0,
+        }
+    }
+}
+
+/// Sequence of profile phases making up the cardiac cycle
+/// Systole and diastole keep the timing set by the heart rate and systole ratio, their profile
+/// phases divide them. An empty sequence behaves as a single plain phase.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CycleProfile {
+    pub systole: heapless::Vec<ProfilePhase, MAX_PROFILE_PHASES>,
+    pub diastole: heapless::Vec<ProfilePhase, MAX_PROFILE_PHASES>,
+}
+
+impl Default for CycleProfile {
+    /// Two phase cycle: pressure during systole, vacuum during diastole
+    fn default() -> Self {
+        let plain = |phase| heapless::Vec::from_iter([ProfilePhase::plain(phase)]);
+
+        Self {
+            systole: plain(CardiacPhase::Systole),
+            diastole: plain(CardiacPhase::Diastole),
+        }
+    }
+}
+
+/// Profile phase a ventricle is in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProfileStep {
+    /// Position in the cycle, systole steps come first
+    pub index: u8,
+    pub phase: ProfilePhase,
+    /// Moment the step ends
+    pub end: Instant,
+}
+
+impl CycleProfile {
+    /// First profile phase of a cardiac phase, used while the heart does not beat
+    pub fn first(&self, phase: CardiacPhase) -> (u8, ProfilePhase) {
+        let first = self.phases(phase).first().copied();
+
+        (
+            self.offset(phase),
+            first.unwrap_or(ProfilePhase::plain(phase)),
+        )
+    }
+
+    /// Profile phase at `at` of a cardiac phase lasting from `start` until `end`
+    /// Steps without duration are skipped, the last step always lasts until `end`
+    pub fn locate(
+        &self,
+        phase: CardiacPhase,
+        start: Instant,
+        end: Instant,
+        at: Instant,
+    ) -> ProfileStep {
+        let steps = self.phases(phase);
+        let offset = self.offset(phase);
+        let total: f32 = steps.iter().map(|step| step.fraction.max(0.0)).sum();
+
+        if steps.is_empty() || total <= 0.0 {
+            return ProfileStep {
+                index: offset,
+                phase: steps.first().copied().unwrap_or(ProfilePhase::plain(phase)),
+                end,
+            };
+        }
+
+        let length_us = end.saturating_duration_since(start).as_micros() as f32;
+        let mut elapsed = 0.0;
+        for (index, step) in steps.iter().enumerate() {
+            elapsed += step.fraction.max(0.0);
+
+            let step_end = if index + 1 == steps.len() {
+                end
+            } else {
+                (start + Duration::from_micros((length_us * elapsed / total) as u64)).min(end)
+            };
+
+            if at < step_end {
+                return ProfileStep {
+                    index: offset + index as u8,
+                    phase: *step,
+                    end: step_end,
+                };
+            }
+        }
+
+        // Only reached once the cardiac phase is over
+        ProfileStep {
+            index: offset + steps.len() as u8 - 1,
+            phase: steps[steps.len() - 1],
+            end,
+        }
+    }
+
+    fn phases(&self, phase: CardiacPhase) -> &[ProfilePhase] {
+        match phase {
+            CardiacPhase::Systole => &self.systole,
+            CardiacPhase::Diastole => &self.diastole,
+        }
+    }
+
+    /// Index in the cycle of the first step of a cardiac phase
+    fn offset(&self, phase: CardiacPhase) -> u8 {
+        match phase {
+            CardiacPhase::Systole => 0,
+            CardiacPhase::Diastole => self.systole.len().max(1) as u8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uom::si::{f32::Pressure, pressure::bar};
+
+    use crate::heart_control::state_machine::{HeartController, tests::parameters};
+
+    fn step(fraction: f32, valve: ValveState, pressure: f32) -> ProfilePhase {
+        ProfilePhase {
+            fraction,
+            valve,
+            pressure,
+        }
+    }
+
+    #[test]
+    fn test_default_profile_is_two_phase() {
+        let profile = CycleProfile::default();
+        let start = Instant::from_secs(1);
+        let end = Instant::from_secs(2);
+
+        let systole = profile.locate(CardiacPhase::Systole, start, end, start);
+        assert_eq!(systole.index, 0);
+        assert_eq!(systole.phase.valve, ValveState::Pressure);
+        assert_eq!(systole.phase.pressure, 1.0);
+        assert_eq!(systole.end, end);
+
+        let diastole = profile.locate(CardiacPhase::Diastole, start, end, start);
+        assert_eq!(diastole.index, 1);
+        assert_eq!(diastole.phase.valve, ValveState::Vacuum);
+    }
+
+    #[test]
+    fn test_steps_divide_the_cardiac_phase() {
+        let profile = CycleProfile {
+            systole: heapless::Vec::from_slice(&[
+                step(3.0, ValveState::Pressure, 1.0),
+                step(1.0, ValveState::Pressure, 0.5),
+            ])
+            .unwrap(),
+            diastole: heapless::Vec::from_slice(&[
+                step(0.25, ValveState::Pressure, 0.0),
+                step(0.0, ValveState::Pressure, 0.5),
+                step(0.75, ValveState::Vacuum, 1.0),
+            ])
+            .unwrap(),
+        };
+        let start = Instant::from_secs(1);
+        let end = start + Duration::from_millis(400);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let pressurise = profile.locate(CardiacPhase::Systole, start, end, at(0));
+        assert_eq!((pressurise.index, pressurise.end), (0, at(300)));
+        let hold = profile.locate(CardiacPhase::Systole, start, end, at(300));
+        assert_eq!((hold.index, hold.phase.pressure, hold.end), (1, 0.5, end));
+
+        // Diastole follows the two systole steps, the empty step is skipped
+        let vent = profile.locate(CardiacPhase::Diastole, start, end, at(0));
+        assert_eq!(
+            (vent.index, vent.phase.pressure, vent.end),
+            (2, 0.0, at(100))
+        );
+        let vacuum = profile.locate(CardiacPhase::Diastole, start, end, at(100));
+        assert_eq!((vacuum.index, vacuum.phase.valve), (4, ValveState::Vacuum));
+        assert_eq!(vacuum.end, end);
+    }
+
+    #[test]
+    fn test_empty_profile_is_plain() {
+        let profile = CycleProfile {
+            systole: heapless::Vec::new(),
+            diastole: heapless::Vec::new(),
+        };
+        let start = Instant::from_secs(1);
+
+        let diastole = profile.locate(CardiacPhase::Diastole, start, start, start);
+        assert_eq!(diastole.index, 1);
+        assert_eq!(diastole.phase, ProfilePhase::plain(CardiacPhase::Diastole));
+        assert_eq!(
+            profile.first(CardiacPhase::Systole),
+            (0, ProfilePhase::plain(CardiacPhase::Systole))
+        );
+    }
+
+    #[test]
+    fn test_cycle_profile_steps() {
+        let mut controller = HeartController::new();
+        // Pressurise, hold, vent to atmosphere and vacuum
+        controller.set_profile(CycleProfile {
+            systole: heapless::Vec::from_slice(&[
+                step(0.5, ValveState::Pressure, 1.0),
+                step(0.5, ValveState::Pressure, 0.5),
+            ])
+            .unwrap(),
+            diastole: heapless::Vec::from_slice(&[
+                step(0.25, ValveState::Pressure, 0.0),
+                step(0.75, ValveState::Vacuum, 1.0),
+            ])
+            .unwrap(),
+        });
+        let params = parameters(60.0, 0.25, 2.0);
+        let start = Instant::from_secs(1);
+        let at = |us| start + Duration::from_micros(us);
+
+        let expected = [
+            (0, ValveState::Pressure, 2.0, 125_000),
+            (125_000, ValveState::Pressure, 1.0, 250_000),
+            (250_000, ValveState::Pressure, 0.0, 437_500),
+            (437_500, ValveState::Vacuum, 2.0, 1_000_000),
+        ];
+        for (index, (time, valve, pressure_bar, deadline)) in expected.into_iter().enumerate() {
+            let step = controller.step(at(time), Some(&params));
+            assert_eq!(step.commands.left_valve, valve);
+            assert_eq!(step.commands.right_valve, valve);
+            assert_eq!(
+                step.commands.regulator_pressure,
+                Pressure::new::<bar>(pressure_bar)
+            );
+            assert_eq!(step.deadline, Some(at(deadline)));
+            assert_eq!(step.events.len(), 2);
+            assert!(
+                step.events
+                    .iter()
+                    .all(|event| usize::from(event.profile_phase) == index)
+            );
+        }
+
+        // The profile restarts with the next beat
+        let step = controller.step(at(1_000_000), Some(&params));
+        assert_eq!(step.events[0].profile_phase, 0);
+        assert_eq!(step.deadline, Some(at(1_125_000)));
+        assert_eq!(controller.timing().switches, 4);
+    }
+}
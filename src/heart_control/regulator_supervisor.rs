@@ -0,0 +1,192 @@
+//! Pressure regulator tracking supervision
+//! Compares the pressure asked of the regulator with its feedback, a regulator that does not follow
+//! its command for too long is disconnected, out of supply air or broken
+
+use embassy_time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    f32::{Pressure, Time},
+    pressure::bar,
+    time::{microsecond, millisecond},
+};
+
+use crate::heart_control::error::ControlError;
+
+/// Regulator supervision configuration
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RegulatorSupervisorConfig {
+    /// Largest deviation of the feedback from the commanded pressure that is not a fault
+    pub tolerance: Pressure,
+    /// Time the regulator gets to follow a change of the commanded pressure
+    pub settling_time: Time,
+    /// How long the deviation has to last, after settling, to raise a fault
+    pub fault_time: Time,
+}
+
+impl Default for RegulatorSupervisorConfig {
+    fn default() -> Self {
+        Self {
+            tolerance: Pressure::new::<bar>(0.2),
+            settling_time: Time::new::<millisecond>(300.0),
+            fault_time: Time::new::<millisecond>(500.0),
+        }
+    }
+}
+
+/// Raises [`ControlError::Regulator`] on a sustained deviation of the regulator feedback
+/// The settling window restarts whenever the commanded pressure moves further than the tolerance,
+/// so the supervisor does not trip on the regulator lagging a phase switch or a shaped waveform
+#[derive(Debug, Clone)]
+pub struct RegulatorSupervisor {
+    config: RegulatorSupervisorConfig,
+    /// Commanded pressure the current settling window started at
+    reference: Option<Pressure>,
+    /// End of the current settling window
+    settled_at: Instant,
+    /// Start of the ongoing deviation
+    deviating_since: Option<Instant>,
+}
+
+impl RegulatorSupervisor {
+    pub const fn new(config: RegulatorSupervisorConfig) -> Self {
+        Self {
+            config,
+            reference: None,
+            settled_at: Instant::from_ticks(0),
+            deviating_since: None,
+        }
+    }
+
+    pub fn config(&self) -> RegulatorSupervisorConfig {
+        self.config
+    }
+
+    /// Change the limits, the ongoing settling window and deviation are kept
+    pub fn set_config(&mut self, config: RegulatorSupervisorConfig) {
+        self.config = config;
+    }
+
+    /// Check the `measured` regulator feedback against the `commanded` pressure at `now`
+    pub fn update(
+        &mut self,
+        commanded: Pressure,
+        measured: Pressure,
+        now: Instant,
+    ) -> Result<(), ControlError> {
+        let tolerance = self.config.tolerance.get::<bar>().abs();
+
+        let moved = self.reference.is_none_or(|reference| {
+            (commanded.get::<bar>() - reference.get::<bar>()).abs() > tolerance
+        });
+        if moved {
+            self.reference = Some(commanded);
+            self.settled_at = now + to_duration(self.config.settling_time);
+            self.deviating_since = None;
+        }
+
+        if now < self.settled_at
+            || (commanded.get::<bar>() - measured.get::<bar>()).abs() <= tolerance
+        {
+            self.deviating_since = None;
+            return Ok(());
+        }
+
+        let deviating_since = *self.deviating_since.get_or_insert(now);
+        if now - deviating_since >= to_duration(self.config.fault_time) {
+            Err(ControlError::Regulator)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn to_duration(time: Time) -> Duration {
+    Duration::from_micros(time.get::<microsecond>().max(0.0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pressure(pressure_bar: f32) -> Pressure {
+        Pressure::new::<bar>(pressure_bar)
+    }
+
+    #[test]
+    fn test_tracking_regulator() {
+        let mut supervisor = RegulatorSupervisor::new(RegulatorSupervisorConfig::default());
+        let start = Instant::from_secs(1);
+
+        for ms in (0..2000).step_by(10) {
+            let now = start + Duration::from_millis(ms);
+            assert_eq!(supervisor.update(pressure(1.0), pressure(0.9), now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_settling_window() {
+        let mut supervisor = RegulatorSupervisor::new(RegulatorSupervisorConfig::default());
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        supervisor
+            .update(pressure(0.0), pressure(0.0), at(0))
+            .unwrap();
+
+        // A step of the command restarts the window, the regulator still has to catch up
+        for ms in (1000..1300).step_by(10) {
+            assert_eq!(
+                supervisor.update(pressure(1.0), pressure(0.0), at(ms)),
+                Ok(())
+            );
+        }
+        // Small changes, like a closed-loop trim, do not
+        assert_eq!(
+            supervisor.update(pressure(1.1), pressure(0.0), at(1300)),
+            Ok(())
+        );
+        assert_eq!(
+            supervisor.update(pressure(1.1), pressure(0.0), at(1799)),
+            Ok(())
+        );
+        assert_eq!(
+            supervisor.update(pressure(1.1), pressure(0.0), at(1800)),
+            Err(ControlError::Regulator)
+        );
+    }
+
+    #[test]
+    fn test_deviation_must_be_sustained() {
+        let mut supervisor = RegulatorSupervisor::new(RegulatorSupervisorConfig::default());
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        supervisor
+            .update(pressure(1.0), pressure(1.0), at(0))
+            .unwrap();
+
+        // A dip shorter than the fault time
+        assert_eq!(
+            supervisor.update(pressure(1.0), pressure(0.5), at(400)),
+            Ok(())
+        );
+        assert_eq!(
+            supervisor.update(pressure(1.0), pressure(0.5), at(800)),
+            Ok(())
+        );
+        assert_eq!(
+            supervisor.update(pressure(1.0), pressure(1.0), at(900)),
+            Ok(())
+        );
+
+        // Restarts the fault timer
+        assert_eq!(
+            supervisor.update(pressure(1.0), pressure(1.5), at(1000)),
+            Ok(())
+        );
+        assert_eq!(
+            supervisor.update(pressure(1.0), pressure(1.5), at(1500)),
+            Err(ControlError::Regulator)
+        );
+    }
+}
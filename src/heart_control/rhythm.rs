@@ -0,0 +1,334 @@
+//! Heart rhythm simulation
+//! Draws the RR interval of every beat as a factor of the nominal cardiac period, from a seeded
+//! pseudo random generator so experiments are reproducible
+
+use serde::{Deserialize, Serialize};
+
+/// Rhythm the heart controller beats in
+#[derive(Debug, Clone, Copy, Default, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub enum RhythmMode {
+    /// Every beat lasts exactly the nominal period
+    #[default]
+    Regular,
+    /// Sinus rhythm with heart rate variability
+    Sinus {
+        /// Standard deviation of the RR interval as a fraction of the nominal period
+        variability: f32,
+    },
+    /// Regular rhythm with premature beats, each followed by a compensatory pause
+    PrematureBeats {
+        /// Chance of any beat being premature, between 0 and 1
+        probability: f32,
+        /// RR interval of a premature beat as a fraction of the nominal period
+        prematurity: f32,
+    },
+    /// Irregularly irregular RR intervals, like atrial fibrillation
+    AtrialFibrillation {
+        /// Maximum deviation of the RR interval as a fraction of the nominal period
+        irregularity: f32,
+    },
+    /// Regular rhythm interrupted by pauses or bradycardia episodes
+    Pauses {
+        /// Chance of an episode starting at any beat, between 0 and 1
+        probability: f32,
+        /// RR interval during the episode as a multiple of the nominal period
+        factor: f32,
+        /// Number of beats an episode lasts, 1 for a single pause
+        beats: u8,
+    },
+}
+
+/// Rhythm mode and the seed that makes it reproducible
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct RhythmConfig {
+    pub mode: RhythmMode,
+    pub seed: u32,
+}
+
+impl RhythmConfig {
+    /// Regular rhythm, the seed only matters once another mode is selected
+    pub const DEFAULT: Self = Self {
+        mode: RhythmMode::Regular,
+        seed: 0x5EED_BEA7,
+    };
+}
+
+impl Default for RhythmConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Shortest RR interval we allow any rhythm to produce, as a fraction of the nominal period
+const MIN_RR_FACTOR: f32 = 0.2;
+/// Longest RR interval we allow any rhythm to produce, as a multiple of the nominal period
+const MAX_RR_FACTOR: f32 = 5.0;
+
+/// Generates the RR interval of consecutive beats for a [`RhythmConfig`]
+#[derive(Debug, Clone)]
+pub struct RhythmGenerator {
+    config: RhythmConfig,
+    rng: XorShift32,
+    /// RR factor of the compensatory pause following a premature beat
+    compensation: Option<f32>,
+    /// Beats left in the current pause episode
+    episode_remaining: u8,
+}
+
+impl Default for RhythmGenerator {
+    fn default() -> Self {
+        Self::new(RhythmConfig::default())
+    }
+}
+
+impl RhythmGenerator {
+    pub const fn new(config: RhythmConfig) -> Self {
+        Self {
+            config,
+            rng: XorShift32::new(config.seed),
+            compensation: None,
+            episode_remaining: 0,
+        }
+    }
+
+    pub fn config(&self) -> RhythmConfig {
+        self.config
+    }
+
+    /// Start over from the seed, the same sequence of beats follows
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config);
+    }
+
+    /// RR interval of the next beat as a factor of the nominal cardiac period
+    pub fn next_rr_factor(&mut self) -> f32 {
+        let factor = match self.config.mode {
+            RhythmMode::Regular => 1.0,
+            RhythmMode::Sinus { variability } => 1.0 + variability * self.rng.next_normal(),
+            RhythmMode::PrematureBeats {
+                probability,
+                prematurity,
+            } => match self.compensation.take() {
+                Some(compensation) => compensation,
+                None if self.rng.next_f32() < probability => {
+                    // The pause after a premature beat restores the underlying rhythm
+                    self.compensation = Some(2.0 - prematurity);
+                    prematurity
+                }
+                None => 1.0,
+            },
+            RhythmMode::AtrialFibrillation { irregularity } => {
+                1.0 + irregularity * (2.0 * self.rng.next_f32() - 1.0)
+            }
+            RhythmMode::Pauses {
+                probability,
+                factor,
+                beats,
+            } => {
+                if self.episode_remaining == 0 && self.rng.next_f32() < probability {
+                    self.episode_remaining = beats;
+                }
+
+                if self.episode_remaining > 0 {
+                    self.episode_remaining -= 1;
+                    factor
+                } else {
+                    1.0
+                }
+            }
+        };
+
+        factor.clamp(MIN_RR_FACTOR, MAX_RR_FACTOR)
+    }
+}
+
+/// Marsaglia's xorshift32, small and good enough to make beats irregular
+#[derive(Debug, Clone)]
+struct XorShift32 {
+    state: u32,
+}
+
+impl XorShift32 {
+    const fn new(seed: u32) -> Self {
+        // An all zero state would only ever produce zeros
+        let state = if seed == 0 { 0x9E37_79B9 } else { seed };
+
+        Self { state }
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+
+        x
+    }
+
+    /// Uniformly distributed in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1u32 << 24) as f32
+    }
+
+    /// Approximately standard normal distributed, using the sum of 4 uniform samples
+    fn next_normal(&mut self) -> f32 {
+        /// sqrt(3), scales the sum of 4 uniform samples to unit variance
+        const SQRT_3: f32 = 1.732_050_8;
+
+        let sum: f32 = (0..4).map(|_| self.next_f32()).sum();
+
+        (sum - 2.0) * SQRT_3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embassy_time::{Duration, Instant};
+
+    use crate::heart_control::state_machine::{HeartController, tests::parameters};
+
+    fn generator(mode: RhythmMode) -> RhythmGenerator {
+        RhythmGenerator::new(RhythmConfig { mode, seed: 42 })
+    }
+
+    #[test]
+    fn test_regular() {
+        let mut rhythm = generator(RhythmMode::Regular);
+
+        assert!((0..100).all(|_| rhythm.next_rr_factor() == 1.0));
+    }
+
+    #[test]
+    fn test_seeded_rhythm_is_reproducible() {
+        let mode = RhythmMode::AtrialFibrillation { irregularity: 0.3 };
+        let mut first = generator(mode);
+        let mut second = generator(mode);
+
+        let sequence: [f32; 16] = core::array::from_fn(|_| first.next_rr_factor());
+        assert!(sequence.iter().all(|rr| *rr == second.next_rr_factor()));
+
+        // Resetting replays the same beats
+        first.reset();
+        assert!(sequence.iter().all(|rr| *rr == first.next_rr_factor()));
+
+        // Another seed yields other beats
+        let mut other = RhythmGenerator::new(RhythmConfig { mode, seed: 7 });
+        assert!(sequence.iter().any(|rr| *rr != other.next_rr_factor()));
+    }
+
+    #[test]
+    fn test_sinus_variability() {
+        let mut rhythm = generator(RhythmMode::Sinus { variability: 0.05 });
+
+        let factors: [f32; 1000] = core::array::from_fn(|_| rhythm.next_rr_factor());
+        let mean = factors.iter().sum::<f32>() / factors.len() as f32;
+        let variance =
+            factors.iter().map(|f| (f - mean) * (f - mean)).sum::<f32>() / factors.len() as f32;
+
+        assert!((mean - 1.0).abs() < 0.01, "mean {mean}");
+        assert!(
+            (variance - 0.05 * 0.05).abs() < 0.0005,
+            "variance {variance}"
+        );
+    }
+
+    #[test]
+    fn test_premature_beats_compensate() {
+        let mut rhythm = generator(RhythmMode::PrematureBeats {
+            probability: 0.2,
+            prematurity: 0.6,
+        });
+
+        let mut premature = 0;
+        for _ in 0..1000 {
+            let factor = rhythm.next_rr_factor();
+            if factor != 1.0 {
+                // A premature beat and its pause together last two regular beats
+                assert_eq!(factor, 0.6);
+                assert_eq!(rhythm.next_rr_factor(), 1.4);
+                premature += 1;
+            }
+        }
+
+        assert!(
+            premature > 100 && premature < 250,
+            "{premature} premature beats"
+        );
+    }
+
+    #[test]
+    fn test_atrial_fibrillation_range() {
+        let mut rhythm = generator(RhythmMode::AtrialFibrillation { irregularity: 0.3 });
+
+        for _ in 0..1000 {
+            let factor = rhythm.next_rr_factor();
+            assert!((0.7..=1.3).contains(&factor), "factor {factor}");
+        }
+    }
+
+    #[test]
+    fn test_pause_episodes() {
+        let mut rhythm = generator(RhythmMode::Pauses {
+            probability: 0.1,
+            factor: 2.0,
+            beats: 3,
+        });
+
+        let factors: [f32; 1000] = core::array::from_fn(|_| rhythm.next_rr_factor());
+
+        // Every episode lasts at least 3 beats
+        let mut run = 0;
+        for factor in factors {
+            if factor == 2.0 {
+                run += 1;
+            } else {
+                assert!(run == 0 || run >= 3, "episode of {run} beats");
+                run = 0;
+            }
+        }
+        assert!(factors.contains(&2.0));
+    }
+
+    #[test]
+    fn test_premature_beat_and_compensatory_pause() {
+        let mut controller = HeartController::new();
+        // Every other beat is premature, lasting half a period
+        controller.set_rhythm(RhythmConfig {
+            mode: RhythmMode::PrematureBeats {
+                probability: 1.0,
+                prematurity: 0.5,
+            },
+            seed: 1,
+        });
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // Premature beat: systole keeps its length, diastole is cut short
+        controller.step(start, Some(&params));
+        let step = controller.step(at(250), Some(&params));
+        assert_eq!(step.deadline, Some(at(500)));
+
+        // Compensatory pause: one and a half periods
+        let step = controller.step(at(500), Some(&params));
+        assert_eq!(controller.beat_epoch(), Some(at(500)));
+        assert_eq!(
+            controller.timing().last_rr_interval,
+            Duration::from_millis(500)
+        );
+        assert_eq!(step.deadline, Some(at(750)));
+        let step = controller.step(at(750), Some(&params));
+        assert_eq!(step.deadline, Some(at(2000)));
+
+        // The underlying rhythm is restored
+        controller.step(at(2000), Some(&params));
+        assert_eq!(controller.beat_epoch(), Some(at(2000)));
+        assert_eq!(
+            controller.timing().last_rr_interval,
+            Duration::from_millis(1500)
+        );
+        assert_eq!(controller.timing().beats, 3);
+    }
+}
@@ -0,0 +1,393 @@
+//! Phase schedule of each ventricle within a beat
+//! Places the systole of the lagging ventricle the interventricular delay after the leading one,
+//! and commands every valve switch the valve latency early so the pneumatic phase follows the
+//! schedule
+
+use embassy_time::{Duration, Instant};
+use uom::si::{f32::Time, time::microsecond};
+
+use crate::heart_control::{
+    config::ValveLatency, phase::CardiacPhase, state_machine::HeartParameters,
+};
+
+/// Valve switching delays of a single ventricle
+#[derive(Debug, Clone, Copy)]
+pub struct ValveLead {
+    /// Delay until the driveline pressurises after commanding
+    /// [`ValveState::Pressure`](crate::heart_control::phase::ValveState::Pressure)
+    pub pressure: Duration,
+    /// Delay until the driveline vents after commanding
+    /// [`ValveState::Vacuum`](crate::heart_control::phase::ValveState::Vacuum)
+    pub vacuum: Duration,
+}
+
+impl ValveLead {
+    pub const NONE: Self = Self {
+        pressure: Duration::from_ticks(0),
+        vacuum: Duration::from_ticks(0),
+    };
+}
+
+impl From<&ValveLatency> for ValveLead {
+    fn from(latency: &ValveLatency) -> Self {
+        let to_duration =
+            |time: Time| Duration::from_micros(time.get::<microsecond>().max(0.0) as u64);
+
+        Self {
+            pressure: to_duration(latency.pressure),
+            vacuum: to_duration(latency.vacuum),
+        }
+    }
+}
+
+/// Systole of a single ventricle, relative to the beat epoch
+#[derive(Debug, Clone, Copy)]
+pub struct SystoleWindow {
+    pub offset: Duration,
+    pub duration: Duration,
+}
+
+/// Systole of the left and right ventricle within a beat of length `beat`
+/// A short beat cuts the systoles short rather than overlapping the next beat
+pub fn get_systole_windows(
+    period_us: u64,
+    beat: Duration,
+    parameters: &HeartParameters,
+) -> (SystoleWindow, SystoleWindow) {
+    let (left_offset, right_offset) = get_ventricle_offsets(period_us, parameters);
+
+    let window = |offset: Duration, systole_ratio: f32| {
+        let offset = offset.min(beat);
+        let duration = CardiacPhase::Systole
+            .get_phase_end(period_us, systole_ratio)
+            .min(beat - offset);
+
+        SystoleWindow { offset, duration }
+    };
+
+    (
+        window(left_offset, parameters.left_systole_ratio),
+        window(right_offset, parameters.right_systole_ratio),
+    )
+}
+
+/// Offsets of the left and right ventricle systole from the beat epoch
+/// The lagging ventricle has to finish its systole within the beat, its delay is clamped to fit
+fn get_ventricle_offsets(period_us: u64, parameters: &HeartParameters) -> (Duration, Duration) {
+    let delay_us = parameters.interventricular_delay.get::<microsecond>();
+
+    let lagging_systole_ratio = if delay_us >= 0.0 {
+        parameters.right_systole_ratio
+    } else {
+        parameters.left_systole_ratio
+    };
+    let max_offset_us = period_us as f32 * (1.0 - lagging_systole_ratio.clamp(0.0, 1.0));
+    let offset = Duration::from_micros(delay_us.abs().min(max_offset_us) as u64);
+
+    if delay_us >= 0.0 {
+        (Duration::from_ticks(0), offset)
+    } else {
+        (offset, Duration::from_ticks(0))
+    }
+}
+
+/// Phase commanded to the valve of a single ventricle at `at`, and the moment it switches to its
+/// next phase
+/// Each switch is commanded the valve latency early, so the pneumatic phase follows the schedule.
+/// This may pull the start of the next systole into the current beat.
+pub fn get_ventricle_phase(
+    epoch: Instant,
+    next_epoch: Instant,
+    systole: SystoleWindow,
+    latency: ValveLead,
+    at: Instant,
+) -> (CardiacPhase, Instant) {
+    // While waiting for an external trigger the next beat starts at `Instant::MAX`
+    let command_start = |epoch: Instant| match epoch.checked_add(systole.offset) {
+        Some(start) => start.checked_sub(latency.pressure).unwrap_or(Instant::MIN),
+        None => Instant::MAX,
+    };
+
+    let systole_start = command_start(epoch);
+    let systole_end = (epoch + systole.offset + systole.duration)
+        .checked_sub(latency.vacuum)
+        .unwrap_or(Instant::MIN)
+        .max(systole_start);
+    let next_systole_start = command_start(next_epoch);
+
+    if at < systole_start {
+        // Lagging ventricle still relaxing from the previous beat
+        (CardiacPhase::Diastole, systole_start)
+    } else if at < systole_end {
+        (CardiacPhase::Systole, systole_end)
+    } else if at < next_systole_start {
+        (CardiacPhase::Diastole, next_systole_start)
+    } else if systole_end > systole_start {
+        // Next systole commanded ahead of the next beat
+        (
+            CardiacPhase::Systole,
+            next_systole_start
+                .checked_add(systole_end - systole_start)
+                .unwrap_or(Instant::MAX),
+        )
+    } else {
+        // The valve latencies leave no systole at all, wait for the next beat
+        (CardiacPhase::Diastole, next_epoch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uom::si::f32::Time;
+
+    use crate::heart_control::{
+        phase::{ValveState, Ventricle, VentriclePhases},
+        rhythm::{RhythmConfig, RhythmMode},
+        state_machine::{
+            HeartController,
+            tests::{dyssynchronous, parameters},
+        },
+    };
+
+    fn latency(pressure_us: f32, vacuum_us: f32) -> ValveLatency {
+        ValveLatency {
+            pressure: Time::new::<microsecond>(pressure_us),
+            vacuum: Time::new::<microsecond>(vacuum_us),
+        }
+    }
+
+    #[test]
+    fn test_right_ventricle_delayed() {
+        let mut controller = HeartController::new();
+        let params = dyssynchronous(0.25, 0.25, 125.0);
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // Left ventricle contracts first
+        let step = controller.step(start, Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases {
+                left: CardiacPhase::Systole,
+                right: CardiacPhase::Diastole
+            }
+        );
+        assert_eq!(step.commands.left_valve, ValveState::Pressure);
+        assert_eq!(step.commands.right_valve, ValveState::Vacuum);
+        assert_eq!(step.deadline, Some(at(125)));
+
+        // Right ventricle follows 125ms later
+        let step = controller.step(at(125), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(step.deadline, Some(at(250)));
+
+        let step = controller.step(at(250), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases {
+                left: CardiacPhase::Diastole,
+                right: CardiacPhase::Systole
+            }
+        );
+        assert_eq!(step.deadline, Some(at(375)));
+
+        let step = controller.step(at(375), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Diastole)
+        );
+        assert_eq!(step.deadline, Some(at(1000)));
+
+        // Next beat starts with the left ventricle again
+        let step = controller.step(at(1000), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases {
+                left: CardiacPhase::Systole,
+                right: CardiacPhase::Diastole
+            }
+        );
+        assert_eq!(step.deadline, Some(at(1125)));
+        assert_eq!(controller.timing().beats, 2);
+    }
+
+    #[test]
+    fn test_right_ventricle_leads_with_longer_systole() {
+        let mut controller = HeartController::new();
+        let params = dyssynchronous(0.25, 0.5, -62.5);
+        let start = Instant::from_secs(1);
+        let at = |us| start + Duration::from_micros(us);
+
+        let step = controller.step(start, Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases {
+                left: CardiacPhase::Diastole,
+                right: CardiacPhase::Systole
+            }
+        );
+        assert_eq!(step.deadline, Some(at(62_500)));
+
+        // Left systole lasts 250ms, right systole 500ms
+        let step = controller.step(at(62_500), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(step.deadline, Some(at(312_500)));
+
+        let step = controller.step(at(312_500), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases {
+                left: CardiacPhase::Diastole,
+                right: CardiacPhase::Systole
+            }
+        );
+        assert_eq!(step.deadline, Some(at(500_000)));
+    }
+
+    #[test]
+    fn test_interventricular_delay_fits_in_beat() {
+        let mut controller = HeartController::new();
+        // A 900ms delay would push right systole into the next beat: clamp it to 750ms
+        let params = dyssynchronous(0.25, 0.25, 900.0);
+        let start = Instant::from_secs(1);
+
+        let step = controller.step(start, Some(&params));
+        assert_eq!(controller.phases().right, CardiacPhase::Diastole);
+        assert_eq!(step.deadline, Some(start + Duration::from_millis(250)));
+
+        let step = controller.step(start + Duration::from_millis(250), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Diastole)
+        );
+        assert_eq!(step.deadline, Some(start + Duration::from_millis(750)));
+
+        let step = controller.step(start + Duration::from_millis(750), Some(&params));
+        assert_eq!(controller.phases().right, CardiacPhase::Systole);
+        assert_eq!(step.deadline, Some(start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_short_beat_cuts_systole() {
+        let mut controller = HeartController::new();
+        controller.set_rhythm(RhythmConfig {
+            mode: RhythmMode::PrematureBeats {
+                probability: 1.0,
+                prematurity: 0.25,
+            },
+            seed: 1,
+        });
+        // A 500ms systole does not fit in a 250ms premature beat
+        let params = parameters(60.0, 0.5, 1.0);
+        let start = Instant::from_secs(1);
+
+        let step = controller.step(start, Some(&params));
+        assert_eq!(step.deadline, Some(start + Duration::from_millis(250)));
+
+        // Next beat starts with a contraction right away
+        let step = controller.step(start + Duration::from_millis(250), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(step.deadline, Some(start + Duration::from_millis(750)));
+        assert_eq!(controller.timing().switches, 1);
+        assert_eq!(controller.timing().beats, 2);
+    }
+
+    #[test]
+    fn test_valve_latency_commands_switches_early() {
+        let epoch = Instant::from_secs(1);
+        let next_epoch = epoch + Duration::from_secs(1);
+        let at = |us| epoch + Duration::from_micros(us);
+        let systole = SystoleWindow {
+            offset: Duration::from_millis(125),
+            duration: Duration::from_millis(250),
+        };
+        let lead = ValveLead::from(&latency(20_000.0, 40_000.0));
+
+        let phase = |now| get_ventricle_phase(epoch, next_epoch, systole, lead, now);
+        assert_eq!(phase(at(0)), (CardiacPhase::Diastole, at(105_000)));
+        assert_eq!(phase(at(105_000)), (CardiacPhase::Systole, at(335_000)));
+        assert_eq!(phase(at(335_000)), (CardiacPhase::Diastole, at(1_105_000)));
+
+        // Without latency the commands follow the schedule
+        let phase = |now| get_ventricle_phase(epoch, next_epoch, systole, ValveLead::NONE, now);
+        assert_eq!(phase(at(0)), (CardiacPhase::Diastole, at(125_000)));
+        assert_eq!(phase(at(125_000)), (CardiacPhase::Systole, at(375_000)));
+        assert_eq!(phase(at(375_000)), (CardiacPhase::Diastole, at(1_125_000)));
+    }
+
+    #[test]
+    fn test_valve_latency_pulls_systole_into_previous_beat() {
+        let mut controller = HeartController::new();
+        controller.set_valve_latency(Ventricle::Left, &latency(15_625.0, 31_250.0));
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+        let at = |us| start + Duration::from_micros(us);
+
+        // Left valve vents early, the right valve has no latency
+        let step = controller.step(start, Some(&params));
+        assert_eq!(step.deadline, Some(at(218_750)));
+        let step = controller.step(at(218_750), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases {
+                left: CardiacPhase::Diastole,
+                right: CardiacPhase::Systole
+            }
+        );
+        assert_eq!(step.deadline, Some(at(250_000)));
+        let step = controller.step(at(250_000), Some(&params));
+        assert_eq!(step.deadline, Some(at(984_375)));
+
+        // Left valve pressurises ahead of the next beat
+        let step = controller.step(at(984_375), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases {
+                left: CardiacPhase::Systole,
+                right: CardiacPhase::Diastole
+            }
+        );
+        assert_eq!(step.deadline, Some(at(1_000_000)));
+        assert_eq!(controller.timing().beats, 1);
+
+        let step = controller.step(at(1_000_000), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(step.deadline, Some(at(1_218_750)));
+        assert_eq!(controller.timing().beats, 2);
+        assert_eq!(controller.timing().resyncs, 0);
+    }
+
+    #[test]
+    fn test_valve_latency_longer_than_systole() {
+        let mut controller = HeartController::new();
+        controller.set_valve_latency(Ventricle::Left, &latency(0.0, 500_000.0));
+        controller.set_valve_latency(Ventricle::Right, &latency(0.0, 500_000.0));
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+
+        // The valves never pressurise but the heart keeps beating
+        let mut step = controller.step(start, Some(&params));
+        for beat in 1..=3 {
+            assert_eq!(
+                controller.phases(),
+                VentriclePhases::both(CardiacPhase::Diastole)
+            );
+            assert_eq!(step.deadline, Some(start + Duration::from_secs(beat)));
+            step = controller.step(step.deadline.unwrap(), Some(&params));
+        }
+        assert_eq!(controller.timing().resyncs, 0);
+    }
+}
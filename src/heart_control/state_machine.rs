@@ -0,0 +1,1070 @@
+//! Pure heart controller core
+//! Keeps track of the cardiac phase without touching any embassy primitives, the embassy task in
+//! [`super::heart_controller`] feeds it the current time and setpoint and actuates whatever it
+//! commands. This keeps the timing logic testable on the host.
+//! Only the transitions between beats and phases live here, the features they sequence live in
+//! sibling modules: [`super::schedule`], [`super::pacing`], [`super::rhythm`],
+//! [`super::profile`], [`super::waveform`] and [`super::beat`].
+
+use defmt::{debug, warn};
+use embassy_time::{Duration, Instant};
+use love_letter::Setpoint;
+use uom::si::{
+    f32::{Frequency, Pressure, Time},
+    pressure::bar,
+    time::second,
+};
+
+use crate::heart_control::{
+    beat::{BeatEvent, BeatEvents, BeatTiming, RrInterval},
+    config::{
+        HeartConfig, PacingMode, SetpointTiming, SetpointTransition, ValveLatency,
+        VentricleSetpoint,
+    },
+    pacing::Pacer,
+    phase::{
+        CardiacPhase, SystoleModel, ValveState, Ventricle, VentriclePhases, get_cycle_period_us,
+    },
+    profile::{CycleProfile, ProfilePhase},
+    rhythm::{RhythmConfig, RhythmGenerator},
+    schedule::{SystoleWindow, ValveLead, get_systole_windows, get_ventricle_phase},
+    waveform::PressureWaveform,
+};
+
+/// Heart parameters the controller acts upon, extracted from the host [`Setpoint`] and the
+/// firmware [`HeartConfig`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeartParameters {
+    pub heart_rate: Frequency,
+    pub pressure: Pressure,
+    pub left_systole_ratio: f32,
+    pub right_systole_ratio: f32,
+    /// Delay of right ventricle systole after left ventricle systole, negative if the right
+    /// ventricle leads
+    pub interventricular_delay: Time,
+}
+
+impl HeartParameters {
+    /// Extract the heart parameters from a host setpoint, `None` if the heart controller is
+    /// disabled
+    pub fn from_setpoint(setpoint: &Setpoint, config: &HeartConfig) -> Option<Self> {
+        let heart_setpoint = setpoint.heart_controller_setpoint.as_ref()?;
+
+        // Without a ventricle setpoint both ventricles beat in sync
+        let ventricles = config.ventricles.unwrap_or(VentricleSetpoint {
+            left_systole_ratio: heart_setpoint.systole_ratio,
+            right_systole_ratio: heart_setpoint.systole_ratio,
+            interventricular_delay: Time::new::<second>(0.0),
+        });
+
+        Some(Self {
+            heart_rate: heart_setpoint.heart_rate,
+            pressure: heart_setpoint.pressure,
+            left_systole_ratio: ventricles.left_systole_ratio,
+            right_systole_ratio: ventricles.right_systole_ratio,
+            interventricular_delay: ventricles.interventricular_delay,
+        })
+    }
+
+    /// Parameters with the systole ratios given by `model` for a cycle of `cycle_period_us`
+    pub fn with_systole_model(&self, model: &SystoleModel, cycle_period_us: u64) -> Self {
+        Self {
+            left_systole_ratio: model.systole_ratio(cycle_period_us, self.left_systole_ratio),
+            right_systole_ratio: model.systole_ratio(cycle_period_us, self.right_systole_ratio),
+            ..*self
+        }
+    }
+}
+
+/// Actuator outputs requested by the [`HeartController`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ActuatorCommands {
+    pub regulator_pressure: Pressure,
+    pub left_valve: ValveState,
+    pub right_valve: ValveState,
+}
+
+impl ActuatorCommands {
+    /// 0 bar pressure seems like the safest state for the solenoid
+    const SAFE_REGULATOR_PRESSURE_BAR: f32 = 0.0;
+    /// Safest solenoid state. Alternative is Vacuum which seems less safe
+    const SAFE_VALVE_STATE: ValveState = ValveState::Pressure;
+
+    /// Valves and pressure regulator in their safe state
+    pub fn safe() -> Self {
+        Self {
+            regulator_pressure: Pressure::new::<bar>(Self::SAFE_REGULATOR_PRESSURE_BAR),
+            left_valve: Self::SAFE_VALVE_STATE,
+            right_valve: Self::SAFE_VALVE_STATE,
+        }
+    }
+
+    /// Valves and pressure regulator effecting the given ventricle phases
+    pub fn for_phases(phases: VentriclePhases, pressure: Pressure) -> Self {
+        Self {
+            regulator_pressure: pressure,
+            left_valve: get_valve_state_for_cardiac_phase(phases.left),
+            right_valve: get_valve_state_for_cardiac_phase(phases.right),
+        }
+    }
+}
+
+/// Outcome of a single [`HeartController::step`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeartStep {
+    /// Actuator outputs to apply now
+    pub commands: ActuatorCommands,
+    /// Absolute deadline of the next phase switch, `None` if only a new setpoint can change the
+    /// outputs
+    pub deadline: Option<Instant>,
+    /// Phase switches effected by the commands
+    pub events: BeatEvents,
+    /// RR interval of the beat that ended, if a new beat started during the step
+    pub rr_interval: Option<RrInterval>,
+}
+
+/// Cardiac phase state machine of the pneumatic heart
+///
+/// Phase boundaries are absolute deadlines computed from a beat epoch: the epoch of the next beat
+/// is the epoch of the current one plus its RR interval, all summed in whole microseconds since an
+/// anchor. Late wake-ups therefore never shift the beats that follow.
+/// The beat epoch is the start of systole of the leading ventricle, the lagging ventricle starts
+/// its systole the interventricular delay later. Systole lasts a fixed fraction of the nominal
+/// cardiac period, diastole absorbs the beat to beat variation of the
+/// [`RhythmMode`](crate::heart_control::rhythm::RhythmMode).
+/// Within systole and diastole the valves and regulator follow the steps of the [`CycleProfile`].
+#[derive(Debug, Clone)]
+pub struct HeartController {
+    /// Current cardiac phase of each ventricle
+    phases: VentriclePhases,
+    /// Start of the first beat scheduled with the current parameters, `None` while the controller
+    /// is disabled
+    anchor: Option<Instant>,
+    /// Start of the current beat in microseconds since `anchor`
+    beat_start_us: u64,
+    /// RR interval of the current beat as a factor of the nominal cardiac period
+    rr_factor: f32,
+    /// Heart parameters the current schedule is based upon
+    parameters: Option<HeartParameters>,
+    /// Latest requested heart parameters, the applied ones follow according to `transition`
+    target: Option<HeartParameters>,
+    /// Moment the applied parameters were last brought towards the target
+    applied_at: Instant,
+    /// How setpoint changes are applied
+    transition: SetpointTransition,
+    /// Systole duration as a function of the heart rate
+    systole_model: SystoleModel,
+    /// Next phase switch of either ventricle
+    deadline: Option<Instant>,
+    /// Moment each ventricle last switched phase, indexed by [`Ventricle`]
+    switched_at: [Instant; 2],
+    /// Start of the current cardiac phase of each ventricle, indexed by [`Ventricle`]
+    phase_start: [Instant; 2],
+    /// Current profile phase of each ventricle, indexed by [`Ventricle`]
+    profile_phases: [u8; 2],
+    /// Actuation sequence within systole and diastole
+    profile: CycleProfile,
+    /// Valve switching delays, indexed by [`Ventricle`]
+    valve_latency: [ValveLead; 2],
+    /// Shape of the pressure regulator setpoint during systole
+    waveform: PressureWaveform,
+    /// Draws the RR interval of every beat
+    rhythm: RhythmGenerator,
+    /// Source of the beats
+    pacer: Pacer,
+    timing: BeatTiming,
+}
+
+impl Default for HeartController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeartController {
+    pub const fn new() -> Self {
+        Self {
+            phases: VentriclePhases::both(CardiacPhase::Systole),
+            anchor: None,
+            beat_start_us: 0,
+            rr_factor: 1.0,
+            parameters: None,
+            target: None,
+            applied_at: Instant::from_ticks(0),
+            transition: SetpointTransition {
+                timing: SetpointTiming::Immediate,
+                max_heart_rate_slew: None,
+                max_pressure_slew: None,
+            },
+            systole_model: SystoleModel::DEFAULT,
+            deadline: None,
+            switched_at: [Instant::from_ticks(0); 2],
+            phase_start: [Instant::from_ticks(0); 2],
+            profile_phases: [0; 2],
+            // Empty phase sequences behave like the default two phase profile
+            profile: CycleProfile {
+                systole: heapless::Vec::new(),
+                diastole: heapless::Vec::new(),
+            },
+            valve_latency: [ValveLead::NONE; 2],
+            waveform: PressureWaveform::Square,
+            rhythm: RhythmGenerator::new(RhythmConfig::DEFAULT),
+            pacer: Pacer::new(PacingMode::Internal),
+            timing: BeatTiming::new(),
+        }
+    }
+
+    pub fn phases(&self) -> VentriclePhases {
+        self.phases
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.anchor.is_some()
+    }
+
+    pub fn timing(&self) -> BeatTiming {
+        self.timing
+    }
+
+    /// Select the driveline pressure waveform, takes effect on the next step
+    pub fn set_waveform(&mut self, waveform: PressureWaveform) {
+        self.waveform = waveform;
+    }
+
+    /// Select the actuation sequence within systole and diastole, takes effect on the next step
+    pub fn set_profile(&mut self, profile: CycleProfile) {
+        self.profile = profile;
+    }
+
+    /// Select the beat source, takes effect on the next step
+    pub fn set_pacing(&mut self, pacing: PacingMode) {
+        self.pacer.set_mode(pacing);
+    }
+
+    /// Register an edge of the external trigger input at `at`, handled on the next step
+    pub fn trigger(&mut self, at: Instant) {
+        self.pacer.trigger(at);
+    }
+
+    /// Select the heart rhythm, takes effect from the next beat
+    /// The rhythm restarts from its seed whenever it changes and whenever the controller is enabled
+    pub fn set_rhythm(&mut self, rhythm: RhythmConfig) {
+        if self.rhythm.config() != rhythm {
+            self.rhythm = RhythmGenerator::new(rhythm);
+        }
+    }
+
+    /// Start of the current beat, `None` while the controller is disabled
+    pub fn beat_epoch(&self) -> Option<Instant> {
+        Some(self.anchor? + Duration::from_micros(self.beat_start_us))
+    }
+
+    /// Phase switches a step at `at` would make if nothing changes until then, the controller
+    /// itself is left as is
+    pub fn preview(&self, at: Instant, parameters: Option<&HeartParameters>) -> BeatEvents {
+        self.clone().step(at, parameters).events
+    }
+
+    /// Advance the state machine to `now` given the latest heart parameters, `None` parameters
+    /// disable the controller and command the safe state
+    pub fn step(&mut self, now: Instant, parameters: Option<&HeartParameters>) -> HeartStep {
+        let Some(parameters) = parameters else {
+            if self.anchor.take().is_some() {
+                debug!("HEART CONTROL: DISABLED -> Moving to safe state");
+            }
+            self.phases = VentriclePhases::both(CardiacPhase::Systole);
+            self.parameters = None;
+            self.target = None;
+            self.deadline = None;
+            self.pacer.reset();
+
+            return HeartStep {
+                commands: ActuatorCommands::safe(),
+                deadline: None,
+                events: BeatEvents::new(),
+                rr_interval: None,
+            };
+        };
+
+        // Moment the phases are evaluated at, a phase switch is handled at its deadline so a late
+        // wake-up does not shift the schedule
+        let mut at = now;
+        // Whether the current beat started during this step
+        let mut started_beat = false;
+        let beats = self.timing.beats;
+        let enabling = self.anchor.is_none();
+        if enabling {
+            // Triggers that arrived while disabled are stale
+            self.pacer.reset();
+        }
+
+        match self.beat_epoch() {
+            None => {
+                // Freshly enabled: every beat starts with a contraction
+                self.timing = BeatTiming {
+                    beats: 1,
+                    ..BeatTiming::default()
+                };
+                self.rhythm.reset();
+                self.rr_factor = self.rhythm.next_rr_factor();
+                self.anchor(now);
+                self.parameters = Some(*parameters);
+                self.target = Some(*parameters);
+                self.applied_at = now;
+                started_beat = true;
+                debug!("HEART CONTROL: ENABLED -> starting a beat");
+            }
+            Some(epoch)
+                if self.target.as_ref() != Some(parameters) && self.retarget(*parameters, now) =>
+            {
+                // New parameters: reschedule the current beat from its original start
+                debug!(
+                    "HEART CONTROL: new parameters, rescheduling beat from {}",
+                    epoch
+                );
+                self.anchor(epoch);
+            }
+            Some(_) => {
+                if let Some(deadline) = self.deadline.filter(|deadline| now >= *deadline) {
+                    self.timing.record_jitter(now - deadline);
+                    at = deadline;
+                }
+            }
+        }
+
+        // An external trigger starts a beat from the moment of its edge
+        if let Some(trigger) = self.pacer.take_trigger() {
+            if let Some(epoch) = self.beat_epoch() {
+                self.timing
+                    .record_beat(trigger.saturating_duration_since(epoch));
+            }
+            self.anchor(trigger);
+            self.rr_factor = self.rhythm.next_rr_factor();
+            self.apply_target(trigger);
+            at = trigger.max(at);
+            started_beat = true;
+        }
+
+        // Did the next beat start?
+        if let Some(period_us) = self.period_us()
+            && at >= self.beat_end(period_us)
+        {
+            let rr_us = self.beat_length_us(period_us);
+            self.beat_start_us += rr_us;
+            self.rr_factor = self.rhythm.next_rr_factor();
+            self.timing.record_beat(Duration::from_micros(rr_us));
+            started_beat = true;
+            debug!(
+                "HEART CONTROL: beat {} started after an RR interval of {}us",
+                self.timing.beats, rr_us
+            );
+
+            // Pending setpoint changes take effect, or continue to ramp, as the new beat starts
+            let epoch = self.anchor.unwrap_or(at) + Duration::from_micros(self.beat_start_us);
+            if self.apply_target(epoch) {
+                debug!("HEART CONTROL: applied new parameters at the start of the beat");
+            }
+        }
+
+        let parameters = self.parameters.unwrap_or(*parameters);
+
+        // A heart that does not beat never switches phase by itself, and simply holds the setpoint
+        // pressure
+        let Some(period_us) = self.period_us() else {
+            self.deadline = None;
+
+            let steps = [Ventricle::Left, Ventricle::Right]
+                .map(|ventricle| self.profile.first(self.phases.get(ventricle)));
+            let commands = self.commands(
+                &parameters,
+                steps.map(|(_, step)| step),
+                parameters.pressure,
+            );
+
+            return HeartStep {
+                commands,
+                deadline: None,
+                events: self.switch_phases(
+                    self.phases,
+                    steps.map(|(index, _)| index),
+                    now,
+                    enabling,
+                ),
+                rr_interval: None,
+            };
+        };
+        let parameters = &parameters.with_systole_model(&self.systole_model, period_us);
+
+        let (mut phases, mut switches) = self.schedule(period_us, parameters, at);
+
+        if switches[0].min(switches[1]) <= now {
+            // We fell a whole phase behind: skipping phases would damage the beat so start a
+            // fresh one right now instead
+            let lateness = now - at;
+            warn!(
+                "HEART CONTROL: fell {} behind schedule, starting a new beat",
+                lateness
+            );
+            if let Some(epoch) = self.beat_epoch() {
+                if started_beat {
+                    self.timing.last_rr_interval += now - epoch;
+                } else {
+                    self.timing.record_beat(now - epoch);
+                    self.rr_factor = self.rhythm.next_rr_factor();
+                }
+            }
+            self.timing.resyncs = self.timing.resyncs.wrapping_add(1);
+            self.timing.drift += lateness;
+            self.anchor(now);
+            at = now;
+
+            (phases, switches) = self.schedule(period_us, parameters, now);
+        }
+
+        // Divide the cardiac phases into the steps of the actuation profile
+        let mut deadline = Instant::MAX;
+        let steps = [Ventricle::Left, Ventricle::Right].map(|ventricle| {
+            let phase = phases.get(ventricle);
+            if enabling || phase != self.phases.get(ventricle) {
+                self.phase_start[ventricle as usize] = at;
+            }
+
+            let step = self.profile.locate(
+                phase,
+                self.phase_start[ventricle as usize],
+                switches[ventricle as usize],
+                at,
+            );
+            deadline = deadline.min(step.end);
+
+            step
+        });
+
+        let events = self.switch_phases(phases, steps.map(|step| step.index), now, enabling);
+        // Nothing to wait for in diastole while only an external trigger starts the next beat
+        self.deadline = Some(deadline).filter(|deadline| *deadline != Instant::MAX);
+
+        // Shape the regulator pressure, a shaped waveform also wakes us up for its next update
+        let (pressure, next_update) = self.regulator_pressure(period_us, parameters, now);
+        let deadline = match (next_update, self.deadline) {
+            (Some(next_update), Some(deadline)) => Some(next_update.min(deadline)),
+            (next_update, deadline) => next_update.or(deadline),
+        };
+
+        // A beat that started during this step, its interval includes any resync stretching it
+        let rr_interval = (!enabling && self.timing.beats != beats).then_some(RrInterval {
+            beat: self.timing.beats,
+            interval: self.timing.last_rr_interval,
+        });
+
+        HeartStep {
+            commands: self.commands(parameters, steps.map(|step| step.phase), pressure),
+            deadline,
+            events,
+            rr_interval,
+        }
+    }
+
+    /// Move to `phases` and the given profile phases at `now`, returns an event for every
+    /// ventricle that switched profile phase, or for both when `enabling`
+    fn switch_phases(
+        &mut self,
+        phases: VentriclePhases,
+        profile_phases: [u8; 2],
+        now: Instant,
+        enabling: bool,
+    ) -> BeatEvents {
+        if phases != self.phases {
+            debug!("HEART CONTROL: switching cardiac phases to {:?}", phases);
+        }
+
+        let mut events = BeatEvents::new();
+        for ventricle in [Ventricle::Left, Ventricle::Right] {
+            let profile_phase = profile_phases[ventricle as usize];
+            if !enabling && profile_phase == self.profile_phases[ventricle as usize] {
+                continue;
+            }
+
+            let switched_at = &mut self.switched_at[ventricle as usize];
+            let ended_phase_duration = (!enabling).then(|| now - *switched_at);
+            *switched_at = now;
+
+            // Holds one event per ventricle
+            let _ = events.push(BeatEvent {
+                beat: self.timing.beats,
+                ventricle,
+                phase: phases.get(ventricle),
+                profile_phase,
+                timestamp: now.as_micros(),
+                ended_phase_duration,
+            });
+        }
+        self.phases = phases;
+        self.profile_phases = profile_phases;
+
+        events
+    }
+
+    /// Actuator outputs for the profile phase of each ventricle, the regulator follows the profile
+    /// of the leading ventricle
+    fn commands(
+        &self,
+        parameters: &HeartParameters,
+        steps: [ProfilePhase; 2],
+        pressure: Pressure,
+    ) -> ActuatorCommands {
+        let [left, right] = steps;
+        let leading = if parameters.interventricular_delay.get::<second>() >= 0.0 {
+            left
+        } else {
+            right
+        };
+
+        ActuatorCommands {
+            regulator_pressure: pressure * leading.pressure.clamp(0.0, 1.0),
+            left_valve: left.valve,
+            right_valve: right.valve,
+        }
+    }
+
+    /// Pressure regulator setpoint at `now` following the driveline waveform, and the moment of
+    /// the next waveform update if one is due during the current systole
+    fn regulator_pressure(
+        &self,
+        period_us: u64,
+        parameters: &HeartParameters,
+        now: Instant,
+    ) -> (Pressure, Option<Instant>) {
+        let epoch = self.beat_epoch().unwrap_or(now);
+        let (left, right) = self.systole_windows(period_us, parameters);
+
+        // The regulator feeds both ventricles: its systole lasts from the leading ventricle
+        // contracting, at the beat epoch, until the last ventricle relaxes
+        let systole_end = epoch + (left.offset + left.duration).max(right.offset + right.duration);
+
+        self.waveform
+            .regulator_pressure(parameters.pressure, epoch, systole_end, now)
+    }
+
+    /// Applied heart parameters, these trail the requested ones while a setpoint change is
+    /// pending or ramping
+    pub fn parameters(&self) -> Option<HeartParameters> {
+        self.parameters
+    }
+
+    /// Set the switching delay of a ventricle valve, its switches are commanded this much early
+    pub fn set_valve_latency(&mut self, ventricle: Ventricle, latency: &ValveLatency) {
+        self.valve_latency[ventricle as usize] = ValveLead::from(latency);
+    }
+
+    /// Select how setpoint changes are applied, takes effect on the next setpoint change
+    pub fn set_transition(&mut self, transition: SetpointTransition) {
+        self.transition = transition;
+    }
+
+    /// Select how the systole duration follows the heart rate, takes effect on the next step
+    pub fn set_systole_model(&mut self, model: SystoleModel) {
+        self.systole_model = model;
+    }
+
+    /// Cardiac period of the applied parameters, `None` if the heart does not beat
+    fn period_us(&self) -> Option<u64> {
+        get_cycle_period_us(self.parameters?.heart_rate)
+    }
+
+    /// Take in newly requested parameters, returns whether the applied parameters changed
+    /// A stopped heart has no beat to wait for and applies them right away
+    fn retarget(&mut self, target: HeartParameters, now: Instant) -> bool {
+        self.target = Some(target);
+
+        let apply_now = match self.transition.timing {
+            SetpointTiming::Immediate => true,
+            SetpointTiming::NextBeat => self.period_us().is_none(),
+        };
+
+        apply_now && self.apply_target(now)
+    }
+
+    /// Move the applied parameters towards the requested ones, as far as the slew limits allow
+    /// since they were last applied. Returns whether the applied parameters changed
+    fn apply_target(&mut self, at: Instant) -> bool {
+        let (Some(applied), Some(target)) = (self.parameters, self.target) else {
+            return false;
+        };
+        let elapsed = at.saturating_duration_since(self.applied_at);
+        self.applied_at = at;
+
+        // A stopped heart has no beats to ramp over
+        let next = if self.period_us().is_some() {
+            self.transition.slew(&applied, &target, elapsed)
+        } else {
+            target
+        };
+        self.parameters = Some(next);
+
+        next != applied
+    }
+
+    /// Restart the beat schedule with the current beat starting at `epoch`
+    fn anchor(&mut self, epoch: Instant) {
+        self.anchor = Some(epoch);
+        self.beat_start_us = 0;
+    }
+
+    /// RR interval of the current beat in microseconds
+    fn rr_us(&self, period_us: u64) -> u64 {
+        if self.rr_factor == 1.0 {
+            // Keep a regular rhythm exact
+            period_us
+        } else {
+            ((period_us as f32 * self.rr_factor) as u64).max(1)
+        }
+    }
+
+    /// Start of the next internally paced beat in microseconds since `anchor`, `None` if only an
+    /// external trigger starts it
+    fn next_beat_us(&self, period_us: u64) -> Option<u64> {
+        self.pacer
+            .next_beat_us(self.anchor, self.beat_start_us, self.rr_us(period_us))
+    }
+
+    /// Start of the next beat, `Instant::MAX` while waiting for an external trigger
+    fn beat_end(&self, period_us: u64) -> Instant {
+        match self.next_beat_us(period_us) {
+            Some(next_beat_us) => {
+                self.anchor.unwrap_or(Instant::MIN) + Duration::from_micros(next_beat_us)
+            }
+            None => Instant::MAX,
+        }
+    }
+
+    /// Length of the current beat in microseconds, the nominal RR interval while waiting for an
+    /// external trigger
+    fn beat_length_us(&self, period_us: u64) -> u64 {
+        match self.next_beat_us(period_us) {
+            Some(next_beat_us) => next_beat_us - self.beat_start_us,
+            None => self.rr_us(period_us),
+        }
+    }
+
+    /// Systole of the left and right ventricle within the current beat
+    fn systole_windows(
+        &self,
+        period_us: u64,
+        parameters: &HeartParameters,
+    ) -> (SystoleWindow, SystoleWindow) {
+        let beat = Duration::from_micros(self.beat_length_us(period_us));
+
+        get_systole_windows(period_us, beat, parameters)
+    }
+
+    /// Phases commanded to both ventricles at `at` within the current beat, and the moment after
+    /// `at` at which each of them switches, indexed by [`Ventricle`]
+    fn schedule(
+        &self,
+        period_us: u64,
+        parameters: &HeartParameters,
+        at: Instant,
+    ) -> (VentriclePhases, [Instant; 2]) {
+        let epoch = self.beat_epoch().unwrap_or(at);
+        let next_epoch = self.beat_end(period_us);
+        let (left_window, right_window) = self.systole_windows(period_us, parameters);
+
+        let [left_latency, right_latency] = self.valve_latency;
+        let (left, left_switch) =
+            get_ventricle_phase(epoch, next_epoch, left_window, left_latency, at);
+        let (right, right_switch) =
+            get_ventricle_phase(epoch, next_epoch, right_window, right_latency, at);
+
+        (VentriclePhases { left, right }, [left_switch, right_switch])
+    }
+}
+
+fn get_valve_state_for_cardiac_phase(phase: CardiacPhase) -> ValveState {
+    match phase {
+        CardiacPhase::Systole => ValveState::Pressure,
+        CardiacPhase::Diastole => ValveState::Vacuum,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use embassy_time::MockDriver;
+    use uom::si::{
+        frequency::{cycle_per_minute, hertz},
+        time::millisecond,
+    };
+
+    // NOTE: durations below are powers of two fractions of a second so they are exact in
+    // embassy-time ticks
+
+    pub fn parameters(bpm: f32, systole_ratio: f32, pressure_bar: f32) -> HeartParameters {
+        HeartParameters {
+            heart_rate: Frequency::new::<cycle_per_minute>(bpm),
+            pressure: Pressure::new::<bar>(pressure_bar),
+            left_systole_ratio: systole_ratio,
+            right_systole_ratio: systole_ratio,
+            interventricular_delay: Time::new::<second>(0.0),
+        }
+    }
+
+    pub fn dyssynchronous(
+        left_systole_ratio: f32,
+        right_systole_ratio: f32,
+        delay_ms: f32,
+    ) -> HeartParameters {
+        HeartParameters {
+            left_systole_ratio,
+            right_systole_ratio,
+            interventricular_delay: Time::new::<millisecond>(delay_ms),
+            ..parameters(60.0, 0.25, 1.0)
+        }
+    }
+
+    #[test]
+    fn test_starts_in_systole() {
+        let mut controller = HeartController::new();
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(5);
+
+        let step = controller.step(start, Some(&params));
+
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(
+            step.commands,
+            ActuatorCommands::for_phases(
+                VentriclePhases::both(CardiacPhase::Systole),
+                Pressure::new::<bar>(1.0)
+            )
+        );
+        assert_eq!(step.deadline, Some(start + Duration::from_millis(250)));
+        assert_eq!(controller.beat_epoch(), Some(start));
+    }
+
+    #[test]
+    fn test_switches_between_systole_and_diastole() {
+        let mut controller = HeartController::new();
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+
+        controller.step(start, Some(&params));
+
+        // Halfway through systole nothing changes
+        let step = controller.step(start + Duration::from_millis(125), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(step.deadline, Some(start + Duration::from_millis(250)));
+
+        // End of systole
+        let step = controller.step(start + Duration::from_millis(250), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Diastole)
+        );
+        assert_eq!(step.commands.left_valve, ValveState::Vacuum);
+        assert_eq!(step.commands.right_valve, ValveState::Vacuum);
+        assert_eq!(step.deadline, Some(start + Duration::from_secs(1)));
+
+        // End of diastole, next beat
+        let step = controller.step(start + Duration::from_secs(1), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(step.commands.left_valve, ValveState::Pressure);
+        assert_eq!(step.deadline, Some(start + Duration::from_millis(1250)));
+        assert_eq!(controller.timing().beats, 2);
+    }
+
+    #[test]
+    fn test_late_wakeup_does_not_shift_beat() {
+        let mut controller = HeartController::new();
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+
+        controller.step(start, Some(&params));
+
+        // Wake up late for the end of systole
+        let late = Duration::from_millis(4);
+        let step = controller.step(start + Duration::from_millis(250) + late, Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Diastole)
+        );
+        assert_eq!(step.deadline, Some(start + Duration::from_secs(1)));
+
+        let timing = controller.timing();
+        assert_eq!(timing.last_jitter, late);
+        assert_eq!(timing.max_jitter, late);
+        assert_eq!(timing.resyncs, 0);
+    }
+
+    #[test]
+    fn test_disable_and_enable() {
+        let mut controller = HeartController::new();
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+
+        controller.step(start, Some(&params));
+        controller.step(start + Duration::from_millis(250), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Diastole)
+        );
+
+        // Disabling commands the safe state and idles
+        let step = controller.step(start + Duration::from_millis(500), None);
+        assert!(!controller.is_enabled());
+        assert_eq!(step.commands, ActuatorCommands::safe());
+        assert_eq!(step.deadline, None);
+
+        // Re-enabling starts a fresh beat, regardless of the time spent disabled
+        let enable = start + Duration::from_secs(10);
+        let step = controller.step(enable, Some(&params));
+        assert!(controller.is_enabled());
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(step.deadline, Some(enable + Duration::from_millis(250)));
+        assert_eq!(controller.timing().beats, 1);
+    }
+
+    #[test]
+    fn test_setpoint_change_mid_beat() {
+        let mut controller = HeartController::new();
+        let start = Instant::from_secs(1);
+
+        controller.step(start, Some(&parameters(60.0, 0.25, 1.0)));
+
+        // 62.5ms into systole the heart rate doubles: systole now ends 125ms into the beat
+        let faster = parameters(120.0, 0.25, 1.5);
+        let step = controller.step(start + Duration::from_micros(62_500), Some(&faster));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(step.commands.regulator_pressure, Pressure::new::<bar>(1.5));
+        assert_eq!(step.deadline, Some(start + Duration::from_millis(125)));
+
+        // 125ms into systole the heart rate doubles again: systole is overdue and ends right away,
+        // the beat still ends 250ms after it started
+        let fastest = parameters(240.0, 0.25, 1.5);
+        let step = controller.step(start + Duration::from_millis(125), Some(&fastest));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Diastole)
+        );
+        assert_eq!(step.deadline, Some(start + Duration::from_millis(250)));
+        assert_eq!(controller.beat_epoch(), Some(start));
+
+        // Rescheduling is not counted as jitter
+        assert_eq!(controller.timing().switches, 0);
+    }
+
+    #[test]
+    fn test_falling_behind_resyncs() {
+        let mut controller = HeartController::new();
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+
+        controller.step(start, Some(&params));
+
+        // Stalled for more than a whole diastole: start a new beat now
+        let now = start + Duration::from_secs(2);
+        let step = controller.step(now, Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(step.deadline, Some(now + Duration::from_millis(250)));
+        assert_eq!(controller.beat_epoch(), Some(now));
+
+        let timing = controller.timing();
+        assert_eq!(timing.resyncs, 1);
+        assert_eq!(timing.drift, Duration::from_millis(1750));
+        assert_eq!(timing.beats, 2);
+    }
+
+    #[test]
+    fn test_zero_heart_rate_never_switches() {
+        let mut controller = HeartController::new();
+
+        let step = controller.step(Instant::from_secs(1), Some(&parameters(0.0, 0.25, 1.0)));
+
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(step.deadline, None);
+    }
+
+    #[test]
+    fn test_preview_phase_switches() {
+        let mut controller = HeartController::new();
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+
+        let step = controller.step(start, Some(&params));
+        let deadline = step.deadline.unwrap();
+
+        let preview = controller.preview(deadline, Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Systole)
+        );
+        assert_eq!(preview.len(), 2);
+        assert_eq!(controller.step(deadline, Some(&params)).events, preview);
+    }
+
+    #[test]
+    fn test_setpoint_applied_at_next_beat() {
+        let mut controller = HeartController::new();
+        controller.set_transition(SetpointTransition {
+            timing: SetpointTiming::NextBeat,
+            ..SetpointTransition::default()
+        });
+        let start = Instant::from_secs(1);
+        let at = |ms| start + Duration::from_millis(ms);
+
+        controller.step(start, Some(&parameters(60.0, 0.25, 1.0)));
+
+        // A faster setpoint mid systole does not cut the beat short
+        let faster = parameters(120.0, 0.25, 1.5);
+        let step = controller.step(at(125), Some(&faster));
+        assert_eq!(step.commands.regulator_pressure, Pressure::new::<bar>(1.0));
+        assert_eq!(step.deadline, Some(at(250)));
+        let step = controller.step(at(250), Some(&faster));
+        assert_eq!(step.deadline, Some(at(1000)));
+
+        // The next beat runs at the new setpoint
+        let step = controller.step(at(1000), Some(&faster));
+        assert_eq!(controller.parameters(), Some(faster));
+        assert_eq!(step.commands.regulator_pressure, Pressure::new::<bar>(1.5));
+        assert_eq!(step.deadline, Some(at(1125)));
+    }
+
+    #[test]
+    fn test_setpoint_slew_limited_beat_by_beat() {
+        const HEART_RATE_SLEW_HZ: f32 = 0.5;
+        const PRESSURE_SLEW_BAR: f32 = 0.25;
+
+        let mut controller = HeartController::new();
+        controller.set_transition(SetpointTransition {
+            timing: SetpointTiming::NextBeat,
+            max_heart_rate_slew: Some(Frequency::new::<hertz>(HEART_RATE_SLEW_HZ)),
+            max_pressure_slew: Some(Pressure::new::<bar>(PRESSURE_SLEW_BAR)),
+        });
+        let start = Instant::from_secs(1);
+        let target = parameters(120.0, 0.25, 1.5);
+
+        let mut step = controller.step(start, Some(&parameters(60.0, 0.25, 1.0)));
+        let mut previous = controller.parameters().unwrap();
+        let mut previous_epoch = start;
+        while let Some(deadline) = step.deadline {
+            step = controller.step(deadline, Some(&target));
+
+            let applied = controller.parameters().unwrap();
+            let epoch = controller.beat_epoch().unwrap();
+            if epoch != previous_epoch {
+                // Every beat ramps by at most the slew limit over the previous beat
+                let seconds = (epoch - previous_epoch).as_micros() as f32 / 1_000_000.0;
+                let heart_rate_step = (applied.heart_rate - previous.heart_rate).get::<hertz>();
+                let pressure_step = (applied.pressure - previous.pressure).get::<bar>();
+                assert!(
+                    heart_rate_step >= 0.0
+                        && heart_rate_step <= HEART_RATE_SLEW_HZ * seconds + 1e-6
+                );
+                assert!(
+                    pressure_step >= 0.0 && pressure_step <= PRESSURE_SLEW_BAR * seconds + 1e-6
+                );
+                assert_eq!(step.commands.regulator_pressure, applied.pressure);
+
+                previous = applied;
+                previous_epoch = epoch;
+            }
+            if applied == target {
+                break;
+            }
+            assert!(
+                deadline < start + Duration::from_secs(10),
+                "ramp never finished"
+            );
+        }
+
+        // Ramping 1 bar to 1.5 bar at 0.25 bar/s takes 2 seconds of beats
+        assert!(previous_epoch >= start + Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_stopped_heart_applies_setpoint_right_away() {
+        let mut controller = HeartController::new();
+        controller.set_transition(SetpointTransition {
+            timing: SetpointTiming::NextBeat,
+            max_heart_rate_slew: Some(Frequency::new::<hertz>(0.5)),
+            max_pressure_slew: None,
+        });
+        let start = Instant::from_secs(1);
+
+        controller.step(start, Some(&parameters(0.0, 0.25, 1.0)));
+
+        // There is no next beat to wait for
+        let beating = parameters(60.0, 0.25, 1.0);
+        let step = controller.step(start + Duration::from_secs(5), Some(&beating));
+        assert_eq!(controller.parameters(), Some(beating));
+        assert_eq!(
+            step.deadline,
+            Some(start + Duration::from_secs(5) + Duration::from_millis(250))
+        );
+    }
+
+    /// Run the controller against the mocked embassy-time driver like the embassy task would,
+    /// waking up late for every deadline, and check the beats stay on the ideal grid
+    // NOTE: this is the only test touching the global mock driver, tests run in parallel
+    #[test]
+    fn test_no_drift_over_thousands_of_beats() {
+        const BEATS: u64 = 10_000;
+        // 72 bpm has a period of 833.333...ms
+        const PERIOD_US: u64 = 833_333;
+
+        let driver = MockDriver::get();
+        driver.reset();
+        driver.advance(Duration::from_secs(1));
+
+        let mut controller = HeartController::new();
+        let params = parameters(72.0, 0.35, 1.0);
+        let start = Instant::now();
+
+        let mut step = controller.step(Instant::now(), Some(&params));
+        let mut max_latency = Duration::from_ticks(0);
+        let mut i: u64 = 0;
+        while u64::from(controller.timing().beats) <= BEATS {
+            // Deterministic scheduling latency between 0 and ~2ms
+            let latency = Duration::from_micros((i * 7919) % 2000);
+            max_latency = max_latency.max(latency);
+            i += 1;
+
+            let deadline = step.deadline.expect("beating heart always has a deadline");
+            driver.advance(deadline - Instant::now() + latency);
+            step = controller.step(Instant::now(), Some(&params));
+        }
+
+        // Beat N started exactly N periods after the first beat
+        assert_eq!(
+            controller.beat_epoch(),
+            Some(start + Duration::from_micros(BEATS * PERIOD_US))
+        );
+
+        let timing = controller.timing();
+        assert_eq!(timing.resyncs, 0);
+        assert_eq!(timing.drift, Duration::from_ticks(0));
+        assert_eq!(u64::from(timing.switches), 2 * BEATS);
+        assert_eq!(timing.max_jitter, max_latency);
+    }
+}
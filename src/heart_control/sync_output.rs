@@ -0,0 +1,356 @@
+//! Sync output for external instruments
+//! Marks selected phase switches of the heart controller with a pulse on a GPIO, so camera and DAQ
+//! recordings can be lined up with the beat timing. Phase switches are known ahead of time, so the
+//! [`SyncSchedule`] arms the pulse before its deadline and a hardware timer starts it.
+
+use embassy_time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use uom::si::{f32::Time, time::microsecond};
+
+use crate::heart_control::{beat::BeatEvent, error::SyncError, phase::Ventricle};
+
+/// Level of the sync output during a pulse, the output idles at the opposite level
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum SyncPolarity {
+    #[default]
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Sync output configuration
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SyncOutputConfig {
+    /// Ventricle whose phase switches are marked
+    pub ventricle: Ventricle,
+    /// Profile phases that start a pulse when switched to, bit `n` selects profile phase `n` of the
+    /// [`CycleProfile`](crate::heart_control::profile::CycleProfile), systole starts at bit 0
+    pub profile_phases: u8,
+    /// Duration of a pulse
+    pub width: Time,
+    pub polarity: SyncPolarity,
+}
+
+impl Default for SyncOutputConfig {
+    /// 1ms active high pulse at the start of every left ventricle systole
+    fn default() -> Self {
+        Self {
+            ventricle: Ventricle::Left,
+            profile_phases: 1 << 0,
+            width: Time::new::<microsecond>(1000.0),
+            polarity: SyncPolarity::ActiveHigh,
+        }
+    }
+}
+
+/// A single pulse on the sync output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SyncPulse {
+    /// Start of the pulse, the deadline of the phase switch it marks
+    pub at: Instant,
+    pub width: Duration,
+}
+
+impl SyncOutputConfig {
+    /// Pulse marking `event`, `None` if the phase switch is not selected
+    pub fn pulse(&self, event: &BeatEvent) -> Option<SyncPulse> {
+        let selected = event.ventricle == self.ventricle
+            && self
+                .profile_phases
+                .checked_shr(event.profile_phase.into())
+                .is_some_and(|phases| phases & 1 != 0);
+
+        selected.then(|| SyncPulse {
+            at: Instant::from_micros(event.timestamp),
+            // Always long enough for an instrument to notice
+            width: Duration::from_micros(self.width.get::<microsecond>().max(1.0) as u64),
+        })
+    }
+}
+
+/// Instruction for the sync output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SyncCommand {
+    /// Fire a pulse, replacing a pulse that has not started yet
+    Pulse(SyncPulse),
+    /// Drop the pulse that has not started yet
+    Cancel,
+}
+
+/// Pulses for the phase switches of the heart controller
+/// Every step the heart controller previews the phase switches at its next deadline, a selected
+/// switch is armed ahead so the timer starts the pulse on time. Switches that were not foreseen,
+/// like the first beat or an externally triggered one, are marked as soon as they happen.
+#[derive(Debug, Clone, Default)]
+pub struct SyncSchedule {
+    /// Pulse armed ahead of its phase switch
+    armed: Option<SyncPulse>,
+}
+
+impl SyncSchedule {
+    /// Commands for a step at `now` with phase switches `events`, `next` holds the deadline of the
+    /// step after it and the phase switches previewed there
+    pub fn update(
+        &mut self,
+        config: Option<&SyncOutputConfig>,
+        now: Instant,
+        events: &[BeatEvent],
+        next: Option<&[BeatEvent]>,
+    ) -> heapless::Vec<SyncCommand, 2> {
+        let mut commands = heapless::Vec::new();
+        let Some(config) = config else {
+            if self.armed.take().is_some() {
+                // Holds both commands
+                let _ = commands.push(SyncCommand::Cancel);
+            }
+            return commands;
+        };
+
+        // A pulse armed for a switch that is due has marked it already
+        let marked = self.armed.take_if(|armed| armed.at <= now).is_some();
+        if !marked && let Some(pulse) = events.iter().find_map(|event| config.pulse(event)) {
+            let _ = commands.push(SyncCommand::Pulse(pulse));
+            // The timer fires one pulse at a time, this one replaced the armed pulse
+            self.armed = None;
+        }
+
+        match next.and_then(|events| events.iter().find_map(|event| config.pulse(event))) {
+            Some(pulse) if self.armed != Some(pulse) => {
+                self.armed = Some(pulse);
+                let _ = commands.push(SyncCommand::Pulse(pulse));
+            }
+            Some(_) => {}
+            None => {
+                if self.armed.take().is_some() {
+                    let _ = commands.push(SyncCommand::Cancel);
+                }
+            }
+        }
+
+        commands
+    }
+}
+
+/// Largest value of the 16 bit sync timer counter
+const MAX_COUNT: u64 = u16::MAX as u64;
+
+/// Timer settings of a pulse, for a timer counting up from 0 in one-pulse mode with its output
+/// active from `start` up to and including `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct PulseTiming {
+    pub prescaler: u16,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PulseTiming {
+    /// Settings for `pulse` when the timer is started at `now`, with the finest prescaler that fits
+    /// the whole pulse in the counter
+    /// A pulse that is already due starts on the first tick
+    pub fn new(pulse: &SyncPulse, now: Instant, clock_hz: u32) -> Result<Self, SyncError> {
+        let to_clocks = |duration: Duration| duration.as_micros() * u64::from(clock_hz) / 1_000_000;
+        let delay = to_clocks(pulse.at.saturating_duration_since(now));
+        let width = to_clocks(pulse.width).max(1);
+
+        let divider = (delay + width).div_ceil(MAX_COUNT);
+        let prescaler = u16::try_from(divider - 1).map_err(|_| SyncError::TooLong)?;
+        let start = (delay / divider).max(1);
+        let end = start + (width / divider).max(1) - 1;
+
+        Ok(Self {
+            prescaler,
+            // Both fit the counter by the choice of the divider
+            start: start as u16,
+            end: end as u16,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heart_control::phase::CardiacPhase;
+
+    fn event(ventricle: Ventricle, phase: CardiacPhase, profile_phase: u8) -> BeatEvent {
+        BeatEvent {
+            beat: 1,
+            ventricle,
+            phase,
+            profile_phase,
+            timestamp: 0,
+            ended_phase_duration: None,
+        }
+    }
+
+    fn systole_at(ms: u64) -> BeatEvent {
+        BeatEvent {
+            timestamp: ms * 1000,
+            ..event(Ventricle::Left, CardiacPhase::Systole, 0)
+        }
+    }
+
+    fn pulse_at(ms: u64) -> SyncCommand {
+        SyncCommand::Pulse(SyncPulse {
+            at: Instant::from_millis(ms),
+            width: Duration::from_millis(1),
+        })
+    }
+
+    #[test]
+    fn test_default_marks_left_systole_start() {
+        let sync = SyncOutputConfig::default();
+
+        let pulse = sync.pulse(&event(Ventricle::Left, CardiacPhase::Systole, 0));
+        assert_eq!(
+            pulse,
+            Some(SyncPulse {
+                at: Instant::from_ticks(0),
+                width: Duration::from_millis(1),
+            })
+        );
+        assert_eq!(
+            sync.pulse(&event(Ventricle::Left, CardiacPhase::Diastole, 1)),
+            None
+        );
+        assert_eq!(
+            sync.pulse(&event(Ventricle::Right, CardiacPhase::Systole, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_selected_profile_phases() {
+        let sync = SyncOutputConfig {
+            ventricle: Ventricle::Right,
+            profile_phases: (1 << 1) | (1 << 3),
+            width: Time::new::<microsecond>(0.0),
+            polarity: SyncPolarity::ActiveLow,
+        };
+
+        let pulse = |profile_phase| {
+            sync.pulse(&event(
+                Ventricle::Right,
+                CardiacPhase::Diastole,
+                profile_phase,
+            ))
+        };
+        assert_eq!(pulse(0), None);
+        assert_eq!(
+            pulse(1).map(|pulse| pulse.width),
+            Some(Duration::from_micros(1))
+        );
+        assert_eq!(pulse(2), None);
+        assert!(pulse(3).is_some());
+        // Beyond the mask
+        assert_eq!(pulse(8), None);
+    }
+
+    #[test]
+    fn test_switches_armed_ahead() {
+        let sync = SyncOutputConfig::default();
+        let mut schedule = SyncSchedule::default();
+        let diastole = BeatEvent {
+            timestamp: 250_000,
+            ..event(Ventricle::Left, CardiacPhase::Diastole, 1)
+        };
+
+        // The first beat is only known once it starts, the next one is armed ahead
+        assert_eq!(
+            schedule.update(
+                Some(&sync),
+                Instant::from_millis(0),
+                &[systole_at(0)],
+                Some(&[diastole])
+            ),
+            [pulse_at(0)]
+        );
+        assert_eq!(
+            schedule.update(
+                Some(&sync),
+                Instant::from_millis(250),
+                &[diastole],
+                Some(&[systole_at(1000)])
+            ),
+            [pulse_at(1000)]
+        );
+        // Waking up early does not arm the same pulse again
+        assert_eq!(
+            schedule.update(
+                Some(&sync),
+                Instant::from_millis(500),
+                &[],
+                Some(&[systole_at(1000)])
+            ),
+            []
+        );
+        // The armed pulse marked the switch, even when waking up late
+        assert_eq!(
+            schedule.update(
+                Some(&sync),
+                Instant::from_millis(1001),
+                &[systole_at(1001)],
+                Some(&[diastole])
+            ),
+            []
+        );
+    }
+
+    #[test]
+    fn test_rescheduled_switch_rearmed() {
+        let sync = SyncOutputConfig::default();
+        let mut schedule = SyncSchedule::default();
+        let now = Instant::from_millis(250);
+
+        schedule.update(Some(&sync), now, &[], Some(&[systole_at(1000)]));
+
+        // A new setpoint moves the next beat
+        assert_eq!(
+            schedule.update(Some(&sync), now, &[], Some(&[systole_at(800)])),
+            [pulse_at(800)]
+        );
+        // Stopping the heart, or the sync output, drops the pulse
+        assert_eq!(
+            schedule.update(Some(&sync), now, &[], None),
+            [SyncCommand::Cancel]
+        );
+        schedule.update(Some(&sync), now, &[], Some(&[systole_at(800)]));
+        assert_eq!(
+            schedule.update(None, now, &[], Some(&[systole_at(800)])),
+            [SyncCommand::Cancel]
+        );
+    }
+
+    #[test]
+    fn test_pulse_timing() {
+        let now = Instant::from_secs(1);
+        let pulse = |delay_us, width_us| SyncPulse {
+            at: now + Duration::from_micros(delay_us),
+            width: Duration::from_micros(width_us),
+        };
+
+        // 1MHz timer clock: 1us ticks while the pulse fits
+        assert_eq!(
+            PulseTiming::new(&pulse(1000, 500), now, 1_000_000),
+            Ok(PulseTiming {
+                prescaler: 0,
+                start: 1000,
+                end: 1499,
+            })
+        );
+        // A pulse that is due starts right away
+        assert_eq!(
+            PulseTiming::new(&pulse(0, 1000), now + Duration::from_millis(2), 1_000_000)
+                .map(|timing| timing.start),
+            Ok(1)
+        );
+        // Longer pulses scale the prescaler rather than being cut short
+        let timing = PulseTiming::new(&pulse(100_000, 100_000), now, 1_000_000).unwrap();
+        assert_eq!(timing.prescaler, 3);
+        assert_eq!(timing.start, 25_000);
+        assert_eq!(timing.end, 49_999);
+        // Until not even the largest prescaler fits
+        assert_eq!(
+            PulseTiming::new(&pulse(0, 30_000_000), now, 170_000_000),
+            Err(SyncError::TooLong)
+        );
+    }
+}
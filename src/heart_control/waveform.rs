@@ -0,0 +1,230 @@
+//! Driveline pressure waveforms
+//! Shapes the pressure regulator setpoint over the course of systole, so we can study how the
+//! actuator fills
+
+use embassy_time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use uom::si::f32::Pressure;
+
+/// Rate at which a shaped waveform updates the pressure regulator setpoint during systole
+pub const WAVEFORM_UPDATE_PERIOD: Duration = Duration::from_millis(5);
+
+/// Maximum number of points in a user supplied [`PressureWaveform::LookupTable`]
+pub const WAVEFORM_TABLE_LEN: usize = 32;
+
+/// Shape of the pressure regulator setpoint during systole
+/// Every waveform yields a fraction of the heart pressure setpoint, outside of systole the
+/// waveform holds its starting level
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum PressureWaveform {
+    /// Setpoint pressure for the whole cycle
+    #[default]
+    Square,
+    /// Linear rise from zero to the setpoint over systole
+    RampUp,
+    /// Linear rise, plateau at the setpoint and linear fall
+    Trapezoid {
+        /// Fraction of systole spent rising
+        rise: f32,
+        /// Fraction of systole spent falling
+        fall: f32,
+    },
+    /// Half a sine period over systole, peaking at the setpoint halfway through
+    HalfSine,
+    /// User supplied fractions of the setpoint, equally spaced over systole and linearly
+    /// interpolated
+    LookupTable(heapless::Vec<f32, WAVEFORM_TABLE_LEN>),
+}
+
+impl PressureWaveform {
+    /// Whether this waveform changes during systole and thus needs periodic updates
+    pub fn is_shaped(&self) -> bool {
+        !matches!(self, PressureWaveform::Square)
+    }
+
+    /// Fraction of the setpoint pressure at `progress` through systole, between 0 and 1
+    pub fn sample(&self, progress: f32) -> f32 {
+        let progress = progress.clamp(0.0, 1.0);
+
+        let fraction = match self {
+            PressureWaveform::Square => 1.0,
+            PressureWaveform::RampUp => progress,
+            PressureWaveform::Trapezoid { rise, fall } => {
+                if progress < *rise {
+                    progress / rise
+                } else if progress > 1.0 - fall {
+                    (1.0 - progress) / fall
+                } else {
+                    1.0
+                }
+            }
+            PressureWaveform::HalfSine => half_sine(progress),
+            PressureWaveform::LookupTable(table) => interpolate(table, progress),
+        };
+
+        fraction.clamp(0.0, 1.0)
+    }
+
+    /// Regulator setpoint at `now` for a systole from `start` until `end` at `pressure`, and the
+    /// moment of the next waveform update if one is due during that systole
+    pub fn regulator_pressure(
+        &self,
+        pressure: Pressure,
+        start: Instant,
+        end: Instant,
+        now: Instant,
+    ) -> (Pressure, Option<Instant>) {
+        if !self.is_shaped() || now < start || now >= end {
+            return (pressure * self.sample(0.0), None);
+        }
+
+        let elapsed = now - start;
+        let progress = elapsed.as_micros() as f32 / (end - start).as_micros() as f32;
+
+        // Updates are aligned to the start of systole so late wake-ups do not shift them either
+        let updates = elapsed.as_ticks() / WAVEFORM_UPDATE_PERIOD.as_ticks() + 1;
+        let next_update = start + Duration::from_ticks(WAVEFORM_UPDATE_PERIOD.as_ticks() * updates);
+
+        (pressure * self.sample(progress), Some(next_update))
+    }
+}
+
+/// sin(pi * x) for x in [0, 1], using Bhaskara I's approximation
+/// Accurate to within 0.2% of full scale, plenty for a regulator setpoint and no libm needed
+fn half_sine(x: f32) -> f32 {
+    let p = x * (1.0 - x);
+
+    16.0 * p / (5.0 - 4.0 * p)
+}
+
+/// Linear interpolation in a table of equally spaced points spanning [0, 1]
+fn interpolate(table: &[f32], x: f32) -> f32 {
+    match table {
+        [] => 0.0,
+        [only] => *only,
+        _ => {
+            let position = x * (table.len() - 1) as f32;
+            let index = (position as usize).min(table.len() - 2);
+            let fraction = position - index as f32;
+
+            table[index] + (table[index + 1] - table[index]) * fraction
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uom::si::pressure::bar;
+
+    use crate::heart_control::{
+        phase::{CardiacPhase, VentriclePhases},
+        state_machine::{
+            HeartController,
+            tests::{dyssynchronous, parameters},
+        },
+    };
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.002,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn test_square_and_ramp() {
+        assert_eq!(PressureWaveform::Square.sample(0.0), 1.0);
+        assert_eq!(PressureWaveform::Square.sample(0.7), 1.0);
+
+        assert_eq!(PressureWaveform::RampUp.sample(0.0), 0.0);
+        assert_eq!(PressureWaveform::RampUp.sample(0.5), 0.5);
+        assert_eq!(PressureWaveform::RampUp.sample(1.0), 1.0);
+    }
+
+    #[test]
+    fn test_trapezoid() {
+        let waveform = PressureWaveform::Trapezoid {
+            rise: 0.25,
+            fall: 0.5,
+        };
+
+        assert_eq!(waveform.sample(0.0), 0.0);
+        assert_eq!(waveform.sample(0.125), 0.5);
+        assert_eq!(waveform.sample(0.25), 1.0);
+        assert_eq!(waveform.sample(0.5), 1.0);
+        assert_eq!(waveform.sample(0.75), 0.5);
+        assert_eq!(waveform.sample(1.0), 0.0);
+    }
+
+    #[test]
+    fn test_half_sine() {
+        let waveform = PressureWaveform::HalfSine;
+
+        assert_close(waveform.sample(0.0), 0.0);
+        assert_close(waveform.sample(1.0 / 6.0), 0.5);
+        assert_close(waveform.sample(0.25), core::f32::consts::FRAC_1_SQRT_2);
+        assert_close(waveform.sample(0.5), 1.0);
+        assert_close(waveform.sample(1.0), 0.0);
+    }
+
+    #[test]
+    fn test_lookup_table() {
+        let table = heapless::Vec::from_slice(&[0.0, 1.0, 0.5]).unwrap();
+        let waveform = PressureWaveform::LookupTable(table);
+
+        assert_eq!(waveform.sample(0.0), 0.0);
+        assert_eq!(waveform.sample(0.25), 0.5);
+        assert_eq!(waveform.sample(0.5), 1.0);
+        assert_eq!(waveform.sample(0.75), 0.75);
+        assert_eq!(waveform.sample(1.0), 0.5);
+
+        // Out of range entries are clamped to the setpoint
+        let table = heapless::Vec::from_slice(&[2.0]).unwrap();
+        assert_eq!(PressureWaveform::LookupTable(table).sample(0.3), 1.0);
+    }
+
+    #[test]
+    fn test_ramp_waveform() {
+        let mut controller = HeartController::new();
+        controller.set_waveform(PressureWaveform::RampUp);
+        let params = parameters(60.0, 0.25, 1.0);
+        let start = Instant::from_secs(1);
+
+        // Systole starts at zero pressure and wakes up for the next waveform update
+        let step = controller.step(start, Some(&params));
+        assert_eq!(step.commands.regulator_pressure, Pressure::new::<bar>(0.0));
+        assert_eq!(step.deadline, Some(start + WAVEFORM_UPDATE_PERIOD));
+
+        // Halfway through systole
+        let step = controller.step(start + Duration::from_millis(125), Some(&params));
+        assert_eq!(step.commands.regulator_pressure, Pressure::new::<bar>(0.5));
+        assert_eq!(
+            step.deadline,
+            Some(start + Duration::from_millis(125) + WAVEFORM_UPDATE_PERIOD)
+        );
+        assert_eq!(controller.timing().switches, 0);
+
+        // Diastole holds the starting level until the next systole
+        let step = controller.step(start + Duration::from_millis(250), Some(&params));
+        assert_eq!(
+            controller.phases(),
+            VentriclePhases::both(CardiacPhase::Diastole)
+        );
+        assert_eq!(step.commands.regulator_pressure, Pressure::new::<bar>(0.0));
+        assert_eq!(step.deadline, Some(start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_waveform_spans_both_ventricles() {
+        let mut controller = HeartController::new();
+        controller.set_waveform(PressureWaveform::RampUp);
+        // Left systole 0-250ms, right systole 250-500ms
+        let params = dyssynchronous(0.25, 0.25, 250.0);
+        let start = Instant::from_secs(1);
+
+        controller.step(start, Some(&params));
+        let step = controller.step(start + Duration::from_millis(250), Some(&params));
+        assert_eq!(step.commands.regulator_pressure, Pressure::new::<bar>(0.5));
+    }
+}
@@ -0,0 +1,65 @@
+//! Pneumatic heart and mockloop control
+//!
+//! The control logic also builds for the host, where `just test` runs its unit tests. The
+//! embassy tasks and everything else touching the hardware only build for the firmware target.
+
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(target_os = "none")]
+pub mod adc_task;
+#[cfg(target_os = "none")]
+pub mod button_task;
+#[cfg(target_os = "none")]
+pub mod chamber_valve_task;
+pub mod comms;
+pub mod dac;
+#[cfg(target_os = "none")]
+pub mod framing_task;
+#[cfg(target_os = "none")]
+pub mod hal;
+pub mod heart_control;
+#[cfg(target_os = "none")]
+pub mod led_task;
+pub mod loop_control;
+#[cfg(target_os = "none")]
+pub mod pinch_valve_task;
+#[cfg(target_os = "none")]
+pub mod reporting_task;
+#[cfg(target_os = "none")]
+pub mod sync_output_task;
+#[cfg(target_os = "none")]
+pub mod trigger_task;
+#[cfg(target_os = "none")]
+pub mod valve_task;
+
+#[cfg(target_os = "none")]
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, watch::Watch};
+#[cfg(target_os = "none")]
+use love_letter::AppState;
+
+/// Application state, shown on the status LED and reported to the host
+#[cfg(target_os = "none")]
+pub static APPSTATE_WATCH: Watch<Cs, AppState, 1> = Watch::new();
+
+/// The host tests have no defmt transport and no executor. Log output is dropped, a defmt panic
+/// fails the test like any other panic and the timer queue has no executor to wake
+#[cfg(test)]
+mod host {
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    #[defmt::panic_handler]
+    fn panic() -> ! {
+        panic!("defmt panic")
+    }
+
+    #[unsafe(no_mangle)]
+    fn __pender(_context: *mut ()) {}
+}
@@ -0,0 +1,135 @@
+//! Beat by beat estimation from the mockloop measurements
+//! The circuit resistance and compliance are estimated over whole beats of the heart controller.
+//! [`BeatAverager`] cuts the measurements into beats, the [`BeatSamples`] of a quantity reduce the
+//! samples of one beat to an estimate.
+
+/// Samples of a single beat, reduced to an estimate once the beat is complete
+pub trait BeatSamples: Default {
+    /// A single measurement
+    type Sample;
+    type Estimate;
+
+    /// Forget the samples of the previous beat
+    fn clear(&mut self);
+
+    fn add(&mut self, sample: Self::Sample);
+
+    /// Estimate of `beat` from the samples added since the last [`BeatSamples::clear`], `None`
+    /// without samples
+    fn estimate(&self, beat: u32) -> Option<Self::Estimate>;
+}
+
+/// Collects measurements per beat of the heart controller, and estimates each complete beat
+/// The beat the estimation starts or restarts in is incomplete and never estimated
+#[derive(Debug, Clone, Default)]
+pub struct BeatAverager<S> {
+    beat: Option<u32>,
+    /// Whether the current beat started before the estimation did
+    partial: bool,
+    samples: S,
+}
+
+impl<S: BeatSamples> BeatAverager<S> {
+    /// Disregard the beat in progress, for instance after moving an actuator
+    pub fn restart(&mut self) {
+        self.partial = true;
+    }
+
+    /// Add a `sample` taken during `beat`, `None` while the heart is not beating
+    /// Returns the estimate of the previous beat once a new one starts
+    pub fn update(&mut self, beat: Option<u32>, sample: S::Sample) -> Option<S::Estimate> {
+        let mut completed = None;
+
+        if beat != self.beat {
+            // A beat cut short by stopping the heart is not estimated either
+            if let Some(previous) = self.beat
+                && beat.is_some()
+                && !self.partial
+            {
+                completed = self.samples.estimate(previous);
+            }
+
+            // Joining a beat halfway makes it partial
+            self.partial = self.beat.is_none();
+            self.beat = beat;
+            self.samples.clear();
+        }
+
+        if self.beat.is_some() {
+            self.samples.add(sample);
+        }
+
+        completed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mean of the samples of a beat
+    #[derive(Debug, Default)]
+    struct Mean {
+        sum: f32,
+        samples: u32,
+    }
+
+    impl BeatSamples for Mean {
+        type Sample = f32;
+        type Estimate = (u32, f32);
+
+        fn clear(&mut self) {
+            *self = Self::default();
+        }
+
+        fn add(&mut self, sample: f32) {
+            self.sum += sample;
+            self.samples += 1;
+        }
+
+        fn estimate(&self, beat: u32) -> Option<(u32, f32)> {
+            (self.samples > 0).then(|| (beat, self.sum / self.samples as f32))
+        }
+    }
+
+    #[test]
+    fn test_complete_beats() {
+        let mut averager = BeatAverager::<Mean>::default();
+
+        // Joining halfway through beat 1
+        assert_eq!(averager.update(Some(1), 10.0), None);
+
+        assert_eq!(averager.update(Some(2), 80.0), None);
+        assert_eq!(averager.update(Some(2), 120.0), None);
+        assert_eq!(averager.update(Some(3), 80.0), Some((2, 100.0)));
+        assert_eq!(averager.update(Some(4), 60.0), Some((3, 80.0)));
+    }
+
+    #[test]
+    fn test_restart_drops_beat() {
+        let mut averager = BeatAverager::<Mean>::default();
+
+        averager.update(Some(1), 10.0);
+        averager.update(Some(2), 10.0);
+
+        // Moving an actuator spoils beat 2
+        averager.restart();
+        assert_eq!(averager.update(Some(3), 20.0), None);
+        assert_eq!(averager.update(Some(4), 30.0), Some((3, 20.0)));
+    }
+
+    #[test]
+    fn test_stopping_drops_beat() {
+        let mut averager = BeatAverager::<Mean>::default();
+
+        averager.update(Some(1), 10.0);
+        averager.update(Some(2), 10.0);
+
+        // Stopping the heart drops the beat in progress, the next start is partial again
+        assert_eq!(averager.update(None, 0.0), None);
+        assert_eq!(averager.update(None, 0.0), None);
+        assert_eq!(averager.update(Some(1), 10.0), None);
+        assert_eq!(averager.update(Some(2), 10.0), None);
+        assert_eq!(averager.update(Some(3), 10.0), Some((2, 10.0)));
+    }
+}
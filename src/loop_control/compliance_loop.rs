@@ -0,0 +1,324 @@
+//! Closed-loop compliance regulation
+//! The chamber model leaves out the compliance of the tubing and the fluid level drifting with the
+//! pressure. The effective compliance of a circuit is estimated each beat from its stroke volume
+//! and afterload pulse pressure, and the regulator pressure is trimmed until it matches the
+//! setpoint.
+
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    f32::{Pressure, Volume, VolumeRate},
+    pressure::{bar, millimeter_of_mercury},
+    volume::milliliter,
+    volume_rate::milliliter_per_second,
+};
+
+use crate::{
+    dac::setpoint::RegulatorSetpoint,
+    loop_control::{
+        beat_average::{BeatAverager, BeatSamples},
+        config::Circuit,
+        setpoint::compliance::{ComplianceChamber, ComplianceSetpoint},
+    },
+};
+
+/// Closed-loop compliance control configuration
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ComplianceLoopConfig {
+    /// Fraction of the regulator pressure error corrected each beat
+    pub gain: f32,
+    /// Largest correction of the modelled regulator pressure
+    pub max_trim: Pressure,
+    /// Beats with a smaller afterload pulse pressure do not tell the compliance apart and are not
+    /// acted upon
+    pub min_pulse_pressure: Pressure,
+}
+
+impl Default for ComplianceLoopConfig {
+    fn default() -> Self {
+        Self {
+            gain: 0.3,
+            max_trim: Pressure::new::<bar>(0.5),
+            min_pulse_pressure: Pressure::new::<millimeter_of_mercury>(5.0),
+        }
+    }
+}
+
+/// Stroke volume and afterload pulse pressure of a circuit over a beat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ComplianceEstimate {
+    /// Beat the estimate belongs to, as counted by the heart controller
+    pub beat: u32,
+    /// Highest minus lowest afterload pressure
+    pub pulse_pressure: Pressure,
+    /// Flow integrated over the beat
+    pub stroke_volume: Volume,
+}
+
+impl ComplianceEstimate {
+    /// Effective compliance in mL/mmHg, not finite without a pulse
+    pub fn compliance(&self) -> f32 {
+        self.stroke_volume.get::<milliliter>() / self.pulse_pressure.get::<millimeter_of_mercury>()
+    }
+}
+
+/// Afterload pressure range and integrated flow of a circuit over a beat
+#[derive(Debug, Clone, Default)]
+pub struct ComplianceSamples {
+    /// Lowest and highest afterload pressure in mmHg
+    pressure_range: Option<(f32, f32)>,
+    /// Integrated flow in mL
+    stroke_volume: f32,
+    /// Timestamp in µs and flow in mL/s of the previous sample, which may be in the previous beat
+    previous: Option<(u64, f32)>,
+}
+
+impl BeatSamples for ComplianceSamples {
+    /// Afterload pressure, flow, and the timestamp in µs they were measured at
+    type Sample = (Pressure, VolumeRate, u64);
+    type Estimate = ComplianceEstimate;
+
+    fn clear(&mut self) {
+        self.pressure_range = None;
+        self.stroke_volume = 0.0;
+    }
+
+    fn add(&mut self, (afterload_pressure, flow, timestamp): (Pressure, VolumeRate, u64)) {
+        let pressure = afterload_pressure.get::<millimeter_of_mercury>();
+        let flow = flow.get::<milliliter_per_second>();
+
+        self.pressure_range = Some(match self.pressure_range {
+            Some((min, max)) => (min.min(pressure), max.max(pressure)),
+            None => (pressure, pressure),
+        });
+
+        // Trapezoid from the previous sample
+        if let Some((previous_timestamp, previous_flow)) = self.previous {
+            let dt = timestamp.saturating_sub(previous_timestamp) as f32 / 1_000_000.0;
+            self.stroke_volume += (previous_flow + flow) / 2.0 * dt;
+        }
+        self.previous = Some((timestamp, flow));
+    }
+
+    fn estimate(&self, beat: u32) -> Option<ComplianceEstimate> {
+        let (min, max) = self.pressure_range?;
+
+        Some(ComplianceEstimate {
+            beat,
+            pulse_pressure: Pressure::new::<millimeter_of_mercury>(max - min),
+            stroke_volume: Volume::new::<milliliter>(self.stroke_volume),
+        })
+    }
+}
+
+/// Integrates the flow and tracks the afterload pressure range of a circuit over each beat
+pub type ComplianceEstimator = BeatAverager<ComplianceSamples>;
+
+/// Compliance of a circuit for the host, once per beat
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct ComplianceStatus {
+    pub circuit: Circuit,
+    pub beat: u32,
+    /// Requested compliance in mL/mmHg
+    pub setpoint: f32,
+    /// Estimated compliance in mL/mmHg
+    pub measured: f32,
+    /// Correction of the modelled regulator pressure in bar, 0 while controlling open-loop
+    pub trim: f32,
+}
+
+/// Trims the modelled compliance chamber pressure of a circuit on its estimated compliance
+/// The error is taken in regulator pressure, through the chamber model, as the compliance falls
+/// off with the inverse of the absolute pressure
+#[derive(Debug, Clone)]
+pub struct ComplianceLoop {
+    config: ComplianceLoopConfig,
+    /// Correction of the modelled regulator pressure
+    trim: Pressure,
+}
+
+impl ComplianceLoop {
+    pub fn new(config: ComplianceLoopConfig) -> Self {
+        Self {
+            config,
+            trim: Pressure::new::<bar>(0.0),
+        }
+    }
+
+    /// Change the gains, the current correction is kept
+    pub fn set_config(&mut self, config: ComplianceLoopConfig) {
+        self.config = config;
+        self.trim = self
+            .trim
+            .max(-config.max_trim.abs())
+            .min(config.max_trim.abs());
+    }
+
+    pub fn trim(&self) -> Pressure {
+        self.trim
+    }
+
+    /// Correct the regulator pressure with the `estimate` of a beat at a `setpoint` in mL/mmHg
+    pub fn update(
+        &mut self,
+        estimate: &ComplianceEstimate,
+        setpoint: f32,
+        chamber: &ComplianceChamber,
+    ) {
+        let measured = estimate.compliance();
+        if estimate.pulse_pressure < self.config.min_pulse_pressure
+            || !measured.is_finite()
+            || measured <= 0.0
+            || setpoint <= 0.0
+        {
+            return;
+        }
+
+        // A circuit more compliant than requested maps to a lower pressure: pressurise further
+        let error = chamber.pressure_for(setpoint) - chamber.pressure_for(measured);
+        let max_trim = self.config.max_trim.abs();
+
+        self.trim = (self.trim + error * self.config.gain)
+            .max(-max_trim)
+            .min(max_trim);
+    }
+
+    /// `setpoint` with the regulator pressure corrected, within reach of the regulator
+    pub fn trimmed(&self, setpoint: ComplianceSetpoint) -> ComplianceSetpoint {
+        let pressure = (setpoint.pressure + self.trim).get::<bar>().clamp(
+            RegulatorSetpoint::REGULATOR_MIN_PRESSURE_BAR,
+            RegulatorSetpoint::REGULATOR_MAX_PRESSURE_BAR,
+        );
+
+        ComplianceSetpoint {
+            pressure: Pressure::new::<bar>(pressure),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mmhg(pressure: f32) -> Pressure {
+        Pressure::new::<millimeter_of_mercury>(pressure)
+    }
+
+    fn ml_per_s(flow: f32) -> VolumeRate {
+        VolumeRate::new::<milliliter_per_second>(flow)
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() < tolerance,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn test_beat_estimate() {
+        let mut samples = ComplianceSamples::default();
+        assert_eq!(samples.estimate(2), None);
+
+        samples.add((mmhg(80.0), ml_per_s(0.0), 100_000));
+        samples.add((mmhg(120.0), ml_per_s(200.0), 200_000));
+        samples.add((mmhg(100.0), ml_per_s(0.0), 300_000));
+        let estimate = samples.estimate(2).unwrap();
+        assert_eq!(estimate.beat, 2);
+        // 2 trapezoids of 200mL/s / 2 * 0.1s
+        assert_close(estimate.stroke_volume.get::<milliliter>(), 20.0, 0.001);
+        assert_close(
+            estimate.pulse_pressure.get::<millimeter_of_mercury>(),
+            40.0,
+            0.001,
+        );
+        assert_close(estimate.compliance(), 0.5, 0.0001);
+    }
+
+    #[test]
+    fn test_stroke_volume_spans_beats() {
+        let mut samples = ComplianceSamples::default();
+
+        samples.add((mmhg(80.0), ml_per_s(100.0), 100_000));
+        // The interval from the last sample of a beat counts towards the next one
+        samples.clear();
+        samples.add((mmhg(80.0), ml_per_s(100.0), 200_000));
+        let estimate = samples.estimate(3).unwrap();
+        assert_close(estimate.stroke_volume.get::<milliliter>(), 10.0, 0.001);
+        assert_eq!(estimate.pulse_pressure.get::<millimeter_of_mercury>(), 0.0);
+    }
+
+    #[test]
+    fn test_trim_towards_setpoint() {
+        let chamber = ComplianceChamber::default();
+        let mut compliance_loop = ComplianceLoop::new(ComplianceLoopConfig {
+            gain: 0.5,
+            max_trim: Pressure::new::<bar>(1.0),
+            ..Default::default()
+        });
+        let estimate = |compliance: f32| ComplianceEstimate {
+            beat: 1,
+            pulse_pressure: mmhg(40.0),
+            stroke_volume: Volume::new::<milliliter>(compliance * 40.0),
+        };
+
+        // Measured more compliant: 0.2 maps to 0.11763bar against 1.2485bar for a setpoint of 0.1
+        compliance_loop.update(&estimate(0.2), 0.1, &chamber);
+        assert_close(compliance_loop.trim().get::<bar>(), 0.56544, 0.0001);
+        assert_close(
+            compliance_loop
+                .trimmed(ComplianceSetpoint::from_raw_compliance(0.1, &chamber))
+                .pressure
+                .get::<bar>(),
+            1.81394,
+            0.0001,
+        );
+
+        // On target
+        compliance_loop.update(&estimate(0.1), 0.1, &chamber);
+        assert_close(compliance_loop.trim().get::<bar>(), 0.56544, 0.0001);
+    }
+
+    #[test]
+    fn test_trim_limits() {
+        let chamber = ComplianceChamber::default();
+        let mut compliance_loop = ComplianceLoop::new(ComplianceLoopConfig {
+            gain: 1.0,
+            max_trim: Pressure::new::<bar>(0.25),
+            min_pulse_pressure: mmhg(5.0),
+        });
+
+        // Barely any pulse
+        compliance_loop.update(
+            &ComplianceEstimate {
+                beat: 1,
+                pulse_pressure: mmhg(1.0),
+                stroke_volume: Volume::new::<milliliter>(0.2),
+            },
+            0.1,
+            &chamber,
+        );
+        assert_eq!(compliance_loop.trim().get::<bar>(), 0.0);
+
+        // Far too stiff
+        compliance_loop.update(
+            &ComplianceEstimate {
+                beat: 2,
+                pulse_pressure: mmhg(100.0),
+                stroke_volume: Volume::new::<milliliter>(5.0),
+            },
+            0.1,
+            &chamber,
+        );
+        assert_close(compliance_loop.trim().get::<bar>(), -0.25, 0.0001);
+        // Never below atmospheric pressure
+        assert_eq!(
+            compliance_loop
+                .trimmed(ComplianceSetpoint {
+                    pressure: Pressure::new::<bar>(0.1)
+                })
+                .pressure
+                .get::<bar>(),
+            0.0
+        );
+    }
+}
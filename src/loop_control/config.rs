@@ -0,0 +1,40 @@
+//! Mockloop configuration that is not part of the love-letter [`love_letter::Setpoint`]
+//! These describe the mockloop hardware, they default to the mockloop as built
+
+use serde::{Deserialize, Serialize};
+
+use crate::loop_control::{
+    compliance_loop::ComplianceLoopConfig,
+    fill::FillConfig,
+    resistance_loop::ResistanceLoopConfig,
+    sensor::SensorCalibrations,
+    setpoint::{compliance::ComplianceChamber, resistance::ResistanceCalibration},
+};
+
+/// Circuits of the mockloop, each with its own chambers, valves and sensors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum Circuit {
+    Systemic,
+    Pulmonary,
+}
+
+/// Firmware side mockloop configuration
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LoopConfig {
+    /// Calibration of the fluid pressure and flow sensors
+    pub sensors: SensorCalibrations,
+    /// Compliance chamber of the systemic afterload
+    pub systemic_chamber: ComplianceChamber,
+    /// Compliance chamber of the pulmonary afterload
+    pub pulmonary_chamber: ComplianceChamber,
+    /// Calibration of the systemic resistance pinch valve
+    pub systemic_resistance: ResistanceCalibration,
+    /// Calibration of the pulmonary resistance pinch valve
+    pub pulmonary_resistance: ResistanceCalibration,
+    /// Closed-loop resistance control of both circuits, `None` sets the calibrated valve opening
+    pub resistance_loop: Option<ResistanceLoopConfig>,
+    /// Closed-loop compliance control of both circuits, `None` sets the modelled chamber pressure
+    pub compliance_loop: Option<ComplianceLoopConfig>,
+    /// Chamber fill and drain sequencer
+    pub fill: FillConfig,
+}
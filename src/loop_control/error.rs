@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    thiserror::Error, Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize,
+)]
+pub enum PresetError {
+    #[error("No preset of that name is stored")]
+    UnknownPreset,
+    #[error("A preset of that name is stored with the same or a newer version")]
+    Outdated,
+    #[error("The preset setpoint does not pass the setpoint limits")]
+    InvalidSetpoint,
+    #[error("No room to store another preset")]
+    Full,
+}
+
+#[derive(
+    thiserror::Error, Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize,
+)]
+pub enum FillError {
+    #[error("Chambers are only filled or drained while the heart controller is disabled")]
+    HeartEnabled,
+    #[error("Another fill or drain sequence is running")]
+    Busy,
+    #[error("Fill level is not between the empty and the highest level")]
+    InvalidLevel,
+    #[error("The chamber level was not reached in time")]
+    Timeout,
+    #[error("The sequence was aborted by the host")]
+    Aborted,
+}
@@ -0,0 +1,498 @@
+//! Compliance chamber fill and drain automation
+//! Each chamber pair has a solenoid valve filling it from the reservoir and one draining it back.
+//! The [`FillSequencer`] carries out host [`FillCommand`]s on these valves against the measured
+//! fluid level, and never runs while the heart is beating.
+
+use embassy_time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use uom::si::{f32::Length, length::millimeter};
+
+use crate::{comms::message::duration_us, loop_control::error::FillError};
+
+/// Chamber pairs a [`FillCommand`] applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum Chambers {
+    Systemic,
+    Pulmonary,
+    All,
+}
+
+impl Chambers {
+    fn systemic(self) -> bool {
+        matches!(self, Chambers::Systemic | Chambers::All)
+    }
+
+    fn pulmonary(self) -> bool {
+        matches!(self, Chambers::Pulmonary | Chambers::All)
+    }
+}
+
+/// Host command for the chamber valves
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FillCommand {
+    /// Fill the chambers up to `level` of fluid
+    Fill { chambers: Chambers, level: Length },
+    /// Drain the chambers down to the empty level
+    Drain(Chambers),
+    /// Flush the chambers with both valves open, carrying trapped air off to the reservoir
+    Purge(Chambers),
+    /// Close all valves
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum FillOperation {
+    Fill,
+    Drain,
+    Purge,
+}
+
+/// State of the fill and drain sequencer, sent to the host on every change
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub enum FillStatus {
+    Idle,
+    Running {
+        operation: FillOperation,
+        chambers: Chambers,
+    },
+    Done {
+        operation: FillOperation,
+        chambers: Chambers,
+    },
+    /// A command that was not carried out
+    Refused(FillError),
+    /// A sequence stopped early, with all valves closed
+    Failed {
+        operation: FillOperation,
+        chambers: Chambers,
+        error: FillError,
+    },
+}
+
+/// Progress of a running sequence, sent to the host at every level check
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FillProgress {
+    pub operation: FillOperation,
+    pub chambers: Chambers,
+    /// Latest fluid levels, `None` without a level measurement
+    pub levels: Option<ChamberLevels>,
+    /// Time since the sequence started
+    #[serde(with = "duration_us")]
+    pub elapsed: Duration,
+}
+
+/// Fluid level in the chambers of each pair
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ChamberLevels {
+    pub systemic: Length,
+    pub pulmonary: Length,
+}
+
+/// Valves of one chamber pair, `true` is open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct PairValves {
+    pub fill: bool,
+    pub drain: bool,
+}
+
+/// Valves of both chamber pairs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct ChamberValves {
+    pub systemic: PairValves,
+    pub pulmonary: PairValves,
+}
+
+impl ChamberValves {
+    pub const CLOSED: Self = Self {
+        systemic: PairValves {
+            fill: false,
+            drain: false,
+        },
+        pulmonary: PairValves {
+            fill: false,
+            drain: false,
+        },
+    };
+}
+
+/// Fill and drain sequencer configuration
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FillConfig {
+    /// Highest fill level, leaving room for the air cushion
+    pub max_level: Length,
+    /// Level at or below which a chamber counts as drained
+    pub empty_level: Length,
+    /// Longest fill or drain before giving up on reaching the level
+    #[serde(with = "duration_us")]
+    pub timeout: Duration,
+    /// How long a purge keeps the valves open
+    #[serde(with = "duration_us")]
+    pub purge_time: Duration,
+}
+
+impl Default for FillConfig {
+    /// Sized for the 30mm high chambers as built
+    fn default() -> Self {
+        Self {
+            max_level: Length::new::<millimeter>(27.0),
+            empty_level: Length::new::<millimeter>(1.0),
+            timeout: Duration::from_secs(60),
+            purge_time: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Sequence in progress
+#[derive(Debug, Clone)]
+struct Sequence {
+    operation: FillOperation,
+    chambers: Chambers,
+    /// Fill level, unused while draining or purging
+    level: Length,
+    started: Instant,
+    /// Whether each pair still has to reach its level
+    systemic: bool,
+    pulmonary: bool,
+}
+
+/// Runs one [`FillCommand`] at a time on the chamber valves
+/// Each pair is closed as soon as it reaches its level, the sequence fails with all valves closed
+/// on a timeout or when the heart starts beating
+#[derive(Debug, Clone, Default)]
+pub struct FillSequencer {
+    sequence: Option<Sequence>,
+}
+
+impl FillSequencer {
+    pub fn is_running(&self) -> bool {
+        self.sequence.is_some()
+    }
+
+    /// Start or abort a sequence, commands other than abort are refused while `heart_beating` or
+    /// while another sequence runs
+    pub fn handle(
+        &mut self,
+        command: FillCommand,
+        heart_beating: bool,
+        config: &FillConfig,
+        now: Instant,
+    ) -> FillStatus {
+        let (operation, chambers, level) = match command {
+            FillCommand::Abort => {
+                return match self.sequence.take() {
+                    Some(sequence) => FillStatus::Failed {
+                        operation: sequence.operation,
+                        chambers: sequence.chambers,
+                        error: FillError::Aborted,
+                    },
+                    None => FillStatus::Idle,
+                };
+            }
+            FillCommand::Fill { chambers, level } => (FillOperation::Fill, chambers, level),
+            FillCommand::Drain(chambers) => (FillOperation::Drain, chambers, config.empty_level),
+            FillCommand::Purge(chambers) => (FillOperation::Purge, chambers, config.empty_level),
+        };
+
+        if heart_beating {
+            return FillStatus::Refused(FillError::HeartEnabled);
+        }
+        if self.sequence.is_some() {
+            return FillStatus::Refused(FillError::Busy);
+        }
+        // Filling below the empty level is draining, the NaN check is in the comparisons
+        if operation == FillOperation::Fill
+            && !(level > config.empty_level && level <= config.max_level)
+        {
+            return FillStatus::Refused(FillError::InvalidLevel);
+        }
+
+        self.sequence = Some(Sequence {
+            operation,
+            chambers,
+            level,
+            started: now,
+            systemic: chambers.systemic(),
+            pulmonary: chambers.pulmonary(),
+        });
+
+        FillStatus::Running {
+            operation,
+            chambers,
+        }
+    }
+
+    /// Close the pairs that reached their level, returns the status once the sequence ends
+    /// `levels` is `None` without a level measurement, no level is reached then
+    pub fn update(
+        &mut self,
+        levels: Option<ChamberLevels>,
+        heart_beating: bool,
+        config: &FillConfig,
+        now: Instant,
+    ) -> Option<FillStatus> {
+        let sequence = self.sequence.as_mut()?;
+        let elapsed = now.saturating_duration_since(sequence.started);
+
+        let (operation, target) = (sequence.operation, sequence.level);
+        let reached = |level: Length| match operation {
+            FillOperation::Fill => level >= target,
+            FillOperation::Drain => level <= target,
+            // A purge runs for its time regardless of the level
+            FillOperation::Purge => false,
+        };
+        if operation == FillOperation::Purge && elapsed >= config.purge_time {
+            sequence.systemic = false;
+            sequence.pulmonary = false;
+        } else if let Some(levels) = levels {
+            sequence.systemic &= !reached(levels.systemic);
+            sequence.pulmonary &= !reached(levels.pulmonary);
+        }
+
+        let status = if heart_beating {
+            FillStatus::Failed {
+                operation: sequence.operation,
+                chambers: sequence.chambers,
+                error: FillError::HeartEnabled,
+            }
+        } else if !sequence.systemic && !sequence.pulmonary {
+            FillStatus::Done {
+                operation: sequence.operation,
+                chambers: sequence.chambers,
+            }
+        } else if elapsed >= config.timeout {
+            FillStatus::Failed {
+                operation: sequence.operation,
+                chambers: sequence.chambers,
+                error: FillError::Timeout,
+            }
+        } else {
+            return None;
+        };
+
+        self.sequence = None;
+        Some(status)
+    }
+
+    /// Progress of the sequence in progress at `now`, with the latest `levels`
+    pub fn progress(&self, levels: Option<ChamberLevels>, now: Instant) -> Option<FillProgress> {
+        let sequence = self.sequence.as_ref()?;
+
+        Some(FillProgress {
+            operation: sequence.operation,
+            chambers: sequence.chambers,
+            levels,
+            elapsed: now.saturating_duration_since(sequence.started),
+        })
+    }
+
+    /// Valve positions of the sequence in progress, all closed otherwise
+    pub fn valves(&self) -> ChamberValves {
+        let Some(sequence) = &self.sequence else {
+            return ChamberValves::CLOSED;
+        };
+        let pair = |pending: bool| PairValves {
+            fill: pending && sequence.operation != FillOperation::Drain,
+            drain: pending && sequence.operation != FillOperation::Fill,
+        };
+
+        ChamberValves {
+            systemic: pair(sequence.systemic),
+            pulmonary: pair(sequence.pulmonary),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mm(level: f32) -> Length {
+        Length::new::<millimeter>(level)
+    }
+
+    fn levels(systemic: f32, pulmonary: f32) -> Option<ChamberLevels> {
+        Some(ChamberLevels {
+            systemic: mm(systemic),
+            pulmonary: mm(pulmonary),
+        })
+    }
+
+    fn at(seconds: u64) -> Instant {
+        Instant::from_secs(100 + seconds)
+    }
+
+    #[test]
+    fn test_fill_pairs_close_on_level() {
+        let config = FillConfig::default();
+        let mut sequencer = FillSequencer::default();
+
+        assert_eq!(
+            sequencer.handle(
+                FillCommand::Fill {
+                    chambers: Chambers::All,
+                    level: mm(15.0),
+                },
+                false,
+                &config,
+                at(0),
+            ),
+            FillStatus::Running {
+                operation: FillOperation::Fill,
+                chambers: Chambers::All
+            }
+        );
+        assert_eq!(
+            sequencer.handle(FillCommand::Drain(Chambers::All), false, &config, at(0)),
+            FillStatus::Refused(FillError::Busy)
+        );
+
+        let open = PairValves {
+            fill: true,
+            drain: false,
+        };
+        assert_eq!(
+            sequencer.valves(),
+            ChamberValves {
+                systemic: open,
+                pulmonary: open
+            }
+        );
+
+        assert_eq!(
+            sequencer.update(levels(15.5, 10.0), false, &config, at(5)),
+            None
+        );
+        assert_eq!(
+            sequencer.progress(levels(15.5, 10.0), at(5)),
+            Some(FillProgress {
+                operation: FillOperation::Fill,
+                chambers: Chambers::All,
+                levels: levels(15.5, 10.0),
+                elapsed: Duration::from_secs(5),
+            })
+        );
+        assert_eq!(
+            sequencer.valves(),
+            ChamberValves {
+                systemic: PairValves::default(),
+                pulmonary: open
+            }
+        );
+
+        // The systemic level dropping back does not reopen its valve
+        assert_eq!(
+            sequencer.update(levels(14.0, 15.0), false, &config, at(10)),
+            Some(FillStatus::Done {
+                operation: FillOperation::Fill,
+                chambers: Chambers::All
+            })
+        );
+        assert_eq!(sequencer.valves(), ChamberValves::CLOSED);
+        assert_eq!(sequencer.progress(levels(14.0, 15.0), at(10)), None);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let config = FillConfig::default();
+        let mut sequencer = FillSequencer::default();
+
+        sequencer.handle(
+            FillCommand::Drain(Chambers::Pulmonary),
+            false,
+            &config,
+            at(0),
+        );
+        assert_eq!(
+            sequencer.valves().pulmonary,
+            PairValves {
+                fill: false,
+                drain: true
+            }
+        );
+        assert_eq!(sequencer.valves().systemic, PairValves::default());
+
+        // Only the pulmonary pair has to be drained
+        assert_eq!(
+            sequencer.update(levels(10.0, 5.0), false, &config, at(30)),
+            None
+        );
+        assert_eq!(
+            sequencer.update(None, false, &config, at(60)),
+            Some(FillStatus::Failed {
+                operation: FillOperation::Drain,
+                chambers: Chambers::Pulmonary,
+                error: FillError::Timeout
+            })
+        );
+        assert!(!sequencer.is_running());
+    }
+
+    #[test]
+    fn test_interlocks() {
+        let config = FillConfig::default();
+        let mut sequencer = FillSequencer::default();
+
+        assert_eq!(
+            sequencer.handle(FillCommand::Purge(Chambers::All), true, &config, at(0)),
+            FillStatus::Refused(FillError::HeartEnabled)
+        );
+        for level in [0.5, 28.0, f32::NAN] {
+            assert_eq!(
+                sequencer.handle(
+                    FillCommand::Fill {
+                        chambers: Chambers::Systemic,
+                        level: mm(level),
+                    },
+                    false,
+                    &config,
+                    at(0),
+                ),
+                FillStatus::Refused(FillError::InvalidLevel)
+            );
+        }
+
+        // The heart starting halfway stops the purge
+        sequencer.handle(FillCommand::Purge(Chambers::All), false, &config, at(0));
+        assert_eq!(
+            sequencer.valves().systemic,
+            PairValves {
+                fill: true,
+                drain: true
+            }
+        );
+        assert_eq!(
+            sequencer.update(levels(15.0, 15.0), true, &config, at(1)),
+            Some(FillStatus::Failed {
+                operation: FillOperation::Purge,
+                chambers: Chambers::All,
+                error: FillError::HeartEnabled
+            })
+        );
+        assert_eq!(sequencer.valves(), ChamberValves::CLOSED);
+
+        // A purge runs for its time regardless of the level
+        sequencer.handle(FillCommand::Purge(Chambers::All), false, &config, at(0));
+        assert_eq!(sequencer.update(None, false, &config, at(9)), None);
+        assert_eq!(
+            sequencer.update(None, false, &config, at(10)),
+            Some(FillStatus::Done {
+                operation: FillOperation::Purge,
+                chambers: Chambers::All
+            })
+        );
+
+        sequencer.handle(FillCommand::Drain(Chambers::All), false, &config, at(0));
+        assert_eq!(
+            sequencer.handle(FillCommand::Abort, true, &config, at(1)),
+            FillStatus::Failed {
+                operation: FillOperation::Drain,
+                chambers: Chambers::All,
+                error: FillError::Aborted
+            }
+        );
+        assert_eq!(
+            sequencer.handle(FillCommand::Abort, false, &config, at(1)),
+            FillStatus::Idle
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use defmt::{info, warn};
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, channel::Channel};
+use embassy_time::{Duration, Instant, Ticker};
+
+use crate::{
+    adc_task::CHAMBER_LEVEL_WATCH,
+    chamber_valve_task::CHAMBER_VALVE_WATCH,
+    comms::message::StatusReport,
+    framing_task::publish_status,
+    heart_control::heart_controller::LEFT_PHASE_WATCH,
+    loop_control::{
+        fill::{ChamberValves, FillCommand, FillSequencer, FillStatus},
+        loop_controller::LOOP_CONFIG_WATCH,
+    },
+};
+
+/// Host commands filling, draining or purging the compliance chambers, the progress of each is
+/// reported to the host
+pub static FILL_CHANNEL: Channel<Cs, FillCommand, 2> = Channel::new();
+
+/// Period between level checks while a sequence runs
+const FILL_STEP: Duration = Duration::from_millis(100);
+
+/// Compliance chamber fill and drain loop
+/// Runs the host fill commands on the chamber valves, only while the heart is not beating
+#[embassy_executor::task]
+pub async fn fill_control_loop() {
+    info!("starting FILL CONTROL task");
+
+    let valves_tx = CHAMBER_VALVE_WATCH.sender();
+    let mut level_rx = CHAMBER_LEVEL_WATCH
+        .receiver()
+        .expect("Update CHAMBER_LEVEL_WATCH N");
+    // The heart controller clears the phase of a stopped heart
+    let mut phase_rx = LEFT_PHASE_WATCH
+        .receiver()
+        .expect("Update LEFT_PHASE_WATCH N");
+    let mut config_rx = LOOP_CONFIG_WATCH
+        .receiver()
+        .expect("Update LOOP_CONFIG_WATCH N");
+
+    valves_tx.send(ChamberValves::CLOSED);
+    publish_status(StatusReport::Fill(FillStatus::Idle));
+
+    let mut sequencer = FillSequencer::default();
+    let mut ticker = Ticker::every(FILL_STEP);
+
+    info!("FILL CONTROL: starting loop");
+    loop {
+        let running = sequencer.is_running();
+        let step = async {
+            if running {
+                ticker.next().await
+            } else {
+                core::future::pending().await
+            }
+        };
+
+        // Await either:
+        // A: A host fill command
+        // B: The next level check of the sequence in progress
+        let event = select(FILL_CHANNEL.receive(), step).await;
+        let config = config_rx.try_get().unwrap_or_default().fill;
+        let heart_beating = phase_rx.try_get().is_some();
+
+        let status = match event {
+            Either::First(command) => {
+                let status = sequencer.handle(command, heart_beating, &config, Instant::now());
+                ticker.reset();
+                Some(status)
+            }
+            Either::Second(()) => {
+                let levels = level_rx.try_get();
+                let now = Instant::now();
+                let status = sequencer.update(levels, heart_beating, &config, now);
+                if let Some(progress) = sequencer.progress(levels, now) {
+                    publish_status(StatusReport::FillProgress(progress));
+                }
+                status
+            }
+        };
+
+        if let Some(status) = status {
+            match status {
+                FillStatus::Refused(err) => warn!("FILL CONTROL: command refused: {}", err),
+                FillStatus::Failed { error, .. } => {
+                    warn!("FILL CONTROL: sequence failed: {}", error)
+                }
+                _ => info!("FILL CONTROL: {:?}", status),
+            }
+            publish_status(StatusReport::Fill(status));
+        }
+        valves_tx.send(sequencer.valves());
+    }
+}
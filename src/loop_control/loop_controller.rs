@@ -1,17 +1,54 @@
 use defmt::{debug, info, trace};
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, watch};
+use embassy_futures::select::{Either4, select4};
+use embassy_sync::{
+    blocking_mutex::raw::ThreadModeRawMutex as Cs,
+    watch::{self, Watch},
+};
 use love_letter::Setpoint;
-use uom::si::{f32::Pressure, pressure::bar};
+use uom::si::{
+    f32::{Pressure, VolumeRate},
+    pressure::bar,
+    volume_rate::liter_per_minute,
+};
 
 use crate::{
-    dac::dac_task::{DAC_PULMONARY_COMPLIANCE_WATCH, DAC_SYSTEMIC_COMPLIANCE_WATCH},
-    loop_control::setpoint::{compliance::ComplianceSetpoint, resistance::ResistanceSetpoint},
+    adc_task::MEASUREMENTS_WATCH,
+    comms::message::StatusReport,
+    dac::dac_task::{
+        DAC_FLOW_CONTROLLER_WATCH, DAC_PULMONARY_COMPLIANCE_WATCH, DAC_SYSTEMIC_COMPLIANCE_WATCH,
+    },
+    framing_task::publish_status,
+    heart_control::heart_controller::LEFT_PHASE_WATCH,
+    loop_control::{
+        compliance_loop::{
+            ComplianceEstimator, ComplianceLoop, ComplianceLoopConfig, ComplianceStatus,
+        },
+        config::{Circuit, LoopConfig},
+        preset::PresetName,
+        preset_controller::ACTIVE_PRESET_WATCH,
+        resistance_loop::{
+            ResistanceEstimator, ResistanceLoop, ResistanceLoopConfig, ResistanceStatus,
+        },
+        setpoint::{
+            compliance::{ComplianceChamber, ComplianceSetpoint},
+            resistance::{ResistanceCalibration, ResistanceSetpoint},
+        },
+    },
+    pinch_valve_task::{PULMONARY_PINCH_VALVE_WATCH, SYSTEMIC_PINCH_VALVE_WATCH},
 };
 
+/// Firmware side mockloop configuration, see [`LoopConfig`]
+pub static LOOP_CONFIG_WATCH: Watch<Cs, LoopConfig, 4> = Watch::new();
+/// System flow through the mass-flow controller, part of the loop setpoint but not of the
+/// love-letter [`love_letter::MockloopSetpoint`] (yet). Validated host
+/// [`HostCommand::FlowSetpoint`](crate::comms::message::HostCommand::FlowSetpoint)s, no flow
+/// until one is received
+pub static FLOW_SETPOINT_WATCH: Watch<Cs, VolumeRate, 1> = Watch::new();
+
 /// Mockloop control loop
 /// This control mockloop parameters like systemic/pulmonary flow resistance and compliance
 #[embassy_executor::task]
-pub async fn mockloop_control_loop(mut setpoint_rx: watch::Receiver<'static, Cs, Setpoint, 3>) {
+pub async fn mockloop_control_loop(mut setpoint_rx: watch::Receiver<'static, Cs, Setpoint, 4>) {
     info!("starting LOOP CONTROL task");
 
     // let connection_state_rx = CONNECTION_STATE
@@ -20,67 +57,386 @@ pub async fn mockloop_control_loop(mut setpoint_rx: watch::Receiver<'static, Cs,
 
     let systemic_pressure_tx = DAC_SYSTEMIC_COMPLIANCE_WATCH.sender();
     let pulmonary_pressure_tx = DAC_PULMONARY_COMPLIANCE_WATCH.sender();
+    let flow_tx = DAC_FLOW_CONTROLLER_WATCH.sender();
+    let systemic_valve_tx = SYSTEMIC_PINCH_VALVE_WATCH.sender();
+    let pulmonary_valve_tx = PULMONARY_PINCH_VALVE_WATCH.sender();
+    let mut config_rx = LOOP_CONFIG_WATCH
+        .receiver()
+        .expect("Update LOOP_CONFIG_WATCH N");
+    let mut flow_rx = FLOW_SETPOINT_WATCH
+        .receiver()
+        .expect("Update FLOW_SETPOINT_WATCH N");
+    let mut measurements_rx = MEASUREMENTS_WATCH
+        .receiver()
+        .expect("Update MEASUREMENTS_WATCH N");
+    let mut phase_rx = LEFT_PHASE_WATCH
+        .receiver()
+        .expect("Update LEFT_PHASE_WATCH N");
+    let mut active_preset_rx = ACTIVE_PRESET_WATCH
+        .receiver()
+        .expect("Update ACTIVE_PRESET_WATCH N");
 
     info!("LOOP CONTROL: Moving mockloop into safe state");
-    to_safe_loop_state(&systemic_pressure_tx, &pulmonary_pressure_tx);
+    to_safe_loop_state(
+        &systemic_pressure_tx,
+        &pulmonary_pressure_tx,
+        &systemic_valve_tx,
+        &pulmonary_valve_tx,
+        &flow_tx,
+    );
 
     info!("LOOP CONTROL: Waiting for initial setpoint");
     // Current setpoint
     let mut setpoint = setpoint_rx.changed().await;
+    // Current mockloop configuration, defaults until one is received
+    let mut config = config_rx.try_get().unwrap_or_default();
+    // Current system flow setpoint
+    let mut flow = flow_rx
+        .try_get()
+        .unwrap_or(VolumeRate::new::<liter_per_minute>(0.0));
+    // Resistance and compliance estimation and closed-loop control of both circuits
+    let mut systemic_resistance = CircuitResistance::new(Circuit::Systemic, config.resistance_loop);
+    let mut pulmonary_resistance =
+        CircuitResistance::new(Circuit::Pulmonary, config.resistance_loop);
+    let mut systemic_compliance = CircuitCompliance::new(Circuit::Systemic, config.compliance_loop);
+    let mut pulmonary_compliance =
+        CircuitCompliance::new(Circuit::Pulmonary, config.compliance_loop);
+    // Preset the setpoint ramps towards, `None` while the host sets it
+    let mut preset_target: Option<(PresetName, u16)> = None;
+    // Whether the actuators need updating
+    let mut actuate = true;
 
     info!("LOOP CONTROL: starting loop");
     loop {
-        // Only control the mockloop if the loop controller is enabled
-        if let Some(ref mockloop_setpoint) = setpoint.mockloop_setpoint {
-            // Convert raw compliance setpoint into pressure setpoint for the compliance chamber
-            // pressure regulators
-            let pulmonary_pressure_setpoint = ComplianceSetpoint::from_raw_compliance(
-                mockloop_setpoint.systemic_afterload_compliance,
-            );
-            let systemic_pressure_setpoint = ComplianceSetpoint::from_raw_compliance(
-                mockloop_setpoint.systemic_afterload_compliance,
-            );
-
-            debug!(
-                "LOOP CONTROL: Converted raw systemic compliance setpoint {} into pressure setpoint {}bar",
-                mockloop_setpoint.systemic_afterload_compliance,
-                systemic_pressure_setpoint.pressure.get::<bar>()
-            );
-            debug!(
-                "LOOP CONTROL: Converted raw pulmonary compliance setpoint {} into pressure setpoint {}bar",
-                mockloop_setpoint.pulmonary_afterload_compliance,
-                pulmonary_pressure_setpoint.pressure.get::<bar>()
-            );
-
-            let systemic_resistance_setpoint =
-                ResistanceSetpoint::from_raw_resistance(mockloop_setpoint.systemic_resistance);
-            debug!(
-                "LOOP CONTROL: Converted raw systemic resistance setpoint {} into setpoint {}",
-                mockloop_setpoint.systemic_resistance,
-                systemic_resistance_setpoint.valve_open_percentage
-            );
-            let pulmonary_resistance_setpoint =
-                ResistanceSetpoint::from_raw_resistance(mockloop_setpoint.pulmonary_resistance);
-            debug!(
-                "LOOP CONTROL: Converted raw pulmonary resistance setpoint {} into setpoint {}",
-                mockloop_setpoint.pulmonary_resistance,
-                pulmonary_resistance_setpoint.valve_open_percentage
-            );
-
-            // Ask DAC task to actuate the compliance chamber regulators
-            systemic_pressure_tx.send(systemic_pressure_setpoint.pressure);
-            pulmonary_pressure_tx.send(pulmonary_pressure_setpoint.pressure);
-
-            // TODO: Control resistance
-        } else {
-            // Heart Controller is disabled: Set the valves and pressure regulator into safe state
-            debug!("LOOP CONTROL: DISABLED -> Moving to safe state and ready for more action");
-
-            to_safe_loop_state(&systemic_pressure_tx, &pulmonary_pressure_tx);
+        // Only actuate when the setpoint, configuration, flow or a valve correction changed
+        if actuate {
+            // Only control the mockloop if the loop controller is enabled
+            if let Some(ref mockloop_setpoint) = setpoint.mockloop_setpoint {
+                // Convert raw compliance setpoint into pressure setpoint for the compliance chamber
+                // pressure regulators
+                let pulmonary_pressure_setpoint = pulmonary_compliance.chamber_setpoint(
+                    mockloop_setpoint.pulmonary_afterload_compliance,
+                    &config.pulmonary_chamber,
+                );
+                let systemic_pressure_setpoint = systemic_compliance.chamber_setpoint(
+                    mockloop_setpoint.systemic_afterload_compliance,
+                    &config.systemic_chamber,
+                );
+
+                debug!(
+                    "LOOP CONTROL: Converted raw systemic compliance setpoint {} into pressure setpoint {}bar",
+                    mockloop_setpoint.systemic_afterload_compliance,
+                    systemic_pressure_setpoint.pressure.get::<bar>()
+                );
+                debug!(
+                    "LOOP CONTROL: Converted raw pulmonary compliance setpoint {} into pressure setpoint {}bar",
+                    mockloop_setpoint.pulmonary_afterload_compliance,
+                    pulmonary_pressure_setpoint.pressure.get::<bar>()
+                );
+
+                let systemic_resistance_setpoint = systemic_resistance.valve_setpoint(
+                    mockloop_setpoint.systemic_resistance,
+                    &config.systemic_resistance,
+                );
+                debug!(
+                    "LOOP CONTROL: Converted raw systemic resistance setpoint {} into setpoint {}% open",
+                    mockloop_setpoint.systemic_resistance,
+                    systemic_resistance_setpoint.valve_open_percentage
+                );
+                let pulmonary_resistance_setpoint = pulmonary_resistance.valve_setpoint(
+                    mockloop_setpoint.pulmonary_resistance,
+                    &config.pulmonary_resistance,
+                );
+                debug!(
+                    "LOOP CONTROL: Converted raw pulmonary resistance setpoint {} into setpoint {}% open",
+                    mockloop_setpoint.pulmonary_resistance,
+                    pulmonary_resistance_setpoint.valve_open_percentage
+                );
+
+                // Ask DAC task to actuate the compliance chamber regulators
+                systemic_pressure_tx.send(systemic_pressure_setpoint.pressure);
+                pulmonary_pressure_tx.send(pulmonary_pressure_setpoint.pressure);
+
+                // Ask pinch valve task to actuate the resistance pinch valves
+                systemic_valve_tx.send(systemic_resistance_setpoint.valve_open_percentage);
+                pulmonary_valve_tx.send(pulmonary_resistance_setpoint.valve_open_percentage);
+
+                // Ask DAC task to actuate the mass-flow controller
+                debug!(
+                    "LOOP CONTROL: Setting system flow to {}L/min",
+                    flow.get::<liter_per_minute>()
+                );
+                flow_tx.send(flow);
+            } else {
+                // Heart Controller is disabled: Set the valves and pressure regulator into safe state
+                debug!("LOOP CONTROL: DISABLED -> Moving to safe state and ready for more action");
+
+                to_safe_loop_state(
+                    &systemic_pressure_tx,
+                    &pulmonary_pressure_tx,
+                    &systemic_valve_tx,
+                    &pulmonary_valve_tx,
+                    &flow_tx,
+                );
+                systemic_resistance =
+                    CircuitResistance::new(Circuit::Systemic, config.resistance_loop);
+                pulmonary_resistance =
+                    CircuitResistance::new(Circuit::Pulmonary, config.resistance_loop);
+                systemic_compliance =
+                    CircuitCompliance::new(Circuit::Systemic, config.compliance_loop);
+                pulmonary_compliance =
+                    CircuitCompliance::new(Circuit::Pulmonary, config.compliance_loop);
+            }
+        }
+
+        // Await either:
+        // A: A new setpoint
+        // B: A new mockloop configuration
+        // C: A new system flow setpoint
+        // D: A new measurement, the resistances and compliances are estimated at the end of
+        //    every beat
+        actuate = match select4(
+            setpoint_rx.changed(),
+            config_rx.changed(),
+            flow_rx.changed(),
+            measurements_rx.changed(),
+        )
+        .await
+        {
+            Either4::First(new_setpoint) => {
+                setpoint = new_setpoint;
+                // The valves and regulators move halfway through the beat, the small steps of a
+                // ramp towards the same preset do not spoil it
+                let target = active_preset_rx
+                    .try_get()
+                    .flatten()
+                    .map(|preset| (preset.name, preset.version));
+                if target.is_none() || target != preset_target {
+                    systemic_resistance.estimator.restart();
+                    pulmonary_resistance.estimator.restart();
+                    systemic_compliance.estimator.restart();
+                    pulmonary_compliance.estimator.restart();
+                }
+                preset_target = target;
+                true
+            }
+            Either4::Second(new_config) => {
+                debug!("LOOP CONTROL: Received a new mockloop configuration");
+                systemic_resistance.set_config(new_config.resistance_loop);
+                pulmonary_resistance.set_config(new_config.resistance_loop);
+                systemic_compliance.set_config(new_config.compliance_loop);
+                pulmonary_compliance.set_config(new_config.compliance_loop);
+                config = new_config;
+                true
+            }
+            Either4::Third(new_flow) => {
+                flow = new_flow;
+                true
+            }
+            Either4::Fourth(measurements) => {
+                let beat = phase_rx.try_get().map(|event| event.beat);
+                let mockloop_setpoint = setpoint.mockloop_setpoint.as_ref();
+
+                let systemic_resistance_status = systemic_resistance.update(
+                    beat,
+                    measurements.systemic_afterload_pressure
+                        - measurements.systemic_preload_pressure,
+                    measurements.systemic_flow,
+                    mockloop_setpoint.map(|setpoint| setpoint.systemic_resistance),
+                    &config.systemic_resistance,
+                );
+                let pulmonary_resistance_status = pulmonary_resistance.update(
+                    beat,
+                    measurements.pulmonary_afterload_pressure
+                        - measurements.pulmonary_preload_pressure,
+                    measurements.pulmonary_flow,
+                    mockloop_setpoint.map(|setpoint| setpoint.pulmonary_resistance),
+                    &config.pulmonary_resistance,
+                );
+                let systemic_compliance_status = systemic_compliance.update(
+                    beat,
+                    measurements.systemic_afterload_pressure,
+                    measurements.systemic_flow,
+                    measurements.timestamp,
+                    mockloop_setpoint.map(|setpoint| setpoint.systemic_afterload_compliance),
+                    &config.systemic_chamber,
+                );
+                let pulmonary_compliance_status = pulmonary_compliance.update(
+                    beat,
+                    measurements.pulmonary_afterload_pressure,
+                    measurements.pulmonary_flow,
+                    measurements.timestamp,
+                    mockloop_setpoint.map(|setpoint| setpoint.pulmonary_afterload_compliance),
+                    &config.pulmonary_chamber,
+                );
+                for status in [systemic_resistance_status, pulmonary_resistance_status]
+                    .into_iter()
+                    .flatten()
+                {
+                    publish_status(StatusReport::Resistance(status));
+                }
+                for status in [systemic_compliance_status, pulmonary_compliance_status]
+                    .into_iter()
+                    .flatten()
+                {
+                    publish_status(StatusReport::Compliance(status));
+                }
+
+                // Apply the corrected valve openings and chamber pressures
+                let resistance_corrected = config.resistance_loop.is_some()
+                    && (systemic_resistance_status.is_some()
+                        || pulmonary_resistance_status.is_some());
+                let compliance_corrected = config.compliance_loop.is_some()
+                    && (systemic_compliance_status.is_some()
+                        || pulmonary_compliance_status.is_some());
+                resistance_corrected || compliance_corrected
+            }
+        };
+    }
+}
+
+/// Resistance estimation and closed-loop control of one circuit
+struct CircuitResistance {
+    circuit: Circuit,
+    estimator: ResistanceEstimator,
+    /// `None` while setting the calibrated valve opening
+    control: Option<ResistanceLoop>,
+}
+
+impl CircuitResistance {
+    fn new(circuit: Circuit, config: Option<ResistanceLoopConfig>) -> Self {
+        Self {
+            circuit,
+            estimator: ResistanceEstimator::default(),
+            control: config.map(ResistanceLoop::new),
+        }
+    }
+
+    fn set_config(&mut self, config: Option<ResistanceLoopConfig>) {
+        match (&mut self.control, config) {
+            (Some(control), Some(config)) => control.set_config(config),
+            (control, config) => *control = config.map(ResistanceLoop::new),
+        }
+    }
+
+    /// Valve opening for a `resistance` in mmHg·s/mL, corrected while controlling closed-loop
+    fn valve_setpoint(
+        &self,
+        resistance: f32,
+        calibration: &ResistanceCalibration,
+    ) -> ResistanceSetpoint {
+        let setpoint = ResistanceSetpoint::from_raw_resistance(resistance, calibration);
+
+        match &self.control {
+            Some(control) => control.trimmed(setpoint),
+            None => setpoint,
+        }
+    }
+
+    /// Add a measurement taken during `beat`, returns the status once a beat is estimated
+    /// `setpoint` is the requested resistance, `None` while the mockloop is disabled
+    fn update(
+        &mut self,
+        beat: Option<u32>,
+        pressure_drop: Pressure,
+        flow: VolumeRate,
+        setpoint: Option<f32>,
+        calibration: &ResistanceCalibration,
+    ) -> Option<ResistanceStatus> {
+        let estimate = self.estimator.update(beat, (pressure_drop, flow))?;
+        let setpoint = setpoint?;
+
+        if let Some(control) = &mut self.control {
+            control.update(&estimate, setpoint, calibration);
+        }
+        trace!(
+            "LOOP CONTROL: beat {} resistance {} for setpoint {}",
+            estimate.beat,
+            estimate.resistance(),
+            setpoint
+        );
+
+        Some(ResistanceStatus {
+            circuit: self.circuit,
+            beat: estimate.beat,
+            setpoint,
+            measured: estimate.resistance(),
+            trim: self.control.as_ref().map_or(0.0, ResistanceLoop::trim),
+        })
+    }
+}
+
+/// Compliance estimation and closed-loop control of one circuit
+struct CircuitCompliance {
+    circuit: Circuit,
+    estimator: ComplianceEstimator,
+    /// `None` while setting the modelled chamber pressure
+    control: Option<ComplianceLoop>,
+}
+
+impl CircuitCompliance {
+    fn new(circuit: Circuit, config: Option<ComplianceLoopConfig>) -> Self {
+        Self {
+            circuit,
+            estimator: ComplianceEstimator::default(),
+            control: config.map(ComplianceLoop::new),
+        }
+    }
+
+    fn set_config(&mut self, config: Option<ComplianceLoopConfig>) {
+        match (&mut self.control, config) {
+            (Some(control), Some(config)) => control.set_config(config),
+            (control, config) => *control = config.map(ComplianceLoop::new),
+        }
+    }
+
+    /// Chamber pressure for a `compliance` in mL/mmHg, corrected while controlling closed-loop
+    fn chamber_setpoint(&self, compliance: f32, chamber: &ComplianceChamber) -> ComplianceSetpoint {
+        let setpoint = ComplianceSetpoint::from_raw_compliance(compliance, chamber);
+
+        match &self.control {
+            Some(control) => control.trimmed(setpoint),
+            None => setpoint,
+        }
+    }
+
+    /// Add a measurement taken at `timestamp` µs during `beat`, returns the status once a beat is
+    /// estimated
+    /// `setpoint` is the requested compliance, `None` while the mockloop is disabled
+    fn update(
+        &mut self,
+        beat: Option<u32>,
+        afterload_pressure: Pressure,
+        flow: VolumeRate,
+        timestamp: u64,
+        setpoint: Option<f32>,
+        chamber: &ComplianceChamber,
+    ) -> Option<ComplianceStatus> {
+        let estimate = self
+            .estimator
+            .update(beat, (afterload_pressure, flow, timestamp))?;
+        let setpoint = setpoint?;
+
+        if let Some(control) = &mut self.control {
+            control.update(&estimate, setpoint, chamber);
         }
+        trace!(
+            "LOOP CONTROL: beat {} compliance {} for setpoint {}",
+            estimate.beat,
+            estimate.compliance(),
+            setpoint
+        );
 
-        // Await a new setpoint
-        setpoint = setpoint_rx.changed().await;
+        Some(ComplianceStatus {
+            circuit: self.circuit,
+            beat: estimate.beat,
+            setpoint,
+            measured: estimate.compliance(),
+            trim: self
+                .control
+                .as_ref()
+                .map_or(0.0, |control| control.trim().get::<bar>()),
+        })
     }
 }
 
@@ -88,11 +444,20 @@ pub async fn mockloop_control_loop(mut setpoint_rx: watch::Receiver<'static, Cs,
 fn to_safe_loop_state(
     systemic_pressure_tx: &watch::Sender<'static, Cs, Pressure, 1>,
     pulmonary_pressure_tx: &watch::Sender<'static, Cs, Pressure, 1>,
+    systemic_valve_tx: &watch::Sender<'static, Cs, f32, 1>,
+    pulmonary_valve_tx: &watch::Sender<'static, Cs, f32, 1>,
+    flow_tx: &watch::Sender<'static, Cs, VolumeRate, 1>,
 ) {
     const COMPLIANCE_REGULATOR_SAFE_PRESSURE_BAR: f32 = 0.0;
+    const FLOW_CONTROLLER_SAFE_FLOW_LPM: f32 = 0.0;
 
     debug!("HEART CONTROL: to SAFE state",);
 
     systemic_pressure_tx.send(Pressure::new::<bar>(COMPLIANCE_REGULATOR_SAFE_PRESSURE_BAR));
     pulmonary_pressure_tx.send(Pressure::new::<bar>(COMPLIANCE_REGULATOR_SAFE_PRESSURE_BAR));
+    systemic_valve_tx.send(ResistanceSetpoint::safe().valve_open_percentage);
+    pulmonary_valve_tx.send(ResistanceSetpoint::safe().valve_open_percentage);
+    flow_tx.send(VolumeRate::new::<liter_per_minute>(
+        FLOW_CONTROLLER_SAFE_FLOW_LPM,
+    ));
 }
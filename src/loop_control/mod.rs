@@ -1,2 +1,15 @@
+pub mod beat_average;
+pub mod compliance_loop;
+pub mod config;
+pub mod error;
+pub mod fill;
+#[cfg(target_os = "none")]
+pub mod fill_controller;
+#[cfg(target_os = "none")]
 pub mod loop_controller;
+pub mod preset;
+#[cfg(target_os = "none")]
+pub mod preset_controller;
+pub mod resistance_loop;
+pub mod sensor;
 pub mod setpoint;
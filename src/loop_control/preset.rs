@@ -0,0 +1,357 @@
+//! Patient condition presets
+//! Experimenters think in conditions like "hypertension" rather than in raw setpoint numbers. A
+//! [`Preset`] holds a named and versioned set of heart and mockloop setpoints, kept in a
+//! [`PresetLibrary`] and selected by a host [`PresetCommand`]. Switching presets ramps from the
+//! current setpoint to the preset through a [`PresetTransition`].
+
+use embassy_time::{Duration, Instant};
+use love_letter::{HeartControllerSetpoint, MockloopSetpoint, Setpoint};
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    f32::{Frequency, Pressure},
+    frequency::cycle_per_minute,
+    pressure::bar,
+};
+
+use crate::{
+    comms::{message::duration_us, validation::SetpointLimits},
+    loop_control::error::PresetError,
+};
+
+/// Longest preset name
+pub const PRESET_NAME_LEN: usize = 24;
+/// Number of presets stored on the device, the built-in presets included
+pub const PRESETS: usize = 12;
+
+pub type PresetName = heapless::String<PRESET_NAME_LEN>;
+
+/// Heart and mockloop setpoints of one patient condition
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: PresetName,
+    /// Revision of the preset, a stored preset is only replaced by a newer one
+    pub version: u16,
+    pub heart: HeartControllerSetpoint,
+    pub mockloop: MockloopSetpoint,
+}
+
+impl Preset {
+    /// Presets present from the start, scaled to the reach of the mockloop as built
+    /// Heart rate in beats per minute, driveline pressure in bar, resistance in mmHg·s/mL and
+    /// compliance in mL/mmHg
+    pub fn built_in() -> [Self; 4] {
+        const BUILT_IN: [(&str, f32, f32, f32, [f32; 4]); 4] = [
+            // Name, heart rate, systole ratio, pressure, [systemic resistance, pulmonary
+            // resistance, systemic compliance, pulmonary compliance]
+            ("healthy-rest", 70.0, 0.35, 1.0, [1.0, 0.1, 0.15, 0.2]),
+            ("hypertension", 80.0, 0.35, 1.4, [1.8, 0.12, 0.08, 0.18]),
+            ("hfref", 95.0, 0.3, 0.6, [1.4, 0.25, 0.12, 0.15]),
+            ("exercise", 140.0, 0.45, 1.6, [0.5, 0.06, 0.18, 0.2]),
+        ];
+
+        BUILT_IN.map(|(name, bpm, systole_ratio, pressure, mockloop)| Self {
+            name: PresetName::try_from(name).unwrap_or_default(),
+            version: 1,
+            heart: HeartControllerSetpoint {
+                heart_rate: Frequency::new::<cycle_per_minute>(bpm),
+                systole_ratio,
+                pressure: Pressure::new::<bar>(pressure),
+            },
+            mockloop: MockloopSetpoint {
+                systemic_resistance: mockloop[0],
+                pulmonary_resistance: mockloop[1],
+                systemic_afterload_compliance: mockloop[2],
+                pulmonary_afterload_compliance: mockloop[3],
+            },
+        })
+    }
+
+    pub fn setpoint(&self) -> Setpoint {
+        Setpoint {
+            heart_controller_setpoint: Some(self.heart.clone()),
+            mockloop_setpoint: Some(self.mockloop.clone()),
+        }
+    }
+}
+
+/// Host command managing the presets
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PresetCommand {
+    /// Ramp from the current setpoint to the named preset over `transition`
+    Select {
+        name: PresetName,
+        #[serde(with = "duration_us")]
+        transition: Duration,
+    },
+    /// Add a preset, or replace an older version of the stored preset of the same name
+    Store(Preset),
+}
+
+/// Acknowledgement of a [`PresetCommand`]
+#[derive(Debug, Clone, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct PresetAck {
+    /// Preset the command applied to
+    pub name: PresetName,
+    pub result: Result<(), PresetError>,
+}
+
+/// Preset the setpoint follows, until the host sends a setpoint of its own
+#[derive(Debug, Clone, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct ActivePreset {
+    pub name: PresetName,
+    pub version: u16,
+    /// Fraction of the transition towards the preset done
+    pub progress: f32,
+}
+
+/// Presets stored on the device
+/// Presets are kept until reset, only the built-in presets are there from the start
+#[derive(Debug, Clone)]
+pub struct PresetLibrary {
+    presets: heapless::Vec<Preset, PRESETS>,
+}
+
+impl Default for PresetLibrary {
+    fn default() -> Self {
+        Self {
+            presets: Preset::built_in().into_iter().collect(),
+        }
+    }
+}
+
+impl PresetLibrary {
+    pub fn get(&self, name: &str) -> Option<&Preset> {
+        self.presets.iter().find(|preset| preset.name == name)
+    }
+
+    /// Carry out `command`, presets are checked against the current setpoint `limits` both when
+    /// stored and when selected, as they never pass the host setpoint validation
+    pub fn handle(&mut self, command: PresetCommand, limits: &SetpointLimits) -> PresetAck {
+        let (name, result) = match command {
+            PresetCommand::Select { name, .. } => {
+                let result = self
+                    .get(&name)
+                    .ok_or(PresetError::UnknownPreset)
+                    .and_then(|preset| check(preset, limits));
+                (name, result)
+            }
+            PresetCommand::Store(preset) => (preset.name.clone(), self.store(preset, limits)),
+        };
+
+        PresetAck { name, result }
+    }
+
+    fn store(&mut self, preset: Preset, limits: &SetpointLimits) -> Result<(), PresetError> {
+        check(&preset, limits)?;
+
+        match self
+            .presets
+            .iter_mut()
+            .find(|stored| stored.name == preset.name)
+        {
+            Some(stored) if stored.version >= preset.version => Err(PresetError::Outdated),
+            Some(stored) => {
+                *stored = preset;
+                Ok(())
+            }
+            None => self.presets.push(preset).map_err(|_| PresetError::Full),
+        }
+    }
+}
+
+/// A preset passes if none of its fields would be rejected or clamped
+fn check(preset: &Preset, limits: &SetpointLimits) -> Result<(), PresetError> {
+    match limits.validate(&mut preset.setpoint()) {
+        None => Ok(()),
+        Some(_) => Err(PresetError::InvalidSetpoint),
+    }
+}
+
+/// Linear ramp from a setpoint to a preset
+/// Parts of the setpoint that are disabled at the start jump to the preset straight away
+#[derive(Debug, Clone)]
+pub struct PresetTransition {
+    from: Setpoint,
+    to: Setpoint,
+    start: Instant,
+    duration: Duration,
+}
+
+impl PresetTransition {
+    pub fn new(from: Setpoint, to: &Preset, start: Instant, duration: Duration) -> Self {
+        Self {
+            from,
+            to: to.setpoint(),
+            start,
+            duration,
+        }
+    }
+
+    /// Fraction of the transition done at `now`, from 0 to 1
+    pub fn progress(&self, now: Instant) -> f32 {
+        if self.duration == Duration::from_ticks(0) {
+            return 1.0;
+        }
+        let elapsed = now.saturating_duration_since(self.start);
+
+        (elapsed.as_micros() as f32 / self.duration.as_micros() as f32).min(1.0)
+    }
+
+    /// Setpoint on the ramp at `now`
+    pub fn setpoint(&self, now: Instant) -> Setpoint {
+        let fraction = self.progress(now);
+        let lerp = |from: f32, to: f32| from + (to - from) * fraction;
+
+        let heart_controller_setpoint = match (
+            &self.from.heart_controller_setpoint,
+            &self.to.heart_controller_setpoint,
+        ) {
+            (Some(from), Some(to)) => Some(HeartControllerSetpoint {
+                heart_rate: Frequency::new::<cycle_per_minute>(lerp(
+                    from.heart_rate.get::<cycle_per_minute>(),
+                    to.heart_rate.get::<cycle_per_minute>(),
+                )),
+                systole_ratio: lerp(from.systole_ratio, to.systole_ratio),
+                pressure: Pressure::new::<bar>(lerp(
+                    from.pressure.get::<bar>(),
+                    to.pressure.get::<bar>(),
+                )),
+            }),
+            (_, to) => to.clone(),
+        };
+        let mockloop_setpoint = match (&self.from.mockloop_setpoint, &self.to.mockloop_setpoint) {
+            (Some(from), Some(to)) => Some(MockloopSetpoint {
+                systemic_resistance: lerp(from.systemic_resistance, to.systemic_resistance),
+                pulmonary_resistance: lerp(from.pulmonary_resistance, to.pulmonary_resistance),
+                systemic_afterload_compliance: lerp(
+                    from.systemic_afterload_compliance,
+                    to.systemic_afterload_compliance,
+                ),
+                pulmonary_afterload_compliance: lerp(
+                    from.pulmonary_afterload_compliance,
+                    to.pulmonary_afterload_compliance,
+                ),
+            }),
+            (_, to) => to.clone(),
+        };
+
+        Setpoint {
+            heart_controller_setpoint,
+            mockloop_setpoint,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preset(name: &str, version: u16, systemic_resistance: f32) -> Preset {
+        Preset {
+            name: PresetName::try_from(name).unwrap(),
+            version,
+            mockloop: MockloopSetpoint {
+                systemic_resistance,
+                ..Preset::built_in()[0].mockloop.clone()
+            },
+            ..Preset::built_in()[0].clone()
+        }
+    }
+
+    #[test]
+    fn test_built_in_presets_pass_limits() {
+        let limits = SetpointLimits::default();
+
+        for preset in Preset::built_in() {
+            assert_eq!(check(&preset, &limits), Ok(()), "{}", preset.name);
+        }
+    }
+
+    #[test]
+    fn test_store_and_select() {
+        let mut library = PresetLibrary::default();
+        let limits = SetpointLimits::default();
+        let select = |name: &str| PresetCommand::Select {
+            name: PresetName::try_from(name).unwrap(),
+            transition: Duration::from_secs(10),
+        };
+
+        assert_eq!(
+            library.handle(select("sepsis"), &limits).result,
+            Err(PresetError::UnknownPreset)
+        );
+        assert_eq!(
+            library
+                .handle(PresetCommand::Store(preset("sepsis", 1, 0.4)), &limits)
+                .result,
+            Ok(())
+        );
+        assert_eq!(library.handle(select("sepsis"), &limits).result, Ok(()));
+
+        // Only a newer version replaces a stored preset, built-in ones included
+        assert_eq!(
+            library
+                .handle(PresetCommand::Store(preset("sepsis", 1, 0.3)), &limits)
+                .result,
+            Err(PresetError::Outdated)
+        );
+        assert_eq!(
+            library
+                .handle(PresetCommand::Store(preset("hfref", 2, 1.2)), &limits)
+                .result,
+            Ok(())
+        );
+        assert_eq!(
+            library.get("hfref").unwrap().mockloop.systemic_resistance,
+            1.2
+        );
+
+        assert_eq!(
+            library
+                .handle(PresetCommand::Store(preset("stiff", 1, 50.0)), &limits)
+                .result,
+            Err(PresetError::InvalidSetpoint)
+        );
+        assert_eq!(library.get("stiff"), None);
+
+        // Tighter limits since storing
+        let limits = SetpointLimits {
+            resistance: crate::comms::validation::Limit::new(0.0, 0.2),
+            ..limits
+        };
+        assert_eq!(
+            library.handle(select("sepsis"), &limits).result,
+            Err(PresetError::InvalidSetpoint)
+        );
+    }
+
+    #[test]
+    fn test_transition() {
+        let start = Instant::from_secs(1);
+        let to = preset("to", 1, 2.0);
+        let from = Setpoint {
+            heart_controller_setpoint: None,
+            mockloop_setpoint: Some(MockloopSetpoint {
+                systemic_resistance: 1.0,
+                ..to.mockloop.clone()
+            }),
+        };
+        let transition = PresetTransition::new(from, &to, start, Duration::from_secs(10));
+
+        let halfway = transition.setpoint(start + Duration::from_secs(5));
+        assert_eq!(transition.progress(start + Duration::from_secs(5)), 0.5);
+        assert_eq!(halfway.mockloop_setpoint.unwrap().systemic_resistance, 1.5);
+        // The stopped heart starts at the preset
+        assert_eq!(halfway.heart_controller_setpoint, Some(to.heart.clone()));
+
+        assert_eq!(transition.progress(start + Duration::from_secs(20)), 1.0);
+        assert_eq!(
+            transition.setpoint(start + Duration::from_secs(20)),
+            to.setpoint()
+        );
+
+        let instant =
+            PresetTransition::new(Setpoint::default(), &to, start, Duration::from_secs(0));
+        assert_eq!(instant.progress(start), 1.0);
+    }
+}
@@ -0,0 +1,151 @@
+use defmt::{debug, info, warn};
+use embassy_futures::select::{Either3, select3};
+use embassy_sync::{
+    blocking_mutex::raw::ThreadModeRawMutex as Cs,
+    channel::Channel,
+    watch::{self, Watch},
+};
+use embassy_time::{Duration, Instant, Ticker};
+use love_letter::Setpoint;
+
+use crate::{
+    comms::{message::StatusReport, validation::SETPOINT_LIMITS_WATCH},
+    framing_task::publish_status,
+    loop_control::preset::{ActivePreset, PresetCommand, PresetLibrary, PresetTransition},
+};
+
+/// Host commands selecting or storing presets, each is acknowledged to the host
+pub static PRESET_CHANNEL: Channel<Cs, PresetCommand, 2> = Channel::new();
+/// Preset the setpoint follows, `None` once the host sends a setpoint of its own
+/// Updated before the setpoint it belongs to
+pub static ACTIVE_PRESET_WATCH: Watch<Cs, Option<ActivePreset>, 1> = Watch::new();
+
+/// Period between setpoints on the way to a preset
+const TRANSITION_STEP: Duration = Duration::from_millis(100);
+
+/// Preset control loop
+/// Ramps the setpoint of the heart and mockloop controllers to the preset selected by the host
+#[embassy_executor::task]
+pub async fn preset_control_loop(
+    setpoint_tx: watch::Sender<'static, Cs, Setpoint, 4>,
+    mut setpoint_rx: watch::Receiver<'static, Cs, Setpoint, 4>,
+) {
+    info!("starting PRESET CONTROL task");
+
+    let mut limits_rx = SETPOINT_LIMITS_WATCH
+        .receiver()
+        .expect("Update SETPOINT_LIMITS_WATCH N");
+    let active_tx = ACTIVE_PRESET_WATCH.sender();
+    active_tx.send(None);
+
+    let mut library = PresetLibrary::default();
+    // Latest setpoint, the start of the next transition
+    let mut setpoint = setpoint_rx.try_get().unwrap_or_default();
+    // Transition in progress and the preset it goes to
+    let mut transition: Option<(PresetTransition, ActivePreset)> = None;
+    let mut active: Option<ActivePreset> = None;
+    // Setpoint sent last, to tell it apart from host setpoints
+    let mut sent: Option<Setpoint> = None;
+    let mut ticker = Ticker::every(TRANSITION_STEP);
+
+    info!("PRESET CONTROL: starting loop");
+    loop {
+        let step = async {
+            match transition {
+                Some(_) => ticker.next().await,
+                None => core::future::pending().await,
+            }
+        };
+
+        // Await either:
+        // A: A host preset command
+        // B: A new setpoint, from the host or sent on the way to a preset
+        // C: The next step of the transition in progress
+        match select3(PRESET_CHANNEL.receive(), setpoint_rx.changed(), step).await {
+            Either3::First(command) => {
+                let limits = limits_rx.try_get().unwrap_or_default();
+                let transition_time = match &command {
+                    PresetCommand::Select { transition, .. } => Some(*transition),
+                    PresetCommand::Store(_) => None,
+                };
+                let ack = library.handle(command, &limits);
+
+                match ack.result {
+                    Ok(()) => info!("PRESET CONTROL: preset command applied: {:?}", ack),
+                    Err(err) => warn!("PRESET CONTROL: preset command refused: {}", err),
+                }
+                if let (Ok(()), Some(duration)) = (ack.result, transition_time)
+                    && let Some(preset) = library.get(&ack.name)
+                {
+                    debug!(
+                        "PRESET CONTROL: ramping to preset {} over {}ms",
+                        preset.name,
+                        duration.as_millis()
+                    );
+                    ticker.reset();
+                    transition = Some((
+                        PresetTransition::new(setpoint.clone(), preset, Instant::now(), duration),
+                        ActivePreset {
+                            name: preset.name.clone(),
+                            version: preset.version,
+                            progress: 0.0,
+                        },
+                    ));
+                    // Take the first step straight away
+                    if let Some((step, preset)) = step_transition(&mut transition) {
+                        active = Some(preset);
+                        report_active(&active_tx, &active);
+                        setpoint_tx.send(step.clone());
+                        sent = Some(step);
+                    }
+                }
+                publish_status(StatusReport::PresetAck(ack));
+            }
+            Either3::Second(new_setpoint) => {
+                // Anything but the setpoint sent last is the host taking over
+                if active.is_some() && sent.as_ref() != Some(&new_setpoint) {
+                    info!("PRESET CONTROL: host setpoint overrides the active preset");
+                    transition = None;
+                    active = None;
+                    report_active(&active_tx, &active);
+                }
+                setpoint = new_setpoint;
+            }
+            Either3::Third(()) => {
+                if let Some((step, preset)) = step_transition(&mut transition) {
+                    active = Some(preset);
+                    report_active(&active_tx, &active);
+                    setpoint_tx.send(step.clone());
+                    sent = Some(step);
+                }
+            }
+        }
+    }
+}
+
+/// Setpoint of the transition in progress and its progress, ending it once the preset is reached
+fn step_transition(
+    transition: &mut Option<(PresetTransition, ActivePreset)>,
+) -> Option<(Setpoint, ActivePreset)> {
+    let (ramp, preset) = transition.as_mut()?;
+    let now = Instant::now();
+
+    preset.progress = ramp.progress(now);
+    let step = (ramp.setpoint(now), preset.clone());
+
+    if preset.progress >= 1.0 {
+        info!("PRESET CONTROL: reached preset {}", preset.name);
+        *transition = None;
+    }
+
+    Some(step)
+}
+
+/// Publish the preset the setpoint follows, to the mockloop controller and to the host
+fn report_active(
+    active_tx: &watch::Sender<'static, Cs, Option<ActivePreset>, 1>,
+    active: &Option<ActivePreset>,
+) {
+    active_tx.send(active.clone());
+    publish_status(StatusReport::ActivePreset(active.clone()));
+}
@@ -0,0 +1,293 @@
+//! Closed-loop flow resistance control
+//! The pinch valve calibration shifts with fluid viscosity and temperature. The actual resistance
+//! of a circuit is estimated each beat from its pressure drop and flow, and the valve opening is
+//! trimmed until it matches the setpoint.
+
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    f32::{Pressure, VolumeRate},
+    pressure::millimeter_of_mercury,
+    volume_rate::{liter_per_minute, milliliter_per_second},
+};
+
+use crate::loop_control::{
+    beat_average::{BeatAverager, BeatSamples},
+    config::Circuit,
+    setpoint::resistance::{ResistanceCalibration, ResistanceSetpoint},
+};
+
+/// Closed-loop resistance control configuration
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ResistanceLoopConfig {
+    /// Fraction of the valve opening error corrected each beat
+    pub gain: f32,
+    /// Largest correction of the calibrated valve opening, in percent
+    pub max_trim: f32,
+    /// Beats with less mean flow do not tell the resistance apart and are not acted upon
+    pub min_flow: VolumeRate,
+}
+
+impl Default for ResistanceLoopConfig {
+    fn default() -> Self {
+        Self {
+            gain: 0.3,
+            max_trim: 25.0,
+            min_flow: VolumeRate::new::<liter_per_minute>(0.2),
+        }
+    }
+}
+
+/// Beat averaged pressure drop and flow over a circuit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResistanceEstimate {
+    /// Beat the averages belong to, as counted by the heart controller
+    pub beat: u32,
+    /// Mean afterload minus preload pressure
+    pub pressure_drop: Pressure,
+    pub flow: VolumeRate,
+}
+
+impl ResistanceEstimate {
+    /// Flow resistance in mmHg·s/mL, not finite without flow
+    pub fn resistance(&self) -> f32 {
+        self.pressure_drop.get::<millimeter_of_mercury>() / self.flow.get::<milliliter_per_second>()
+    }
+}
+
+/// Sums of the pressure drop and flow samples of a circuit over a beat
+#[derive(Debug, Clone, Default)]
+pub struct ResistanceSamples {
+    /// Sum of the pressure drop samples in mmHg
+    pressure_drop: f32,
+    /// Sum of the flow samples in mL/s
+    flow: f32,
+    samples: u32,
+}
+
+impl BeatSamples for ResistanceSamples {
+    /// Afterload minus preload pressure, and flow
+    type Sample = (Pressure, VolumeRate);
+    type Estimate = ResistanceEstimate;
+
+    fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    fn add(&mut self, (pressure_drop, flow): (Pressure, VolumeRate)) {
+        self.pressure_drop += pressure_drop.get::<millimeter_of_mercury>();
+        self.flow += flow.get::<milliliter_per_second>();
+        self.samples += 1;
+    }
+
+    fn estimate(&self, beat: u32) -> Option<ResistanceEstimate> {
+        (self.samples > 0).then(|| ResistanceEstimate {
+            beat,
+            pressure_drop: Pressure::new::<millimeter_of_mercury>(
+                self.pressure_drop / self.samples as f32,
+            ),
+            flow: VolumeRate::new::<milliliter_per_second>(self.flow / self.samples as f32),
+        })
+    }
+}
+
+/// Averages the pressure drop and flow of a circuit over each beat
+pub type ResistanceEstimator = BeatAverager<ResistanceSamples>;
+
+/// Resistance of a circuit for the host, once per beat
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct ResistanceStatus {
+    pub circuit: Circuit,
+    pub beat: u32,
+    /// Requested resistance in mmHg·s/mL
+    pub setpoint: f32,
+    /// Estimated resistance in mmHg·s/mL
+    pub measured: f32,
+    /// Correction of the calibrated valve opening in percent, 0 while controlling open-loop
+    pub trim: f32,
+}
+
+/// Trims the calibrated pinch valve opening of a circuit on its estimated resistance
+/// The error is taken in valve opening, through the calibration curve: the correction per beat
+/// does not depend on where on the steep resistance curve the valve is
+#[derive(Debug, Clone)]
+pub struct ResistanceLoop {
+    config: ResistanceLoopConfig,
+    /// Correction of the calibrated valve opening in percent
+    trim: f32,
+}
+
+impl ResistanceLoop {
+    pub fn new(config: ResistanceLoopConfig) -> Self {
+        Self { config, trim: 0.0 }
+    }
+
+    /// Change the gains, the current correction is kept
+    pub fn set_config(&mut self, config: ResistanceLoopConfig) {
+        self.config = config;
+        self.trim = self
+            .trim
+            .clamp(-config.max_trim.abs(), config.max_trim.abs());
+    }
+
+    pub fn trim(&self) -> f32 {
+        self.trim
+    }
+
+    /// Correct the valve opening with the `estimate` of a beat at a `setpoint` in mmHg·s/mL
+    pub fn update(
+        &mut self,
+        estimate: &ResistanceEstimate,
+        setpoint: f32,
+        calibration: &ResistanceCalibration,
+    ) {
+        let measured = estimate.resistance();
+        if estimate.flow.get::<liter_per_minute>().abs()
+            < self.config.min_flow.get::<liter_per_minute>()
+            || !measured.is_finite()
+            || measured <= 0.0
+        {
+            return;
+        }
+
+        // A circuit stiffer than requested maps to a smaller opening: open up further
+        let error = calibration.valve_open_percentage(setpoint)
+            - calibration.valve_open_percentage(measured);
+        let max_trim = self.config.max_trim.abs();
+
+        self.trim = (self.trim + self.config.gain * error).clamp(-max_trim, max_trim);
+    }
+
+    /// `setpoint` with the valve opening corrected
+    pub fn trimmed(&self, setpoint: ResistanceSetpoint) -> ResistanceSetpoint {
+        ResistanceSetpoint {
+            valve_open_percentage: (setpoint.valve_open_percentage + self.trim).clamp(0.0, 100.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loop_control::setpoint::resistance::CalibrationPoint;
+
+    fn mmhg(pressure: f32) -> Pressure {
+        Pressure::new::<millimeter_of_mercury>(pressure)
+    }
+
+    fn ml_per_s(flow: f32) -> VolumeRate {
+        VolumeRate::new::<milliliter_per_second>(flow)
+    }
+
+    fn calibration() -> ResistanceCalibration {
+        ResistanceCalibration {
+            points: [(0.5, 100.0), (1.0, 50.0), (2.0, 0.0)]
+                .iter()
+                .map(|&(resistance, valve_open_percentage)| CalibrationPoint {
+                    resistance,
+                    valve_open_percentage,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_beat_estimate() {
+        let mut estimator = ResistanceEstimator::default();
+
+        // Joining halfway through beat 1
+        assert_eq!(
+            estimator.update(Some(1), (mmhg(10.0), ml_per_s(10.0))),
+            None
+        );
+
+        assert_eq!(
+            estimator.update(Some(2), (mmhg(80.0), ml_per_s(100.0))),
+            None
+        );
+        assert_eq!(
+            estimator.update(Some(2), (mmhg(120.0), ml_per_s(60.0))),
+            None
+        );
+        let estimate = estimator
+            .update(Some(3), (mmhg(80.0), ml_per_s(100.0)))
+            .unwrap();
+        assert_eq!(estimate.beat, 2);
+        // 100mmHg / 80mL/s
+        assert_eq!(estimate.resistance(), 1.25);
+    }
+
+    #[test]
+    fn test_trim_towards_setpoint() {
+        let mut resistance_loop = ResistanceLoop::new(ResistanceLoopConfig {
+            gain: 0.5,
+            ..Default::default()
+        });
+        let calibration = calibration();
+        let estimate = |resistance: f32| ResistanceEstimate {
+            beat: 1,
+            pressure_drop: mmhg(resistance * 50.0),
+            flow: ml_per_s(50.0),
+        };
+
+        // Measured stiffer than calibrated: 1.5 maps to 25% against 50% for the setpoint of 1.0
+        resistance_loop.update(&estimate(1.5), 1.0, &calibration);
+        assert_eq!(resistance_loop.trim(), 12.5);
+        assert_eq!(
+            resistance_loop
+                .trimmed(ResistanceSetpoint::from_raw_resistance(1.0, &calibration))
+                .valve_open_percentage,
+            62.5
+        );
+
+        // On target
+        resistance_loop.update(&estimate(1.0), 1.0, &calibration);
+        assert_eq!(resistance_loop.trim(), 12.5);
+
+        // Too open
+        resistance_loop.update(&estimate(0.75), 1.0, &calibration);
+        assert_eq!(resistance_loop.trim(), 0.0);
+    }
+
+    #[test]
+    fn test_trim_limits() {
+        let mut resistance_loop = ResistanceLoop::new(ResistanceLoopConfig {
+            gain: 1.0,
+            max_trim: 20.0,
+            min_flow: VolumeRate::new::<liter_per_minute>(0.2),
+        });
+        let calibration = calibration();
+
+        // Barely any flow, 0.06 L/min
+        resistance_loop.update(
+            &ResistanceEstimate {
+                beat: 1,
+                pressure_drop: mmhg(100.0),
+                flow: ml_per_s(1.0),
+            },
+            1.0,
+            &calibration,
+        );
+        assert_eq!(resistance_loop.trim(), 0.0);
+
+        for beat in 1..10 {
+            resistance_loop.update(
+                &ResistanceEstimate {
+                    beat,
+                    pressure_drop: mmhg(100.0),
+                    flow: ml_per_s(50.0),
+                },
+                1.0,
+                &calibration,
+            );
+        }
+        assert_eq!(resistance_loop.trim(), 20.0);
+        assert_eq!(
+            resistance_loop
+                .trimmed(ResistanceSetpoint {
+                    valve_open_percentage: 90.0
+                })
+                .valve_open_percentage,
+            100.0
+        );
+    }
+}
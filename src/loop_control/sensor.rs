@@ -0,0 +1,126 @@
+//! Calibration of the mockloop sensors
+//! The analog front end scales the output range of each sensor to the ADC input range. A
+//! calibration maps the raw reading onto the measured quantity, with the offset and span found
+//! when calibrating the sensor.
+
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    f32::{Pressure, VolumeRate},
+    pressure::millimeter_of_mercury,
+    volume_rate::liter_per_minute,
+};
+
+/// Full scale reading of the 12 bit ADC
+pub(crate) const ADC_MAX_VALUE: f32 = ((1 << 12) - 1) as f32;
+/// Pressure at the top of the fluid pressure sensor output range, OsiSense XMLP500MC11F (500mbar)
+const PRESSURE_SENSOR_RANGE_MMHG: f32 = 375.03;
+/// Flow at the top of the DIGIFLOW-EXT1 analog output range
+const FLOW_SENSOR_RANGE_LPM: f32 = 32.0;
+
+/// Linear calibration of a sensor, in the unit of the quantity it measures
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SensorCalibration {
+    /// Reading at the bottom of the ADC range
+    pub offset: f32,
+    /// Reading at the top of the ADC range minus the reading at the bottom
+    pub span: f32,
+}
+
+impl SensorCalibration {
+    /// Quantity read as `raw` ADC counts
+    pub fn convert(&self, raw: u16) -> f32 {
+        self.offset + raw as f32 / ADC_MAX_VALUE * self.span
+    }
+
+    /// Pressure read as `raw` ADC counts, for a calibration in mmHg
+    pub fn pressure(&self, raw: u16) -> Pressure {
+        Pressure::new::<millimeter_of_mercury>(self.convert(raw))
+    }
+
+    /// Flow read as `raw` ADC counts, for a calibration in L/min
+    pub fn flow(&self, raw: u16) -> VolumeRate {
+        VolumeRate::new::<liter_per_minute>(self.convert(raw))
+    }
+}
+
+/// Calibrations of the fluid pressure and flow sensors of both circuits
+/// Pressures are in mmHg, flows in L/min
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SensorCalibrations {
+    pub systemic_preload_pressure: SensorCalibration,
+    pub systemic_afterload_pressure: SensorCalibration,
+    pub pulmonary_preload_pressure: SensorCalibration,
+    pub pulmonary_afterload_pressure: SensorCalibration,
+    pub systemic_flow: SensorCalibration,
+    pub pulmonary_flow: SensorCalibration,
+}
+
+impl Default for SensorCalibrations {
+    /// Nominal ranges of the sensors as built, until they are calibrated
+    fn default() -> Self {
+        let pressure = SensorCalibration {
+            offset: 0.0,
+            span: PRESSURE_SENSOR_RANGE_MMHG,
+        };
+        let flow = SensorCalibration {
+            offset: 0.0,
+            span: FLOW_SENSOR_RANGE_LPM,
+        };
+
+        Self {
+            systemic_preload_pressure: pressure,
+            systemic_afterload_pressure: pressure,
+            pulmonary_preload_pressure: pressure,
+            pulmonary_afterload_pressure: pressure,
+            systemic_flow: flow,
+            pulmonary_flow: flow,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.001,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn test_nominal_ranges() {
+        let calibrations = SensorCalibrations::default();
+
+        assert_close(
+            calibrations
+                .systemic_afterload_pressure
+                .pressure(4095)
+                .get::<millimeter_of_mercury>(),
+            375.03,
+        );
+        assert_close(
+            calibrations
+                .pulmonary_flow
+                .flow(2048)
+                .get::<liter_per_minute>(),
+            16.0039,
+        );
+    }
+
+    #[test]
+    fn test_calibrated_offset() {
+        // A 4-20mA sensor over 0-300mmHg, read across the whole ADC range
+        let calibration = SensorCalibration {
+            offset: -75.0,
+            span: 375.0,
+        };
+
+        assert_close(calibration.convert(819), 0.0);
+        assert_close(
+            calibration.pressure(4095).get::<millimeter_of_mercury>(),
+            300.0,
+        );
+    }
+}
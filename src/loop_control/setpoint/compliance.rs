@@ -1,14 +1,172 @@
-use uom::si::{f32::Pressure, pressure::bar};
+use core::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+use uom::si::{
+    f32::{Length, Pressure, Volume},
+    length::millimeter,
+    pressure::{atmosphere, bar, millimeter_of_mercury},
+    volume::milliliter,
+};
+
+use crate::dac::setpoint::RegulatorSetpoint;
+
+/// Air-cushion compliance chamber, a cylinder partly filled with loop fluid
+/// The air on top of the fluid is held at the regulator pressure, compressing it by Boyle's law
+/// `pV = const` gives the chamber a compliance of `dV/dp = V / p`, with `p` the absolute pressure
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ComplianceChamber {
+    /// Inner diameter of the chamber
+    pub diameter: Length,
+    /// Inner height of the chamber
+    pub height: Length,
+    /// Height of the fluid in the chamber, the air cushion fills the rest
+    pub fluid_level: Length,
+    /// Ambient pressure the regulator pressure is relative to
+    pub atmospheric_pressure: Pressure,
+}
+
+impl Default for ComplianceChamber {
+    /// Mockloop chamber of 120mm diameter and 30mm high, half filled
+    fn default() -> Self {
+        Self {
+            diameter: Length::new::<millimeter>(120.0),
+            height: Length::new::<millimeter>(30.0),
+            fluid_level: Length::new::<millimeter>(15.0),
+            atmospheric_pressure: Pressure::new::<atmosphere>(1.0),
+        }
+    }
+}
+
+impl ComplianceChamber {
+    /// Volume of the air cushion above the fluid
+    pub fn air_volume(&self) -> Volume {
+        let radius = self.diameter / 2.0;
+        let air_height = (self.height - self.fluid_level).max(Length::new::<millimeter>(0.0));
+
+        PI * radius * radius * air_height
+    }
+
+    /// Compliance in mL/mmHg with the air cushion at `pressure` above atmosphere
+    pub fn compliance(&self, pressure: Pressure) -> f32 {
+        self.air_volume().get::<milliliter>()
+            / (self.atmospheric_pressure + pressure).get::<millimeter_of_mercury>()
+    }
+
+    /// Pressure above atmosphere at which the air cushion has a compliance of `compliance` mL/mmHg
+    /// Negative when the air cushion is too small to reach it at atmospheric pressure
+    pub fn pressure_for(&self, compliance: f32) -> Pressure {
+        let absolute = self.air_volume().get::<milliliter>() / compliance;
+
+        Pressure::new::<millimeter_of_mercury>(absolute) - self.atmospheric_pressure
+    }
+}
 
 pub struct ComplianceSetpoint {
     pub pressure: Pressure,
 }
 
 impl ComplianceSetpoint {
-    pub fn from_raw_compliance(compliance: f32) -> Self {
-        defmt::warn!("TODO impl from_raw_compliance");
-        let pressure = Pressure::new::<bar>(compliance);
+    /// Regulator pressure giving `chamber` a compliance of `compliance` mL/mmHg
+    /// Compliances out of reach of the regulator are clamped to its range, the largest compliance
+    /// is at atmospheric pressure
+    pub fn from_raw_compliance(compliance: f32, chamber: &ComplianceChamber) -> Self {
+        let max_pressure = RegulatorSetpoint::REGULATOR_MAX_PRESSURE_BAR;
+        let min_pressure = RegulatorSetpoint::REGULATOR_MIN_PRESSURE_BAR;
+
+        // No compliance at all is as stiff as the chamber gets
+        let pressure = if compliance <= 0.0 {
+            max_pressure
+        } else {
+            chamber
+                .pressure_for(compliance)
+                .get::<bar>()
+                .max(min_pressure)
+                .min(max_pressure)
+        };
+
+        ComplianceSetpoint {
+            pressure: Pressure::new::<bar>(pressure),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() < tolerance,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn test_air_volume() {
+        let chamber = ComplianceChamber::default();
+
+        // pi * (60mm)^2 * 15mm = 169646mm^3
+        assert_close(chamber.air_volume().get::<milliliter>(), 169.646, 0.001);
+
+        let overfilled = ComplianceChamber {
+            fluid_level: Length::new::<millimeter>(40.0),
+            ..chamber
+        };
+        assert_eq!(overfilled.air_volume().get::<milliliter>(), 0.0);
+    }
+
+    #[test]
+    fn test_compliance() {
+        let chamber = ComplianceChamber::default();
+
+        // 169.646mL / 760mmHg
+        assert_close(
+            chamber.compliance(Pressure::new::<bar>(0.0)),
+            0.22322,
+            0.00001,
+        );
+        // 169.646mL / (760mmHg + 750.06mmHg)
+        assert_close(
+            chamber.compliance(Pressure::new::<bar>(1.0)),
+            0.11234,
+            0.00001,
+        );
+    }
+
+    #[test]
+    fn test_pressure_for_compliance() {
+        let chamber = ComplianceChamber::default();
+
+        // 169.646mL / 0.1mL/mmHg = 1696.46mmHg absolute, 936.46mmHg or 1.2485bar above atmosphere
+        assert_close(chamber.pressure_for(0.1).get::<bar>(), 1.2485, 0.0001);
+        // 169.646mL / 0.2mL/mmHg = 848.23mmHg absolute, 88.23mmHg or 0.11763bar above atmosphere
+        assert_close(chamber.pressure_for(0.2).get::<bar>(), 0.11763, 0.0001);
+
+        // 5mm of air: 56.549mL / 0.05mL/mmHg = 1130.97mmHg absolute, 370.97mmHg above atmosphere
+        let filled = ComplianceChamber {
+            fluid_level: Length::new::<millimeter>(25.0),
+            ..chamber
+        };
+        assert_close(filled.pressure_for(0.05).get::<bar>(), 0.49458, 0.0001);
+
+        let pressure = chamber.pressure_for(0.15);
+        assert_close(chamber.compliance(pressure), 0.15, 0.00001);
+    }
+
+    #[test]
+    fn test_setpoint_clamped_to_regulator() {
+        let chamber = ComplianceChamber::default();
+        let pressure = |compliance| {
+            ComplianceSetpoint::from_raw_compliance(compliance, &chamber)
+                .pressure
+                .get::<bar>()
+        };
 
-        ComplianceSetpoint { pressure }
+        assert_close(pressure(0.1), 1.2485, 0.0001);
+        // Needs less than atmospheric pressure
+        assert_eq!(pressure(0.5), 0.0);
+        // Needs more than the regulator delivers
+        assert_eq!(pressure(0.01), 2.0);
+        assert_eq!(pressure(0.0), 2.0);
     }
 }
@@ -1,14 +1,167 @@
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of points in a [`ResistanceCalibration`]
+pub const CALIBRATION_POINTS: usize = 16;
+
+/// Measured resistance of a pinch valve at one opening
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, Serialize, Deserialize)]
+pub struct CalibrationPoint {
+    /// Flow resistance in mmHg·s/mL
+    pub resistance: f32,
+    /// Valve opening in percent
+    pub valve_open_percentage: f32,
+}
+
+/// Resistance of a pinch valve over its opening, linearly interpolated between points sorted by
+/// increasing resistance
+/// Resistances beyond the curve get the opening of its ends, so a valve never closes further than
+/// its most closed point
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResistanceCalibration {
+    pub points: heapless::Vec<CalibrationPoint, CALIBRATION_POINTS>,
+}
+
+impl Default for ResistanceCalibration {
+    /// Nominal curve of an orifice, the resistance rises with the inverse square of the opening
+    /// from 0.05mmHg·s/mL fully open, until the valve is calibrated
+    fn default() -> Self {
+        const NOMINAL: [(f32, f32); 8] = [
+            (0.05, 100.0),
+            (0.102, 70.0),
+            (0.2, 50.0),
+            (0.408, 35.0),
+            (0.8, 25.0),
+            (1.543, 18.0),
+            (3.472, 12.0),
+            (7.813, 8.0),
+        ];
+
+        Self {
+            points: NOMINAL
+                .iter()
+                .map(|&(resistance, valve_open_percentage)| CalibrationPoint {
+                    resistance,
+                    valve_open_percentage,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl ResistanceCalibration {
+    /// Valve opening in percent giving a flow resistance of `resistance` mmHg·s/mL, fully open
+    /// without calibration points
+    pub fn valve_open_percentage(&self, resistance: f32) -> f32 {
+        let (Some(first), Some(last)) = (self.points.first(), self.points.last()) else {
+            return ResistanceSetpoint::SAFE_VALVE_OPEN_PERCENTAGE;
+        };
+
+        let opening = if resistance.is_nan() || resistance <= first.resistance {
+            first.valve_open_percentage
+        } else if resistance >= last.resistance {
+            last.valve_open_percentage
+        } else {
+            self.points
+                .windows(2)
+                .find(|segment| resistance <= segment[1].resistance)
+                .map_or(last.valve_open_percentage, |segment| {
+                    let (from, to) = (segment[0], segment[1]);
+                    let span = to.resistance - from.resistance;
+                    let fraction = if span > 0.0 {
+                        (resistance - from.resistance) / span
+                    } else {
+                        1.0
+                    };
+
+                    from.valve_open_percentage
+                        + (to.valve_open_percentage - from.valve_open_percentage) * fraction
+                })
+        };
+
+        opening.clamp(0.0, 100.0)
+    }
+}
+
 pub struct ResistanceSetpoint {
     pub valve_open_percentage: f32,
 }
 
 impl ResistanceSetpoint {
-    pub fn from_raw_resistance(resistance: f32) -> Self {
-        defmt::warn!("TODO impl from_raw_resistance");
-        let valve_open_percentage = resistance;
+    /// Fully open, the heart can not pump against a closed loop
+    pub const SAFE_VALVE_OPEN_PERCENTAGE: f32 = 100.0;
+
+    /// Pinch valve opening giving a flow resistance of `resistance` mmHg·s/mL
+    pub fn from_raw_resistance(resistance: f32, calibration: &ResistanceCalibration) -> Self {
+        ResistanceSetpoint {
+            valve_open_percentage: calibration.valve_open_percentage(resistance),
+        }
+    }
 
+    pub fn safe() -> Self {
         ResistanceSetpoint {
-            valve_open_percentage,
+            valve_open_percentage: Self::SAFE_VALVE_OPEN_PERCENTAGE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration(points: &[(f32, f32)]) -> ResistanceCalibration {
+        ResistanceCalibration {
+            points: points
+                .iter()
+                .map(|&(resistance, valve_open_percentage)| CalibrationPoint {
+                    resistance,
+                    valve_open_percentage,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let calibration = calibration(&[(0.1, 100.0), (0.5, 50.0), (2.5, 10.0)]);
+        let opening = |resistance| {
+            ResistanceSetpoint::from_raw_resistance(resistance, &calibration).valve_open_percentage
+        };
+
+        assert_eq!(opening(0.1), 100.0);
+        assert_eq!(opening(0.3), 75.0);
+        assert_eq!(opening(0.5), 50.0);
+        assert_eq!(opening(1.5), 30.0);
+        assert_eq!(opening(2.5), 10.0);
+    }
+
+    #[test]
+    fn test_beyond_curve() {
+        let calibration = calibration(&[(0.1, 100.0), (0.5, 50.0), (2.5, 10.0)]);
+
+        assert_eq!(calibration.valve_open_percentage(0.0), 100.0);
+        // Never closes further than calibrated
+        assert_eq!(calibration.valve_open_percentage(100.0), 10.0);
+        assert_eq!(calibration.valve_open_percentage(f32::INFINITY), 10.0);
+        assert_eq!(calibration.valve_open_percentage(f32::NAN), 100.0);
+
+        assert_eq!(
+            ResistanceCalibration {
+                points: heapless::Vec::new()
+            }
+            .valve_open_percentage(1.0),
+            ResistanceSetpoint::SAFE_VALVE_OPEN_PERCENTAGE
+        );
+    }
+
+    #[test]
+    fn test_nominal_curve() {
+        let calibration = ResistanceCalibration::default();
+
+        // Follows the 1/opening² law at the points
+        for point in &calibration.points {
+            let opening = point.valve_open_percentage / 100.0;
+            assert!((point.resistance - 0.05 / (opening * opening)).abs() < 0.001);
         }
+        assert_eq!(calibration.valve_open_percentage(0.2), 50.0);
     }
 }
@@ -1,18 +1,6 @@
 #![no_std]
 #![no_main]
 
-pub mod adc_task;
-pub mod button_task;
-pub mod comms;
-pub mod dac;
-pub mod framing_task;
-pub mod hal;
-pub mod heart_control;
-pub mod led_task;
-pub mod loop_control;
-pub mod reporting_task;
-pub mod valve_task;
-
 use defmt::*;
 use defmt_rtt as _;
 use embassy_executor::Spawner;
@@ -21,20 +9,23 @@ use embassy_stm32::rcc::{
     AHBPrescaler, APBPrescaler, Hsi48Config, LsConfig, PllMul, PllPreDiv, PllRDiv, PllSource,
     RtcClockSource, Sysclk, mux,
 };
-use embassy_sync::channel::Channel;
 use embassy_sync::pipe::{self};
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, watch::Watch};
 use love_letter::{AppState, Report, Setpoint};
 use panic_probe as _;
 use static_cell::StaticCell;
 
-use crate::adc_task::AdcFrame;
-use crate::hal::Hal;
+use plc_lite::adc_task::AdcFrame;
+use plc_lite::hal::Hal;
+use plc_lite::{
+    APPSTATE_WATCH, adc_task, button_task, chamber_valve_task, comms, dac, framing_task, hal,
+    heart_control, led_task, loop_control, pinch_valve_task, reporting_task, sync_output_task,
+    trigger_task,
+};
 
-static ADC_CHAN: Channel<Cs, AdcFrame, 2> = Channel::new();
-static APPSTATE_WATCH: Watch<Cs, AppState, 1> = Watch::new();
+static ADC_FRAME_WATCH: Watch<Cs, AdcFrame, 1> = Watch::new();
 static REPORT_WATCH: Watch<Cs, Report, 1> = Watch::new();
-static SETPOINT_WATCH: Watch<Cs, Setpoint, 3> = Watch::new();
+static SETPOINT_WATCH: Watch<Cs, Setpoint, 4> = Watch::new();
 static REPORT_PIPE: StaticCell<pipe::Pipe<Cs, { love_letter::REPORT_BYTES * 4 }>> =
     StaticCell::new();
 static SETPOINT_PIPE: StaticCell<pipe::Pipe<Cs, { love_letter::SETPOINT_BYTES * 4 }>> =
@@ -78,12 +69,28 @@ async fn main(spawner: Spawner) {
             APPSTATE_WATCH.receiver().expect("Update appstate watch N"),
         ))
         .unwrap();
+    spawner
+        .spawn(trigger_task::watch_external_trigger(hal.external_trigger))
+        .unwrap();
+    spawner
+        .spawn(sync_output_task::drive_sync_output(hal.sync_output))
+        .unwrap();
+    spawner
+        .spawn(pinch_valve_task::control_pinch_valves(hal.pinch_valve_pwm))
+        .unwrap();
+    spawner
+        .spawn(chamber_valve_task::control_chamber_valves(
+            hal.chamber_valves,
+        ))
+        .unwrap();
     spawner
         .spawn(adc_task::read_adc(
             hal.adc1,
+            hal.adc2,
             hal.dma,
+            hal.adc2_dma,
             hal.adc_channels,
-            ADC_CHAN.sender(),
+            ADC_FRAME_WATCH.sender(),
         ))
         .unwrap();
     spawner
@@ -106,7 +113,9 @@ async fn main(spawner: Spawner) {
         .unwrap();
     spawner
         .spawn(reporting_task::collect_and_publish_reports(
-            ADC_CHAN.receiver(),
+            ADC_FRAME_WATCH
+                .receiver()
+                .expect("Update ADC_FRAME_WATCH N"),
             REPORT_WATCH.sender(),
             SETPOINT_WATCH.receiver().expect("Update setpoint watch N"),
         ))
@@ -118,6 +127,17 @@ async fn main(spawner: Spawner) {
                 .expect("max number of setpoint receivers created"),
         ))
         .unwrap();
+    spawner
+        .spawn(loop_control::fill_controller::fill_control_loop())
+        .unwrap();
+    spawner
+        .spawn(loop_control::preset_controller::preset_control_loop(
+            SETPOINT_WATCH.sender(),
+            SETPOINT_WATCH
+                .receiver()
+                .expect("max number of setpoint receivers created"),
+        ))
+        .unwrap();
     spawner
         .spawn(heart_control::heart_controller::heart_control_loop(
             SETPOINT_WATCH
@@ -125,11 +145,15 @@ async fn main(spawner: Spawner) {
                 .expect("max number of setpoint receivers created"),
         ))
         .unwrap();
+    spawner
+        .spawn(heart_control::pressure_controller::pressure_control_loop())
+        .unwrap();
     spawner
         .spawn(dac::dac_task::write_dac(
             hal.heart_pressure_dac,
             hal.systemic_compliance_dac,
             hal.pulmonary_compliance_dac,
+            hal.flow_controller_dac,
         ))
         .unwrap();
 }
@@ -0,0 +1,75 @@
+use defmt::*;
+use embassy_futures::select::{Either, select};
+use embassy_stm32::timer::simple_pwm::{SimplePwm, SimplePwmChannel};
+use embassy_sync::{
+    blocking_mutex::raw::ThreadModeRawMutex as Cs,
+    watch::{self, Watch},
+};
+
+use crate::{hal::PinchValveTimer, loop_control::setpoint::resistance::ResistanceSetpoint};
+
+/// Opening of the systemic resistance pinch valve in percent
+pub static SYSTEMIC_PINCH_VALVE_WATCH: Watch<Cs, f32, 1> = Watch::new();
+/// Opening of the pulmonary resistance pinch valve in percent
+pub static PULMONARY_PINCH_VALVE_WATCH: Watch<Cs, f32, 1> = Watch::new();
+
+/// Resolution of the PWM duty cycle, in steps per percent of valve opening
+const DUTY_STEPS_PER_PERCENT: f32 = 10.0;
+
+/// Proportional pinch valve, its driver opens the valve with the PWM duty cycle
+pub struct PinchValve<'d> {
+    channel: SimplePwmChannel<'d, PinchValveTimer>,
+    rx: watch::Receiver<'static, Cs, f32, 1>,
+}
+
+impl PinchValve<'_> {
+    fn actuate(&mut self, valve_open_percentage: f32) {
+        let duty = (valve_open_percentage.clamp(0.0, 100.0) * DUTY_STEPS_PER_PERCENT) as u16;
+
+        self.channel
+            .set_duty_cycle_fraction(duty, (100.0 * DUTY_STEPS_PER_PERCENT) as u16);
+    }
+}
+
+/// Resistance pinch valve routine
+/// Drives the systemic and pulmonary pinch valves from channel 1 and 2 of the PWM timer
+#[embassy_executor::task]
+pub async fn control_pinch_valves(pwm: SimplePwm<'static, PinchValveTimer>) {
+    info!("starting PINCH VALVE task");
+
+    let channels = pwm.split();
+
+    let mut systemic_valve = PinchValve {
+        channel: channels.ch1,
+        rx: SYSTEMIC_PINCH_VALVE_WATCH
+            .receiver()
+            .expect("Increase systemic pinch valve watch size"),
+    };
+    let mut pulmonary_valve = PinchValve {
+        channel: channels.ch2,
+        rx: PULMONARY_PINCH_VALVE_WATCH
+            .receiver()
+            .expect("Increase pulmonary pinch valve watch size"),
+    };
+
+    // Open up until the loop controller asks otherwise
+    for valve in [&mut systemic_valve, &mut pulmonary_valve] {
+        valve.actuate(ResistanceSetpoint::SAFE_VALVE_OPEN_PERCENTAGE);
+        valve.channel.enable();
+    }
+
+    info!("starting PINCH VALVE loop");
+    loop {
+        // Wait for valve actuation request
+        match select(systemic_valve.rx.changed(), pulmonary_valve.rx.changed()).await {
+            Either::First(opening) => {
+                trace!("PINCH VALVE: systemic valve to {}% open", opening);
+                systemic_valve.actuate(opening)
+            }
+            Either::Second(opening) => {
+                trace!("PINCH VALVE: pulmonary valve to {}% open", opening);
+                pulmonary_valve.actuate(opening)
+            }
+        }
+    }
+}
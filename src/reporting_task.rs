@@ -1,9 +1,20 @@
 use defmt::*;
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, channel, watch};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, watch};
 use embassy_time::{Duration, Ticker};
-use love_letter::{AppState, Report, Setpoint};
+use love_letter::{Report, Setpoint};
+use uom::si::{pressure::bar, volume_rate::liter_per_minute};
 
-use crate::adc_task::AdcFrame;
+use crate::{
+    adc_task::AdcFrame,
+    comms::message::StatusReport,
+    framing_task::publish_status,
+    heart_control::{
+        heart_controller::{BEAT_TIMING_WATCH, CONTROL_FAULT_WATCH, PRIMING_WATCH},
+        pressure_controller::PRESSURE_LOOP_WATCH,
+        priming::app_state,
+    },
+    loop_control::{loop_controller::LOOP_CONFIG_WATCH, sensor::SensorCalibrations},
+};
 
 /// Minimum period between 2 reports
 const REPORT_PERIOD: Duration = Duration::from_millis(100);
@@ -11,31 +22,83 @@ const REPORT_PERIOD: Duration = Duration::from_millis(100);
 /// Parses latest ADC frames, Setpoints and AppState into coherent [`Report`]s
 #[embassy_executor::task]
 pub async fn collect_and_publish_reports(
-    frame_in: channel::Receiver<'static, Cs, AdcFrame, 2>,
+    mut frame_rx: watch::Receiver<'static, Cs, AdcFrame, 1>,
     report_out: watch::Sender<'static, Cs, Report, 1>,
-    mut setpoint_rx: watch::Receiver<'static, Cs, Setpoint, 3>,
+    mut setpoint_rx: watch::Receiver<'static, Cs, Setpoint, 4>,
 ) {
     info!("starting REPORT task");
     let mut ticker = Ticker::every(REPORT_PERIOD);
 
+    let mut beat_timing_rx = BEAT_TIMING_WATCH
+        .receiver()
+        .expect("Increase BEAT_TIMING_WATCH N");
+    let mut pressure_loop_rx = PRESSURE_LOOP_WATCH
+        .receiver()
+        .expect("Increase PRESSURE_LOOP_WATCH N");
+    let mut fault_rx = CONTROL_FAULT_WATCH
+        .receiver()
+        .expect("Increase CONTROL_FAULT_WATCH N");
+    let mut priming_rx = PRIMING_WATCH.receiver().expect("Increase PRIMING_WATCH N");
+    let mut config_rx = LOOP_CONFIG_WATCH
+        .receiver()
+        .expect("Increase LOOP_CONFIG_WATCH N");
+    // The sensors are read through the latest calibration, the nominal one until it is received
+    let mut sensors = SensorCalibrations::default();
+
     info!("starting REPORT loop");
     loop {
         // Wait for latest ADC frame, this is the most important part of the report
-        let frame = frame_in.receive().await;
+        let frame = frame_rx.changed().await;
         // Get the latest known setpoint, or a default one if none is received yet
         // This might seem problematic, but during real operation any interesting adc
         // measurement has been accompanied by at least one previous setpoint
         let setpoint = setpoint_rx.try_get().unwrap_or_default();
+        let fault = fault_rx.try_get().flatten();
+        let priming = priming_rx.try_get();
+        if let Some(config) = config_rx.try_changed() {
+            sensors = config.sensors;
+        }
 
         // Collect mockloop state and latest measurements into a report
         let report = Report {
             setpoint,
-            app_state: calculate_appstate(),
-            measurements: frame.into_measurement(),
+            app_state: app_state(fault, priming),
+            measurements: frame.into_measurement(&sensors),
         };
 
         info!("REPORT: collected report: {:?}", report);
 
+        // Beat scheduling statistics are not part of the love-letter Report (yet), sent along at the
+        // report rate
+        if let Some(beat_timing) = beat_timing_rx.try_changed() {
+            debug!("REPORT: heart beat timing: {:?}", beat_timing);
+            publish_status(StatusReport::BeatTiming(beat_timing));
+        }
+        // Nor is the closed-loop pressure tracking, sent along at the report rate
+        if let Some(pressure_loop) = pressure_loop_rx.try_changed() {
+            debug!(
+                "REPORT: driveline pressure tracking error: {:?}bar (target {:?}bar, measured {:?}bar, command {:?}bar)",
+                pressure_loop.error.get::<bar>(),
+                pressure_loop.target.get::<bar>(),
+                pressure_loop.measured.get::<bar>(),
+                pressure_loop.command.get::<bar>()
+            );
+            publish_status(StatusReport::PressureLoop(pressure_loop));
+        }
+
+        // Nor is the progress of the start-up sequence
+        if let Some(priming) = priming {
+            info!("REPORT: heart start-up sequence: {:?}", priming);
+        }
+
+        // Nor is the actual flow of the mass-flow controller, sent along at the report rate
+        let flow_controller_flow = frame.flow_controller_flow();
+        debug!(
+            "REPORT: flow controller flow: {:?}L/min",
+            flow_controller_flow.get::<liter_per_minute>()
+        );
+        publish_status(StatusReport::FlowControllerFlow(flow_controller_flow));
+
         // Send report to the host
         report_out.send(report);
 
@@ -44,8 +107,3 @@ pub async fn collect_and_publish_reports(
         ticker.next().await;
     }
 }
-
-/// Given the current set of measurements and previous state, what is our current state?
-fn calculate_appstate() -> AppState {
-    AppState::StandBy
-}
@@ -0,0 +1,184 @@
+use defmt::*;
+use embassy_futures::select::{Either3, select3};
+use embassy_stm32::{
+    Peri,
+    gpio::OutputType,
+    time::Hertz,
+    timer::{
+        Ch1, Channel, GeneralInstance4Channel, TimerPin,
+        low_level::{OutputCompareMode, OutputPolarity, Timer},
+        simple_pwm::PwmPin,
+    },
+};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, channel};
+use embassy_time::{Duration, Instant, Timer as Delay};
+
+use crate::{
+    hal::SyncTimer,
+    heart_control::{
+        error::SyncError,
+        heart_controller::HEART_CONFIG_WATCH,
+        sync_output::{PulseTiming, SyncCommand, SyncPolarity, SyncPulse},
+    },
+};
+
+/// Pulses requested by the heart controller at selected phase switches
+pub static SYNC_COMMAND_CHANNEL: channel::Channel<Cs, SyncCommand, 4> = channel::Channel::new();
+
+/// How long before its start a pulse is handed to the timer, short enough for a fine prescaler
+const ARM_LEAD: Duration = Duration::from_millis(50);
+
+/// Sync output driven by a hardware timer in one-pulse mode
+/// The timer both starts and ends the pulse, so neither depends on task scheduling. PWM mode 2
+/// keeps the output at its inactive level while the counter is below the channel 1 compare value,
+/// the timer stops with its counter at 0 after the pulse.
+pub struct SyncOutput<'d, T: GeneralInstance4Channel> {
+    timer: Timer<'d, T>,
+    _pin: PwmPin<'d, T, Ch1>,
+}
+
+impl<'d, T: GeneralInstance4Channel> SyncOutput<'d, T> {
+    pub fn new(tim: Peri<'d, T>, pin: Peri<'d, impl TimerPin<T, Ch1>>) -> Self {
+        let pin = PwmPin::new(pin, OutputType::PushPull);
+        let timer = Timer::new(tim);
+
+        timer.set_tick_freq(Hertz(1_000_000));
+        // Stop counting at the end of the pulse
+        timer.regs_core().cr1().modify(|w| w.set_opm(true));
+        timer.set_output_compare_mode(Channel::Ch1, OutputCompareMode::PwmMode2);
+        timer.set_output_polarity(Channel::Ch1, OutputPolarity::ActiveHigh);
+        timer.set_compare_value(Channel::Ch1, 1);
+        timer.set_max_compare_value(1);
+        timer.enable_channel(Channel::Ch1, true);
+
+        Self { timer, _pin: pin }
+    }
+
+    /// Set the active level of the pulses, the output idles at the opposite level from now on
+    pub fn set_polarity(&mut self, polarity: SyncPolarity) {
+        let polarity = match polarity {
+            SyncPolarity::ActiveHigh => OutputPolarity::ActiveHigh,
+            SyncPolarity::ActiveLow => OutputPolarity::ActiveLow,
+        };
+
+        self.timer.set_output_polarity(Channel::Ch1, polarity);
+    }
+
+    /// Whether the timer runs, waiting for the start of a pulse or in the middle of one
+    fn is_running(&self) -> bool {
+        self.timer.regs_core().cr1().read().cen()
+    }
+
+    /// Whether the timer is waiting for the start of a pulse
+    fn is_armed(&self) -> bool {
+        self.is_running()
+            && u32::from(self.timer.regs_core().cnt().read().cnt())
+                < self.timer.get_compare_value(Channel::Ch1)
+    }
+
+    /// Start the timer for `pulse`, replacing a pulse that has not started yet
+    /// Returns the end of the pulse
+    pub fn arm(&mut self, pulse: &SyncPulse) -> Result<Instant, SyncError> {
+        let now = Instant::now();
+        let timing = PulseTiming::new(pulse, now, self.timer.get_clock_frequency().0)?;
+
+        self.timer.stop();
+        self.timer.regs_core().psc().write_value(timing.prescaler);
+        self.timer
+            .set_compare_value(Channel::Ch1, timing.start.into());
+        // Active from the compare value until the counter wraps
+        self.timer.set_max_compare_value(timing.end.into());
+        // Load the prescaler and restart the counter from 0
+        self.timer.regs_core().egr().write(|w| w.set_ug(true));
+        self.timer.start();
+
+        Ok(pulse.at.max(now) + pulse.width)
+    }
+
+    /// Drop the pulse that has not started yet, a pulse that has started runs to its end
+    pub fn cancel(&mut self) {
+        if self.is_armed() {
+            self.timer.stop();
+            self.timer.reset();
+        }
+    }
+}
+
+/// Sync output routine
+/// Fires a pulse for every pulse the heart controller requests, and sets the idle level of the
+/// output as soon as the sync output is configured
+#[embassy_executor::task]
+pub async fn drive_sync_output(mut sync_output: SyncOutput<'static, SyncTimer>) {
+    info!("starting SYNC OUTPUT task");
+
+    let mut config_rx = HEART_CONFIG_WATCH
+        .receiver()
+        .expect("Update HEART_CONFIG_WATCH N");
+    let mut polarity = config_rx
+        .try_get()
+        .and_then(|config| config.sync_output)
+        .map(|sync| sync.polarity)
+        .unwrap_or_default();
+    sync_output.set_polarity(polarity);
+    // Pulse waiting to be handed to the timer
+    let mut next: Option<SyncPulse> = None;
+    // End of the latest pulse handed to the timer
+    let mut busy_until = Instant::from_ticks(0);
+
+    loop {
+        let arm = async {
+            match next {
+                Some(pulse) => Delay::at(arm_at(&pulse, busy_until)).await,
+                None => core::future::pending().await,
+            }
+        };
+
+        // Await either:
+        // A: A pulse or cancellation from the heart controller, replacing the pulse that has not
+        //    started yet
+        // B: A new heart configuration, which may change the idle level
+        // C: The moment to arm the next pulse
+        match select3(SYNC_COMMAND_CHANNEL.receive(), config_rx.changed(), arm).await {
+            Either3::First(command) => {
+                trace!("SYNC OUTPUT: {:?}", command);
+                sync_output.cancel();
+                next = match command {
+                    SyncCommand::Pulse(pulse) => Some(pulse),
+                    SyncCommand::Cancel => None,
+                };
+            }
+            Either3::Second(config) => {
+                polarity = config
+                    .sync_output
+                    .map(|sync| sync.polarity)
+                    .unwrap_or_default();
+                if !sync_output.is_running() {
+                    sync_output.set_polarity(polarity);
+                }
+            }
+            Either3::Third(()) => {}
+        }
+
+        // Arm a pulse as soon as it is due, so a command right behind it does not replace it
+        if let Some(pulse) = next
+            && arm_at(&pulse, busy_until) <= Instant::now()
+        {
+            next = None;
+            sync_output.set_polarity(polarity);
+            match sync_output.arm(&pulse) {
+                Ok(end) => busy_until = end,
+                Err(err) => warn!("SYNC OUTPUT: {}, dropping {:?}", err, pulse),
+            }
+        }
+    }
+}
+
+/// Moment to hand `pulse` to the timer: shortly before it starts, once the pulse before it has
+/// ended at `busy_until`
+fn arm_at(pulse: &SyncPulse, busy_until: Instant) -> Instant {
+    pulse
+        .at
+        .checked_sub(ARM_LEAD)
+        .unwrap_or(pulse.at)
+        .max(busy_until)
+}
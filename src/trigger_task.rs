@@ -0,0 +1,24 @@
+use defmt::*;
+use embassy_stm32::exti::ExtiInput;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex as Cs, signal::Signal};
+use embassy_time::Instant;
+
+/// Moment of the latest rising edge on the external trigger input, e.g. an ECG R-wave detector
+/// or an external pacer
+pub static EXTERNAL_TRIGGER_SIGNAL: Signal<Cs, Instant> = Signal::new();
+
+/// External trigger routine
+/// Timestamps every rising edge of the trigger input straight from the EXTI interrupt, the heart
+/// controller decides what to do with it
+#[embassy_executor::task]
+pub async fn watch_external_trigger(mut trigger: ExtiInput<'static>) {
+    info!("starting EXTERNAL TRIGGER task");
+
+    loop {
+        trigger.wait_for_rising_edge().await;
+        let at = Instant::now();
+        trace!("EXTERNAL TRIGGER: rising edge at {}", at);
+
+        EXTERNAL_TRIGGER_SIGNAL.signal(at);
+    }
+}
@@ -6,15 +6,11 @@ use embassy_sync::{
     watch::{self, Watch},
 };
 
+use crate::heart_control::phase::ValveState;
+
 pub static LEFT_VALVE_WATCH: Watch<Cs, ValveState, 1> = Watch::new();
 pub static RIGHT_VALVE_WATCH: Watch<Cs, ValveState, 1> = Watch::new();
 
-#[derive(Debug, Clone, Copy, defmt::Format)]
-pub enum ValveState {
-    Pressure,
-    Vacuum,
-}
-
 pub struct Valve {
     pin: Output<'static>,
     state: ValveState,